@@ -0,0 +1,57 @@
+/*
+    Measures how many file names per second can be encrypted/decrypted.
+
+    Run with `cargo bench --bench name_crypto`.
+*/
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rclone_crypt::cipher::Cipher;
+
+const NAMES_PER_ITERATION: usize = 1000;
+
+fn names(len: usize) -> Vec<String> {
+    (0..NAMES_PER_ITERATION)
+        .map(|i| format!("{:0>width$}", i, width = len))
+        .collect()
+}
+
+fn name_crypto(c: &mut Criterion) {
+    let cipher = Cipher::new("bench", Some("bench")).unwrap();
+
+    let mut group = c.benchmark_group("names");
+    group.throughput(Throughput::Elements(NAMES_PER_ITERATION as u64));
+
+    // Short names, typical file names and names close to the 255 byte limit
+    for len in [8, 40, 200] {
+        let plain = names(len);
+        let encrypted: Vec<String> = plain
+            .iter()
+            .map(|name| cipher.encrypt_file_name(name).unwrap())
+            .collect();
+
+        group.bench_with_input(BenchmarkId::new("encrypt", len), &plain, |b, plain| {
+            b.iter(|| {
+                for name in plain {
+                    black_box(cipher.encrypt_file_name(black_box(name)).unwrap());
+                }
+            })
+        });
+
+        group.bench_with_input(
+            BenchmarkId::new("decrypt", len),
+            &encrypted,
+            |b, encrypted| {
+                b.iter(|| {
+                    for name in encrypted {
+                        black_box(cipher.decrypt_file_name(black_box(name)).unwrap());
+                    }
+                })
+            },
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, name_crypto);
+criterion_main!(benches);
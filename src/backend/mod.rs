@@ -0,0 +1,109 @@
+//! Internal abstraction over the primitives used for data encryption:
+//! NaCl secretbox (XSalsa20 + Poly1305) and a secure random number generator.
+//!
+//! Two implementations exist: libsodium through `sodiumoxide` (the `sodiumoxide` feature)
+//! and pure Rust through RustCrypto's `xsalsa20poly1305` and `getrandom` (the `pure-rust` feature).
+//! Both produce identical output. If both features are enabled, the pure Rust backend is used.
+use alloc::vec::Vec;
+use anyhow::Result;
+
+#[cfg(feature = "pure-rust")]
+mod rust_crypto;
+// Only needed next to the pure Rust backend to compare the two in tests
+#[cfg(all(feature = "sodiumoxide", any(test, not(feature = "pure-rust"))))]
+mod sodium;
+
+#[cfg(feature = "pure-rust")]
+pub(crate) use rust_crypto::RustCrypto;
+#[cfg(all(feature = "sodiumoxide", any(test, not(feature = "pure-rust"))))]
+pub(crate) use sodium::Sodium;
+
+#[cfg(not(any(feature = "sodiumoxide", feature = "pure-rust")))]
+compile_error!("Either the `sodiumoxide` or the `pure-rust` feature must be enabled");
+
+/// Secretbox key
+pub(crate) type Key = [u8; 32];
+/// Secretbox nonce
+pub(crate) type Nonce = [u8; 24];
+
+/// Size of the authentication tag which secretbox prepends to the ciphertext
+pub(crate) const MAC_SIZE: usize = 16;
+
+/// A provider of secretbox sealing/opening and random bytes.
+pub(crate) trait Backend {
+    /// Prepares the backend for use. Must be called before any other function.
+    fn init() -> Result<()>;
+
+    /// Encrypts and authenticates `plaintext`. The result is `MAC_SIZE` bytes longer.
+    fn seal(plaintext: &[u8], nonce: &Nonce, key: &Key) -> Vec<u8>;
+
+    /// Verifies and decrypts `ciphertext`, which starts with the authentication tag.
+    fn open(ciphertext: &[u8], nonce: &Nonce, key: &Key) -> Result<Vec<u8>>;
+
+    /// Fills `buf` with cryptographically secure random bytes.
+    fn random_bytes(buf: &mut [u8]) -> Result<()>;
+}
+
+#[cfg(feature = "pure-rust")]
+type Active = RustCrypto;
+#[cfg(all(feature = "sodiumoxide", not(feature = "pure-rust")))]
+type Active = Sodium;
+
+#[cfg(any(feature = "sodiumoxide", feature = "pure-rust"))]
+pub(crate) fn init() -> Result<()> {
+    Active::init()
+}
+
+#[cfg(any(feature = "sodiumoxide", feature = "pure-rust"))]
+pub(crate) fn seal(plaintext: &[u8], nonce: &Nonce, key: &Key) -> Vec<u8> {
+    Active::seal(plaintext, nonce, key)
+}
+
+#[cfg(any(feature = "sodiumoxide", feature = "pure-rust"))]
+pub(crate) fn open(ciphertext: &[u8], nonce: &Nonce, key: &Key) -> Result<Vec<u8>> {
+    Active::open(ciphertext, nonce, key)
+}
+
+#[cfg(any(feature = "sodiumoxide", feature = "pure-rust"))]
+pub(crate) fn random_bytes(buf: &mut [u8]) -> Result<()> {
+    Active::random_bytes(buf)
+}
+
+#[cfg(all(test, feature = "sodiumoxide", feature = "pure-rust"))]
+mod tests {
+    use super::{Backend, RustCrypto, Sodium, MAC_SIZE};
+
+    const KEY: [u8; 32] = [0x42; 32];
+    const NONCE: [u8; 24] = [0x24; 24];
+
+    #[test]
+    fn backends_should_produce_identical_ciphertext() {
+        Sodium::init().unwrap();
+        RustCrypto::init().unwrap();
+
+        for len in [0, 1, 15, 16, 17, 1000, 64 * 1024] {
+            let plaintext: Vec<u8> = (0..len).map(|i| (i % 253) as u8).collect();
+
+            let sodium = Sodium::seal(&plaintext, &NONCE, &KEY);
+            let rust_crypto = RustCrypto::seal(&plaintext, &NONCE, &KEY);
+
+            assert_eq!(sodium.len(), len + MAC_SIZE);
+            assert_eq!(sodium, rust_crypto);
+
+            assert_eq!(Sodium::open(&rust_crypto, &NONCE, &KEY).unwrap(), plaintext);
+            assert_eq!(RustCrypto::open(&sodium, &NONCE, &KEY).unwrap(), plaintext);
+        }
+    }
+
+    #[test]
+    fn backends_should_reject_the_same_tampering() {
+        let mut sealed = Sodium::seal(b"hello world", &NONCE, &KEY);
+        sealed[MAC_SIZE] ^= 1;
+
+        assert!(Sodium::open(&sealed, &NONCE, &KEY).is_err());
+        assert!(RustCrypto::open(&sealed, &NONCE, &KEY).is_err());
+
+        assert!(Sodium::open(&sealed[..MAC_SIZE - 1], &NONCE, &KEY).is_err());
+        assert!(RustCrypto::open(&sealed[..MAC_SIZE - 1], &NONCE, &KEY).is_err());
+    }
+}
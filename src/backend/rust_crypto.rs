@@ -0,0 +1,50 @@
+use super::{Backend, Key, Nonce, MAC_SIZE};
+use alloc::vec;
+use alloc::vec::Vec;
+use anyhow::{anyhow, Result};
+use xsalsa20poly1305::aead::{AeadInPlace, KeyInit};
+use xsalsa20poly1305::{Tag, XSalsa20Poly1305};
+
+/// Backend using the pure Rust RustCrypto implementations.
+pub(crate) struct RustCrypto;
+
+impl Backend for RustCrypto {
+    fn init() -> Result<()> {
+        Ok(())
+    }
+
+    fn seal(plaintext: &[u8], nonce: &Nonce, key: &Key) -> Vec<u8> {
+        let cipher = XSalsa20Poly1305::new(key.into());
+
+        // NaCl puts the tag in front of the ciphertext, RustCrypto appends it by default
+        let mut out = vec![0u8; MAC_SIZE + plaintext.len()];
+        out[MAC_SIZE..].copy_from_slice(plaintext);
+
+        let tag = cipher
+            .encrypt_in_place_detached(nonce.into(), b"", &mut out[MAC_SIZE..])
+            .expect("secretbox messages are never too long");
+        out[..MAC_SIZE].copy_from_slice(&tag);
+
+        out
+    }
+
+    fn open(ciphertext: &[u8], nonce: &Nonce, key: &Key) -> Result<Vec<u8>> {
+        if ciphertext.len() < MAC_SIZE {
+            return Err(anyhow!("Failed to open secretbox"));
+        }
+
+        let cipher = XSalsa20Poly1305::new(key.into());
+        let (tag, ciphertext) = ciphertext.split_at(MAC_SIZE);
+
+        let mut out = ciphertext.to_vec();
+        cipher
+            .decrypt_in_place_detached(nonce.into(), b"", &mut out, Tag::from_slice(tag))
+            .map_err(|_| anyhow!("Failed to open secretbox"))?;
+
+        Ok(out)
+    }
+
+    fn random_bytes(buf: &mut [u8]) -> Result<()> {
+        getrandom::getrandom(buf).map_err(|e| anyhow!("Failed to generate random bytes: {}", e))
+    }
+}
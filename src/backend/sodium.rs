@@ -0,0 +1,27 @@
+use super::{Backend, Key, Nonce};
+use anyhow::{anyhow, Result};
+use sodiumoxide::crypto::secretbox;
+use sodiumoxide::randombytes::randombytes_into;
+
+/// Backend using the C libsodium library.
+pub(crate) struct Sodium;
+
+impl Backend for Sodium {
+    fn init() -> Result<()> {
+        sodiumoxide::init().map_err(|_| anyhow!("Could not initialize sodiumoxide"))
+    }
+
+    fn seal(plaintext: &[u8], nonce: &Nonce, key: &Key) -> Vec<u8> {
+        secretbox::seal(plaintext, &secretbox::Nonce(*nonce), &secretbox::Key(*key))
+    }
+
+    fn open(ciphertext: &[u8], nonce: &Nonce, key: &Key) -> Result<Vec<u8>> {
+        secretbox::open(ciphertext, &secretbox::Nonce(*nonce), &secretbox::Key(*key))
+            .map_err(|_| anyhow!("Failed to open secretbox"))
+    }
+
+    fn random_bytes(buf: &mut [u8]) -> Result<()> {
+        randombytes_into(buf);
+        Ok(())
+    }
+}
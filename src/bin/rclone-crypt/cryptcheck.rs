@@ -0,0 +1,108 @@
+use anyhow::{anyhow, Context, Result};
+use clap::Args;
+use rclone_crypt::cipher::Cipher;
+use rclone_crypt::cryptcheck::{
+    check_file, check_file_hash, check_tree, parse_hash_list, CheckOptions, CheckStatus,
+    CiphertextHash,
+};
+use rclone_crypt::decrypter::read_nonce;
+use rclone_crypt::FILE_HEADER_SIZE;
+use std::fs::{self, File};
+use std::io::{BufReader, Read};
+use std::path::PathBuf;
+
+#[derive(Args)]
+pub struct CryptcheckArgs {
+    /// Plaintext file or directory
+    plaintext: PathBuf,
+
+    /// Encrypted file or directory. With a known hash, only the file header is read
+    encrypted: PathBuf,
+
+    /// Expected MD5 or SHA-1 (hex) of the encrypted file
+    #[arg(long, conflicts_with = "hashes")]
+    hash: Option<String>,
+
+    /// `md5sum`/`sha1sum` style list of hashes of the encrypted tree, relative to ENCRYPTED
+    #[arg(long)]
+    hashes: Option<PathBuf>,
+}
+
+pub fn cryptcheck(cipher: &Cipher, args: &CryptcheckArgs) -> Result<()> {
+    cipher.require_data_encryption()?;
+    if fs::metadata(&args.plaintext)
+        .with_context(|| format!("Failed to read {}", args.plaintext.display()))?
+        .is_dir()
+    {
+        return cryptcheck_tree(cipher, args);
+    }
+
+    let plaintext = BufReader::new(File::open(&args.plaintext)?);
+    let mut encrypted = BufReader::new(File::open(&args.encrypted)?);
+
+    let matches = match &args.hash {
+        Some(hash) => {
+            let hash = CiphertextHash::from_hex(hash)?;
+            let mut header = [0u8; FILE_HEADER_SIZE];
+            encrypted
+                .read_exact(&mut header)
+                .context("Failed to read file header")?;
+            check_file_hash(cipher, plaintext, &read_nonce(&header)?, &hash)?
+        }
+        None => check_file(cipher, plaintext, encrypted)?,
+    };
+
+    if matches {
+        println!("= {}", args.plaintext.display());
+        Ok(())
+    } else {
+        println!("* {}", args.plaintext.display());
+        Err(anyhow!("Files differ"))
+    }
+}
+
+fn cryptcheck_tree(cipher: &Cipher, args: &CryptcheckArgs) -> Result<()> {
+    let hashes = match &args.hashes {
+        Some(path) => parse_hash_list(
+            &fs::read_to_string(path)
+                .with_context(|| format!("Failed to read {}", path.display()))?,
+        )?,
+        None => Default::default(),
+    };
+
+    let report = check_tree(
+        cipher,
+        &args.plaintext,
+        &args.encrypted,
+        &CheckOptions { hashes },
+    )?;
+
+    let mut differences = 0;
+    for result in &report.results {
+        let path = result.path.as_ref().unwrap_or(&result.encrypted_path);
+        let marker = match &result.status {
+            CheckStatus::Match => "=",
+            CheckStatus::Differ => "*",
+            CheckStatus::MissingEncrypted => "+",
+            CheckStatus::MissingPlaintext => "-",
+            CheckStatus::Error(e) => {
+                eprintln!("Failed to check {}: {}", path.display(), e);
+                "!"
+            }
+        };
+        if result.status != CheckStatus::Match {
+            differences += 1;
+        }
+        println!("{} {}", marker, path.display());
+    }
+
+    if differences > 0 {
+        Err(anyhow!(
+            "{} of {} files differ",
+            differences,
+            report.results.len()
+        ))
+    } else {
+        Ok(())
+    }
+}
@@ -0,0 +1,180 @@
+use anyhow::{anyhow, Context, Result};
+use clap::{Args, ValueEnum};
+use rclone_crypt::cipher::Cipher;
+use rclone_crypt::partial::{decrypt_partial, Tail};
+use rclone_crypt::stream::{EncryptedReader, EncryptedWriter, SalvageMode};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::PathBuf;
+
+use crate::{non_std_path, AuthFailure};
+
+#[derive(Args)]
+pub struct DataArgs {
+    /// Input file, stdin if missing or `-`
+    input: Option<PathBuf>,
+
+    /// Output file, stdout if missing or `-`
+    #[arg(long, short)]
+    output: Option<PathBuf>,
+}
+
+impl DataArgs {
+    fn open_input(&self) -> Result<Box<dyn Read>> {
+        Ok(match non_std_path(&self.input) {
+            Some(path) => Box::new(BufReader::new(
+                File::open(path).with_context(|| format!("Failed to open {}", path.display()))?,
+            )),
+            None => Box::new(io::stdin().lock()),
+        })
+    }
+
+    /// Runs `f` with the output writer. A partially written output file is removed on failure.
+    fn with_output(&self, f: impl FnOnce(&mut dyn Write) -> Result<()>) -> Result<()> {
+        match non_std_path(&self.output) {
+            Some(path) => {
+                let file = File::create(path)
+                    .with_context(|| format!("Failed to create {}", path.display()))?;
+                let mut output = BufWriter::new(file);
+
+                let result = f(&mut output).and_then(|_| Ok(output.flush()?));
+                if result.is_err() {
+                    drop(output);
+                    let _ = fs::remove_file(path);
+                }
+                result
+            }
+            None => f(&mut io::stdout().lock()),
+        }
+    }
+}
+
+pub fn encrypt(cipher: &Cipher, args: &DataArgs) -> Result<()> {
+    let mut input = args.open_input()?;
+
+    args.with_output(|output| {
+        let mut writer = EncryptedWriter::new_with_cipher(output, cipher.clone())?;
+        io::copy(&mut input, &mut writer)?;
+        writer.finish()?;
+        Ok(())
+    })
+}
+
+#[derive(Args)]
+pub struct DecryptArgs {
+    #[command(flatten)]
+    data: DataArgs,
+
+    /// Keep going past blocks which fail authentication, zeroing or skipping them
+    #[arg(long, value_enum)]
+    salvage: Option<Salvage>,
+}
+
+/// Command-line counterpart of [`SalvageMode`](SalvageMode).
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Salvage {
+    /// Replace damaged blocks with zeros
+    Zero,
+    /// Leave damaged blocks out
+    Skip,
+}
+
+impl From<Salvage> for SalvageMode {
+    fn from(salvage: Salvage) -> Self {
+        match salvage {
+            Salvage::Zero => SalvageMode::Zero,
+            Salvage::Skip => SalvageMode::Skip,
+        }
+    }
+}
+
+pub fn decrypt(cipher: &Cipher, args: &DecryptArgs) -> Result<()> {
+    let input = args.data.open_input()?;
+    let mut damaged_ranges = Vec::new();
+
+    args.data.with_output(|output| {
+        // The first block is already decrypted while opening the reader
+        let reader = match args.salvage {
+            Some(salvage) => {
+                EncryptedReader::new_with_salvage(input, cipher.clone(), salvage.into())
+            }
+            None => EncryptedReader::new_with_cipher(input, cipher.clone()),
+        };
+        let mut reader = reader.map_err(auth_error)?;
+        io::copy(&mut reader, output).map_err(auth_error)?;
+
+        damaged_ranges = reader.damaged_ranges().to_vec();
+        Ok(())
+    })?;
+
+    if damaged_ranges.is_empty() {
+        return Ok(());
+    }
+    for range in &damaged_ranges {
+        eprintln!("Damaged plaintext bytes {}..{}", range.start, range.end);
+    }
+    Err(anyhow!(AuthFailure(format!(
+        "Salvaged {} damaged blocks",
+        damaged_ranges.len()
+    ))))
+}
+
+#[derive(Args)]
+pub struct PartialArgs {
+    #[command(flatten)]
+    data: DataArgs,
+
+    /// Size of the whole encrypted file, if known
+    #[arg(long)]
+    size: Option<u64>,
+}
+
+pub fn decrypt_partial_file(cipher: &Cipher, args: &PartialArgs) -> Result<()> {
+    cipher.require_data_encryption()?;
+    let input = args.data.open_input()?;
+    let mut report = None;
+
+    args.data.with_output(|output| {
+        report = Some(
+            decrypt_partial(cipher, input, output, args.size).map_err(|e| {
+                if e.downcast_ref::<io::Error>().is_some() {
+                    e
+                } else {
+                    e.context(AuthFailure("Failed to authenticate data".to_string()))
+                }
+            })?,
+        );
+        Ok(())
+    })?;
+
+    let report = report.unwrap();
+    match report.tail {
+        Tail::BlockBoundary => eprintln!("Ends on a block boundary"),
+        Tail::FinalBlock { size } => eprintln!("Ends with a final block of {} bytes", size),
+        Tail::Incomplete { size } => eprintln!("Ends with {} bytes of an incomplete block", size),
+    }
+    match report.plaintext_size {
+        Some(total) => eprintln!(
+            "{} of {} plaintext bytes available",
+            report.available, total
+        ),
+        None => eprintln!("{} plaintext bytes available", report.available),
+    }
+    match report.complete {
+        Some(true) => eprintln!("Complete"),
+        Some(false) => eprintln!("Incomplete"),
+        None => eprintln!("Possibly incomplete"),
+    }
+    Ok(())
+}
+
+/// Marks errors caused by blocks which failed to decrypt as [`AuthFailure`](AuthFailure).
+fn auth_error(e: io::Error) -> anyhow::Error {
+    if e.kind() == ErrorKind::InvalidData {
+        anyhow::Error::new(e).context(AuthFailure(
+            "Failed to authenticate data, wrong keys or corrupted file".to_string(),
+        ))
+    } else {
+        e.into()
+    }
+}
@@ -0,0 +1,164 @@
+use anyhow::{anyhow, Context, Result};
+use clap::Args;
+use rclone_crypt::cipher::Cipher;
+use rclone_crypt::config::{default_config_path, read_crypt_remote};
+use rclone_crypt::obscure::reveal;
+use std::path::{Path, PathBuf};
+
+/// Where the keys come from: either a password and an optional salt, or a crypt remote
+/// from an rclone config file.
+#[derive(Args)]
+pub struct KeyArgs {
+    /// Password (`password` in rclone.conf)
+    #[arg(
+        long,
+        short,
+        global = true,
+        env = "RCLONE_CRYPT_PASSWORD",
+        hide_env_values = true
+    )]
+    password: Option<String>,
+
+    /// Salt (`password2` in rclone.conf). rclone's default salt is used if missing
+    #[arg(
+        long,
+        short,
+        global = true,
+        env = "RCLONE_CRYPT_PASSWORD2",
+        hide_env_values = true
+    )]
+    salt: Option<String>,
+
+    /// The password and salt are obscured, like in rclone.conf
+    #[arg(long, global = true)]
+    obscured: bool,
+
+    /// File data is stored as-is, only names are encrypted (`no_data_encryption` in rclone.conf)
+    #[arg(long, global = true)]
+    no_data_encryption: bool,
+
+    /// Take the keys from this crypt remote of the rclone config
+    #[arg(
+        long,
+        short,
+        global = true,
+        conflicts_with_all = ["password", "salt", "obscured", "no_data_encryption"]
+    )]
+    remote: Option<String>,
+
+    /// rclone config file to read the remote from
+    #[arg(long, global = true, env = "RCLONE_CONFIG")]
+    config: Option<PathBuf>,
+}
+
+/// Revealed keys, and whether file data is encrypted with them.
+pub struct Keys {
+    pub password: String,
+    /// `None` for rclone's default salt.
+    pub salt: Option<String>,
+    pub data_encryption: bool,
+}
+
+impl Keys {
+    pub fn cipher(&self) -> Result<Cipher> {
+        let cipher = Cipher::new(&self.password, self.salt.as_deref())?;
+        Ok(cipher.with_data_encryption(self.data_encryption))
+    }
+}
+
+impl KeyArgs {
+    pub fn cipher(&self) -> Result<Cipher> {
+        self.keys()?.cipher()
+    }
+
+    pub fn keys(&self) -> Result<Keys> {
+        let mut keys = keys(
+            self.remote.as_deref(),
+            self.config.as_deref(),
+            self.password.as_deref(),
+            self.salt.as_deref(),
+            self.obscured,
+            "--password or --remote",
+        )?;
+        keys.data_encryption &= !self.no_data_encryption;
+        Ok(keys)
+    }
+}
+
+/// The keys to switch to, like [`KeyArgs`](KeyArgs).
+#[derive(Args)]
+pub struct NewKeyArgs {
+    /// New password
+    #[arg(long, env = "RCLONE_CRYPT_NEW_PASSWORD", hide_env_values = true)]
+    new_password: Option<String>,
+
+    /// New salt. rclone's default salt is used if missing
+    #[arg(long, env = "RCLONE_CRYPT_NEW_PASSWORD2", hide_env_values = true)]
+    new_salt: Option<String>,
+
+    /// The new password and salt are obscured, like in rclone.conf
+    #[arg(long)]
+    new_obscured: bool,
+
+    /// Take the new keys from this crypt remote of the rclone config
+    #[arg(long, conflicts_with_all = ["new_password", "new_salt", "new_obscured"])]
+    new_remote: Option<String>,
+}
+
+impl NewKeyArgs {
+    /// Builds the new [`Cipher`](Cipher), reading remotes from the same config as `current`.
+    pub fn cipher(&self, current: &KeyArgs) -> Result<Cipher> {
+        keys(
+            self.new_remote.as_deref(),
+            current.config.as_deref(),
+            self.new_password.as_deref(),
+            self.new_salt.as_deref(),
+            self.new_obscured,
+            "--new-password or --new-remote",
+        )?
+        .cipher()
+    }
+}
+
+/// Returns the keys, revealed if needed, with the data encryption of the remote.
+fn keys(
+    remote: Option<&str>,
+    config: Option<&Path>,
+    password: Option<&str>,
+    salt: Option<&str>,
+    obscured: bool,
+    options: &str,
+) -> Result<Keys> {
+    if let Some(remote) = remote {
+        let config = config
+            .map(Path::to_path_buf)
+            .or_else(default_config_path)
+            .ok_or_else(|| anyhow!("Unable to find the rclone config file"))?;
+        let remote = read_crypt_remote(&config, remote)?;
+        remote.check_supported()?;
+        return Ok(Keys {
+            password: remote.password,
+            salt: remote.salt,
+            data_encryption: !remote.no_data_encryption,
+        });
+    }
+
+    let password = password.ok_or_else(|| anyhow!("No keys given, use {}", options))?;
+    let salt = salt.filter(|s| !s.is_empty());
+
+    let (password, salt) = if obscured {
+        let password = reveal(password).context("Failed to reveal password")?;
+        let salt = salt
+            .map(reveal)
+            .transpose()
+            .context("Failed to reveal salt")?;
+        (password, salt)
+    } else {
+        (password.to_string(), salt.map(str::to_string))
+    };
+    Ok(Keys {
+        password,
+        salt,
+        data_encryption: true,
+    })
+}
@@ -0,0 +1,160 @@
+//! `rclone-crypt`: encrypt and decrypt files and names in rclone's crypt format.
+mod cryptcheck;
+mod data;
+mod keys;
+mod names;
+mod password;
+mod rekey;
+mod restore;
+mod serve;
+mod sync;
+mod verify;
+
+use clap::{Parser, Subcommand};
+use rclone_crypt::obscure::{obscure, reveal};
+use std::fmt;
+use std::io::{self, BufRead};
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use keys::KeyArgs;
+
+/// Exit code for any error other than a failed authentication.
+const EXIT_FAILURE: u8 = 1;
+/// Exit code when data or a name fails authentication, usually because of a wrong password or salt.
+const EXIT_AUTH_FAILURE: u8 = 3;
+
+#[derive(Parser)]
+#[command(version, about = "Encrypt and decrypt data in rclone's crypt format")]
+struct Cli {
+    #[command(flatten)]
+    keys: KeyArgs,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Encrypt a file
+    Encrypt(data::DataArgs),
+    /// Decrypt a file
+    Decrypt(data::DecryptArgs),
+    /// Decrypt the complete blocks of a partially downloaded file
+    ///
+    /// Prints how the data ends and how many plaintext bytes were decrypted to stderr.
+    DecryptPartial(data::PartialArgs),
+    /// Encrypt file names, one segment or a `/`-separated name per argument
+    EncryptName(names::NameArgs),
+    /// Decrypt file names, one segment or a `/`-separated name per argument
+    DecryptName(names::DecryptNameArgs),
+    /// Encrypt every segment of a path
+    EncryptPath(names::PathArgs),
+    /// Decrypt every segment of a path
+    DecryptPath(names::PathArgs),
+    /// Sync a plaintext directory into an encrypted directory
+    Sync(sync::SyncArgs),
+    /// Re-encrypt an encrypted directory under new keys, without writing plaintext to disk
+    ///
+    /// Interrupted runs can be resumed by passing the same `--journal` again.
+    Rekey(rekey::RekeyArgs),
+    /// Restore an encrypted directory to plaintext
+    Restore(restore::RestoreArgs),
+    /// Serve an encrypted directory with decrypted names and data
+    Serve(serve::ServeArgs),
+    /// Check plaintext files against their encrypted copies or known hashes of them
+    ///
+    /// Prints `=` for matching files, `*` for differing ones, `+` for files missing from the
+    /// encrypted side, `-` for files missing from the plaintext side and `!` for errors.
+    Cryptcheck(cryptcheck::CryptcheckArgs),
+    /// Verify that every block of an encrypted file or tree authenticates, without decrypting to disk
+    ///
+    /// For trees, also checks that every name decrypts and that no two files share a nonce.
+    Verify(verify::VerifyArgs),
+    /// Check the keys against the names and files of an encrypted directory
+    ///
+    /// Prints `Correct`, `Wrong password`, `Wrong salt`, `Not rclone crypt` or `Inconclusive`.
+    CheckPassword(password::CheckPasswordArgs),
+    /// Obscure a secret for use in rclone.conf
+    Obscure {
+        /// The secret to obscure, read from stdin if missing
+        secret: Option<String>,
+    },
+    /// Reveal a secret obscured by rclone
+    Reveal {
+        /// The obscured secret, read from stdin if missing
+        #[arg(value_name = "OBSCURED")]
+        secret: Option<String>,
+    },
+}
+
+/// Marks an error caused by data or a name which failed to decrypt.
+#[derive(Debug)]
+pub struct AuthFailure(pub String);
+
+impl fmt::Display for AuthFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Returns `arg`, or the first line of stdin if it's missing.
+fn arg_or_stdin(arg: Option<String>) -> anyhow::Result<String> {
+    match arg {
+        Some(arg) => Ok(arg),
+        None => {
+            let mut line = String::new();
+            io::stdin().lock().read_line(&mut line)?;
+            Ok(line.trim_end_matches(['\r', '\n']).to_string())
+        }
+    }
+}
+
+fn run(cli: Cli) -> anyhow::Result<()> {
+    match cli.command {
+        Command::Encrypt(args) => data::encrypt(&cli.keys.cipher()?, &args),
+        Command::Decrypt(args) => data::decrypt(&cli.keys.cipher()?, &args),
+        Command::DecryptPartial(args) => data::decrypt_partial_file(&cli.keys.cipher()?, &args),
+        Command::EncryptName(args) => names::encrypt_names(&cli.keys.cipher()?, &args),
+        Command::DecryptName(args) => names::decrypt_names(&cli.keys.cipher()?, &args),
+        Command::EncryptPath(args) => names::encrypt_path(&cli.keys.cipher()?, &args),
+        Command::DecryptPath(args) => names::decrypt_path(&cli.keys.cipher()?, &args),
+        Command::Sync(args) => sync::sync(&cli.keys.cipher()?, &args),
+        Command::Rekey(args) => rekey::rekey(&cli.keys.cipher()?, &cli.keys, &args),
+        Command::Restore(args) => restore::restore(&cli.keys.cipher()?, &args),
+        Command::Serve(args) => serve::serve(&cli.keys.cipher()?, &args),
+        Command::Cryptcheck(args) => cryptcheck::cryptcheck(&cli.keys.cipher()?, &args),
+        Command::Verify(args) => verify::verify(&cli.keys.cipher()?, &args),
+        Command::CheckPassword(args) => password::check(&cli.keys, &args),
+        Command::Obscure { secret } => {
+            println!("{}", obscure(&arg_or_stdin(secret)?)?);
+            Ok(())
+        }
+        Command::Reveal { secret } => {
+            println!("{}", reveal(&arg_or_stdin(secret)?)?);
+            Ok(())
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+
+    match run(cli) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {:#}", e);
+
+            if e.downcast_ref::<AuthFailure>().is_some() {
+                ExitCode::from(EXIT_AUTH_FAILURE)
+            } else {
+                ExitCode::from(EXIT_FAILURE)
+            }
+        }
+    }
+}
+
+/// Returns `path`, unless it's missing or `-`, which stand for stdin/stdout.
+fn non_std_path(path: &Option<PathBuf>) -> Option<&PathBuf> {
+    path.as_ref().filter(|p| p.as_os_str() != "-")
+}
@@ -0,0 +1,85 @@
+use anyhow::{Context, Result};
+use clap::{Args, ValueEnum};
+use rclone_crypt::cipher::{Cipher, NamePolicy};
+use std::path::PathBuf;
+
+use crate::AuthFailure;
+
+#[derive(Args)]
+pub struct NameArgs {
+    /// The names
+    #[arg(required = true)]
+    names: Vec<String>,
+}
+
+#[derive(Args)]
+pub struct DecryptNameArgs {
+    #[command(flatten)]
+    names: NameArgs,
+
+    /// What to do with names which don't decrypt, like rclone's `strict_names`
+    #[arg(long, value_enum, default_value = "fail")]
+    undecryptable: Undecryptable,
+}
+
+/// Command-line counterpart of [`NamePolicy`](NamePolicy).
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Undecryptable {
+    /// Fail on the first name which doesn't decrypt
+    Fail,
+    /// Leave the name out, with a warning on stderr
+    Skip,
+    /// Print the name as it is
+    PassThrough,
+}
+
+impl From<Undecryptable> for NamePolicy {
+    fn from(undecryptable: Undecryptable) -> Self {
+        match undecryptable {
+            Undecryptable::Fail => NamePolicy::Fail,
+            Undecryptable::Skip => NamePolicy::Skip,
+            Undecryptable::PassThrough => NamePolicy::PassThrough,
+        }
+    }
+}
+
+#[derive(Args)]
+pub struct PathArgs {
+    /// The path
+    path: PathBuf,
+}
+
+pub fn encrypt_names(cipher: &Cipher, args: &NameArgs) -> Result<()> {
+    for name in &args.names {
+        println!("{}", cipher.encrypt_file_name(name)?);
+    }
+    Ok(())
+}
+
+pub fn decrypt_names(cipher: &Cipher, args: &DecryptNameArgs) -> Result<()> {
+    let names = args.names.names.iter().map(String::as_str);
+    let listed = cipher
+        .decrypt_listing(names, args.undecryptable.into())
+        .with_context(|| AuthFailure("Failed to decrypt names".to_string()))?;
+
+    for listed in &listed {
+        match listed.name() {
+            Some(name) => println!("{}", name),
+            None => eprintln!("Skipping {}: failed to decrypt", listed.encrypted()),
+        }
+    }
+    Ok(())
+}
+
+pub fn encrypt_path(cipher: &Cipher, args: &PathArgs) -> Result<()> {
+    println!("{}", cipher.encrypt_path(&args.path)?.display());
+    Ok(())
+}
+
+pub fn decrypt_path(cipher: &Cipher, args: &PathArgs) -> Result<()> {
+    let decrypted = cipher
+        .decrypt_path(&args.path)
+        .with_context(|| AuthFailure(format!("Failed to decrypt {}", args.path.display())))?;
+    println!("{}", decrypted.display());
+    Ok(())
+}
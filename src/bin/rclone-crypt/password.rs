@@ -0,0 +1,68 @@
+use anyhow::{anyhow, Result};
+use clap::Args;
+use rclone_crypt::password::{check_password, Sample, Verdict};
+use std::path::PathBuf;
+
+use crate::keys::KeyArgs;
+use crate::AuthFailure;
+
+#[derive(Args)]
+pub struct CheckPasswordArgs {
+    /// Encrypted directory to take sample names and files from
+    source: PathBuf,
+
+    /// Maximum number of names and of files to check
+    #[arg(long, default_value_t = 100)]
+    limit: usize,
+}
+
+pub fn check(keys: &KeyArgs, args: &CheckPasswordArgs) -> Result<()> {
+    let keys = keys.keys()?;
+    let sample = Sample::from_tree(&args.source, args.limit)?;
+    let check = check_password(&keys.password, keys.salt.as_deref(), &sample)?;
+
+    let stats = &check.stats;
+    eprintln!(
+        "Names: {} checked, {} well-formed, {} decrypted",
+        stats.names, stats.well_formed_names, stats.decrypted_names
+    );
+    eprintln!(
+        "Files: {} checked, {} well-formed, {} with data, {} authenticated",
+        stats.files, stats.well_formed_files, stats.testable_files, stats.authenticated_files
+    );
+
+    match check.verdict {
+        Verdict::Correct => {
+            println!("Correct");
+            Ok(())
+        }
+        Verdict::WrongSalt { matching_salt } => {
+            println!("Wrong salt");
+            match matching_salt {
+                Some(salt) => eprintln!("The password matches with the salt {:?}", salt),
+                None => eprintln!("The password matches with rclone's default salt"),
+            }
+            Err(anyhow!(AuthFailure("Wrong salt".to_string())))
+        }
+        Verdict::WrongPassword => {
+            println!("Wrong password");
+            Err(anyhow!(AuthFailure("Wrong password".to_string())))
+        }
+        Verdict::WrongPasswordOrSalt => {
+            println!("Wrong password or salt");
+            eprintln!("No salt was given, so the remote may use a custom one");
+            Err(anyhow!(AuthFailure("Wrong password or salt".to_string())))
+        }
+        Verdict::NotRcloneCrypt => {
+            println!("Not rclone crypt");
+            Err(anyhow!(
+                "{} is not encrypted by rclone crypt",
+                args.source.display()
+            ))
+        }
+        Verdict::Inconclusive => {
+            println!("Inconclusive");
+            Err(anyhow!("Nothing to check the keys against"))
+        }
+    }
+}
@@ -0,0 +1,53 @@
+use anyhow::{Context, Result};
+use clap::Args;
+use rclone_crypt::cipher::Cipher;
+use rclone_crypt::rekey::{rekey_tree, RekeyOptions};
+use std::io;
+use std::path::PathBuf;
+
+use crate::keys::{KeyArgs, NewKeyArgs};
+use crate::AuthFailure;
+
+#[derive(Args)]
+pub struct RekeyArgs {
+    /// Encrypted directory, under the current keys
+    source: PathBuf,
+
+    /// Directory to write the tree re-encrypted under the new keys to
+    destination: PathBuf,
+
+    #[command(flatten)]
+    new_keys: NewKeyArgs,
+
+    /// Record re-encrypted files in this file, and skip the ones already recorded in it
+    #[arg(long)]
+    journal: Option<PathBuf>,
+}
+
+pub fn rekey(cipher: &Cipher, keys: &KeyArgs, args: &RekeyArgs) -> Result<()> {
+    let new = args.new_keys.cipher(keys)?;
+    // Checked up front, as errors of rekey_tree() are taken as authentication failures
+    cipher.require_data_encryption()?;
+    new.require_data_encryption()
+        .context("The new keys don't encrypt data")?;
+    let options = RekeyOptions {
+        journal: args.journal.clone(),
+    };
+
+    let report =
+        rekey_tree(cipher, &new, &args.source, &args.destination, &options).map_err(|e| {
+            // Anything but an I/O error means a name or file failed to decrypt
+            if e.downcast_ref::<io::Error>().is_some() {
+                e
+            } else {
+                e.context(AuthFailure(
+                    "Failed to decrypt with the current keys".to_string(),
+                ))
+            }
+        })?;
+    eprintln!(
+        "{} files re-encrypted, {} directories created, {} already done",
+        report.files, report.dirs, report.resumed
+    );
+    Ok(())
+}
@@ -0,0 +1,75 @@
+use anyhow::{anyhow, Result};
+use clap::Args;
+use rclone_crypt::cipher::Cipher;
+use rclone_crypt::restore::{restore_tree, RestoreFailureKind, RestoreOptions};
+use std::path::PathBuf;
+
+use crate::data::Salvage;
+use crate::AuthFailure;
+
+#[derive(Args)]
+pub struct RestoreArgs {
+    /// Encrypted directory
+    source: PathBuf,
+
+    /// Plaintext directory
+    destination: PathBuf,
+
+    /// Only restore this plaintext file or directory, relative to the source
+    #[arg(long)]
+    path: Option<PathBuf>,
+
+    /// Keep going past blocks which fail authentication, zeroing or skipping them
+    #[arg(long, value_enum)]
+    salvage: Option<Salvage>,
+}
+
+pub fn restore(cipher: &Cipher, args: &RestoreArgs) -> Result<()> {
+    let options = RestoreOptions {
+        path: args.path.clone(),
+        salvage: args.salvage.map(Into::into),
+    };
+
+    let report = restore_tree(cipher, &args.source, &args.destination, &options)?;
+
+    for failure in &report.failures {
+        let path = failure.path.as_ref().unwrap_or(&failure.encrypted_path);
+        eprintln!("Failed to restore {}: {:#}", path.display(), failure.error);
+    }
+    for damaged in &report.damaged {
+        let ranges: Vec<_> = damaged
+            .ranges
+            .iter()
+            .map(|range| format!("{}..{}", range.start, range.end))
+            .collect();
+        eprintln!(
+            "Salvaged {}, damaged bytes: {}",
+            damaged.path.display(),
+            ranges.join(", ")
+        );
+    }
+    eprintln!(
+        "{} files and {} directories restored, {} failed, {} damaged",
+        report.files,
+        report.dirs,
+        report.failures.len(),
+        report.damaged.len()
+    );
+
+    let failed = report.failures.len() + report.damaged.len();
+    if !report.damaged.is_empty()
+        || report
+            .failures
+            .iter()
+            .any(|failure| failure.kind != RestoreFailureKind::Io)
+    {
+        Err(anyhow!(AuthFailure(format!(
+            "{} entries failed to decrypt",
+            failed
+        ))))
+    } else if failed > 0 {
+        Err(anyhow!("{} entries failed to restore", failed))
+    } else {
+        Ok(())
+    }
+}
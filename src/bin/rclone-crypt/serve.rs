@@ -0,0 +1,94 @@
+use anyhow::{Context, Result};
+use clap::{Args, Subcommand};
+use rclone_crypt::cipher::Cipher;
+use rclone_crypt::cryptfs::CryptFs;
+use rclone_crypt::server::{HttpServer, WebDavServer};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+#[derive(Args)]
+pub struct ServeArgs {
+    #[command(subcommand)]
+    protocol: Protocol,
+}
+
+#[derive(Subcommand)]
+enum Protocol {
+    /// Serve an encrypted directory read-only over HTTP, with plaintext names and data
+    ///
+    /// Files support range requests, directories are listed as HTML or, with `?format=json`, JSON.
+    Http(ListenArgs),
+    /// Serve an encrypted directory over WebDAV, with plaintext names and data
+    Webdav(WebDavArgs),
+}
+
+#[derive(Args)]
+struct ListenArgs {
+    /// Encrypted directory to serve
+    root: PathBuf,
+
+    /// Address to listen on
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    addr: String,
+}
+
+#[derive(Args)]
+struct WebDavArgs {
+    #[command(flatten)]
+    listen: ListenArgs,
+
+    /// User name clients must log in with
+    #[arg(long, requires = "pass")]
+    user: Option<String>,
+
+    /// Password clients must log in with
+    #[arg(
+        long,
+        env = "RCLONE_CRYPT_SERVE_PASS",
+        hide_env_values = true,
+        requires = "user"
+    )]
+    pass: Option<String>,
+}
+
+pub fn serve(cipher: &Cipher, args: &ServeArgs) -> Result<()> {
+    match &args.protocol {
+        Protocol::Http(args) => serve_http(cipher, args),
+        Protocol::Webdav(args) => serve_webdav(cipher, args),
+    }
+}
+
+impl ListenArgs {
+    fn fs(&self, cipher: &Cipher) -> Result<CryptFs> {
+        CryptFs::new(&self.root, cipher.clone())
+            .with_context(|| format!("Failed to open {}", self.root.display()))
+    }
+
+    fn announce(&self, addr: Option<SocketAddr>) {
+        match addr {
+            Some(addr) => eprintln!("Serving {} on http://{}/", self.root.display(), addr),
+            None => eprintln!("Serving {}", self.root.display()),
+        }
+    }
+}
+
+fn serve_http(cipher: &Cipher, args: &ListenArgs) -> Result<()> {
+    let server = HttpServer::bind(args.addr.as_str(), args.fs(cipher)?)
+        .with_context(|| format!("Failed to listen on {}", args.addr))?;
+    args.announce(server.local_addr());
+    server.serve();
+    Ok(())
+}
+
+fn serve_webdav(cipher: &Cipher, args: &WebDavArgs) -> Result<()> {
+    let listen = &args.listen;
+    let mut server = WebDavServer::bind(listen.addr.as_str(), listen.fs(cipher)?)
+        .with_context(|| format!("Failed to listen on {}", listen.addr))?;
+    if let (Some(user), Some(pass)) = (&args.user, &args.pass) {
+        server = server.with_basic_auth(user, pass);
+    }
+
+    listen.announce(server.local_addr());
+    server.serve();
+    Ok(())
+}
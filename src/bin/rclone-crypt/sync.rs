@@ -0,0 +1,59 @@
+use anyhow::Result;
+use clap::Args;
+use rclone_crypt::cipher::Cipher;
+use rclone_crypt::sync::{sync_tree, SyncAction, SyncOptions};
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[derive(Args)]
+pub struct SyncArgs {
+    /// Plaintext directory
+    source: PathBuf,
+
+    /// Encrypted directory
+    destination: PathBuf,
+
+    /// Delete encrypted entries which aren't in the source
+    #[arg(long)]
+    delete: bool,
+
+    /// Only print what would be done
+    #[arg(long, short = 'n')]
+    dry_run: bool,
+
+    /// Modification times closer than this many milliseconds are considered equal
+    #[arg(long, default_value_t = 0)]
+    modify_window: u64,
+}
+
+pub fn sync(cipher: &Cipher, args: &SyncArgs) -> Result<()> {
+    let options = SyncOptions {
+        delete: args.delete,
+        dry_run: args.dry_run,
+        modify_window: Duration::from_millis(args.modify_window),
+    };
+
+    let report = sync_tree(cipher, &args.source, &args.destination, &options)?;
+
+    for change in &report.changes {
+        let action = match change.action {
+            SyncAction::CreateDir => "mkdir",
+            SyncAction::Create => "create",
+            SyncAction::Update => "update",
+            SyncAction::Delete => "delete",
+        };
+        let path = change.path.as_ref().unwrap_or(&change.encrypted_path);
+        println!("{:<6} {}", action, path.display());
+    }
+    for path in &report.skipped {
+        eprintln!("Skipped {}: not a file or directory", path.display());
+    }
+    eprintln!(
+        "{} changed, {} unchanged{}",
+        report.changes.len(),
+        report.unchanged,
+        if args.dry_run { " (dry run)" } else { "" }
+    );
+
+    Ok(())
+}
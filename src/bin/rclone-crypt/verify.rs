@@ -0,0 +1,123 @@
+use anyhow::{anyhow, Context, Result};
+use clap::Args;
+use rclone_crypt::cipher::Cipher;
+use rclone_crypt::verify::{verify_file, verify_tree, FileProblem, FileReport};
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use crate::AuthFailure;
+
+#[derive(Args)]
+pub struct VerifyArgs {
+    /// Encrypted file or directory
+    path: PathBuf,
+
+    /// List every block which failed to authenticate
+    #[arg(long)]
+    blocks: bool,
+}
+
+pub fn verify(cipher: &Cipher, args: &VerifyArgs) -> Result<()> {
+    cipher.require_data_encryption()?;
+    if fs::metadata(&args.path)
+        .with_context(|| format!("Failed to read {}", args.path.display()))?
+        .is_dir()
+    {
+        return verify_dir(cipher, args);
+    }
+
+    let report = verify_file(cipher, BufReader::new(File::open(&args.path)?))?;
+    print_file(&args.path, &report, args.blocks);
+
+    if report.is_ok() {
+        Ok(())
+    } else {
+        Err(anyhow!(AuthFailure("Verification failed".to_string())))
+    }
+}
+
+fn verify_dir(cipher: &Cipher, args: &VerifyArgs) -> Result<()> {
+    let report = verify_tree(cipher, &args.path)?;
+    let mut io_errors = 0;
+
+    for file in &report.files {
+        let path = file.path.as_ref().unwrap_or(&file.encrypted_path);
+        match &file.report {
+            Ok(file_report) => print_file(path, file_report, args.blocks),
+            Err(e) => {
+                println!("ERROR {}: {}", path.display(), e);
+                io_errors += 1;
+            }
+        }
+    }
+    for path in &report.bad_names {
+        println!("BADNAME {}", path.display());
+    }
+    for paths in &report.duplicate_nonces {
+        let paths: Vec<_> = paths
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect();
+        println!("DUPNONCE {}", paths.join(" "));
+    }
+
+    let failed = report
+        .files
+        .iter()
+        .filter(|file| file.report.as_ref().is_ok_and(|report| !report.is_ok()))
+        .count();
+    eprintln!(
+        "{} files, {} failed, {} bad names, {} reused nonces, {} errors",
+        report.files.len(),
+        failed,
+        report.bad_names.len(),
+        report.duplicate_nonces.len(),
+        io_errors
+    );
+
+    if failed > 0 || !report.bad_names.is_empty() || !report.duplicate_nonces.is_empty() {
+        Err(anyhow!(AuthFailure("Verification failed".to_string())))
+    } else if io_errors > 0 {
+        Err(anyhow!("Some files couldn't be read"))
+    } else {
+        Ok(())
+    }
+}
+
+fn print_file(path: &Path, report: &FileReport, blocks: bool) {
+    if report.is_ok() {
+        println!("OK {}", path.display());
+        return;
+    }
+
+    let mut reasons = Vec::new();
+    match report.problem {
+        Some(FileProblem::ShortHeader { size }) => {
+            reasons.push(format!("header is only {} bytes", size))
+        }
+        Some(FileProblem::BadMagic) => reasons.push("bad magic".to_string()),
+        Some(FileProblem::TrailingFragment { offset, size }) => {
+            reasons.push(format!("{} byte fragment at offset {}", size, offset))
+        }
+        None => {}
+    }
+    if let Some(first) = report.first_failed_block {
+        reasons.push(format!(
+            "{} of {} blocks failed, first is block {}",
+            report.failed_blocks,
+            report.blocks.len(),
+            first
+        ));
+    }
+    println!("FAILED {}: {}", path.display(), reasons.join(", "));
+
+    if blocks {
+        for block in report.blocks.iter().filter(|block| !block.ok) {
+            println!(
+                "  block {} at offset {} ({} bytes)",
+                block.block_id, block.offset, block.size
+            );
+        }
+    }
+}
@@ -2,15 +2,21 @@
 /// Note: ONLY Name Encryption is currently supported; not obfuscation.
 use anyhow::{anyhow, Result};
 
+use alloc::string::String;
+use alloc::vec::Vec;
+
 use crate::eme;
-use crate::eme::{AesEme, NAME_CIPHER_BLOCK_SIZE};
+use crate::eme::{AesEme, Tweak, EME_MAX_BLOCKS, NAME_CIPHER_BLOCK_SIZE};
 use arrayref::array_ref;
-use block_modes::block_padding::{Padding, Pkcs7};
 use data_encoding::{BASE32HEX, BASE32HEX_NOPAD};
 use scrypt::{scrypt, Params};
+#[cfg(feature = "std")]
 use std::path::{Component, Path, PathBuf};
+use subtle::{ConditionallySelectable, ConstantTimeEq, ConstantTimeGreater, CtOption};
 
 const TOTAL_KEY_SIZE: usize = 32 + 32 + eme::NAME_CIPHER_BLOCK_SIZE; // this should probably be defined more nicely
+/// Longest encrypted name (after decoding) EME can decrypt.
+const MAX_ENCRYPTED_NAME_SIZE: usize = EME_MAX_BLOCKS * NAME_CIPHER_BLOCK_SIZE;
 const RCLONE_DEFAULT_SALT: [u8; 16] = [
     0xA8, 0x0D, 0xF4, 0x3A, 0x8F, 0xBD, 0x03, 0x08, 0xA7, 0xCA, 0xB8, 0x3E, 0x58, 0x1F, 0x86, 0xB1,
 ];
@@ -31,7 +37,10 @@ pub struct Cipher {
 
     /// The tweak used for name encryption.
     /// This is generated from the password and salt using scrypt.
-    tweak_key: TweakKey,
+    tweak: Tweak,
+
+    /// Whether file data is encrypted, see [`with_data_encryption()`](Cipher::with_data_encryption).
+    data_encryption: bool,
 }
 
 /// Calculates the keys using scrypt.
@@ -41,7 +50,7 @@ fn generate_keys(password: &str, salt: &[u8]) -> Result<(FileKey, NameKey, Tweak
     let params = Params::new(14, 8, 1, Params::RECOMMENDED_LEN).unwrap();
 
     let mut key = [0u8; TOTAL_KEY_SIZE];
-    scrypt(password.as_bytes(), salt, &params, &mut key)?; /* slow? */
+    scrypt(password.as_bytes(), salt, &params, &mut key).map_err(|e| anyhow!("{}", e))?; /* slow? */
 
     Ok((
         *array_ref!(key, 0, 32),
@@ -59,10 +68,115 @@ fn decode_segment(name: &str) -> Result<Vec<u8>> {
     let equals = rounded_up_chars - name.len();
 
     let result = name.to_uppercase() + &"========"[..equals];
-    let result = BASE32HEX.decode(result.as_bytes())?;
+    let result = BASE32HEX
+        .decode(result.as_bytes())
+        .map_err(|e| anyhow!("{}", e))?;
     Ok(result)
 }
 
+/// Returns whether `segment` has the form of an encrypted name, regardless of the key.
+#[cfg(feature = "std")]
+pub(crate) fn is_encrypted_segment(segment: &str) -> bool {
+    decode_segment(segment).is_ok_and(|decoded| {
+        !decoded.is_empty()
+            && decoded.len() % NAME_CIPHER_BLOCK_SIZE == 0
+            && decoded.len() <= MAX_ENCRYPTED_NAME_SIZE
+    })
+}
+
+/// Returns whether the decrypted `name` is a single plain path segment, which can be joined to
+/// a path without leaving it: not empty, `.` or `..`, and without `/`, NUL or a root.
+#[cfg(feature = "std")]
+pub(crate) fn is_plain_segment(name: &str) -> bool {
+    let mut components = Path::new(name).components();
+    !name.contains(['/', '\0'])
+        && matches!(components.next(), Some(Component::Normal(_)))
+        && components.next().is_none()
+}
+
+/// Pads `data` to a multiple of [`NAME_CIPHER_BLOCK_SIZE`](NAME_CIPHER_BLOCK_SIZE) using Pkcs7.
+fn pkcs7_pad(data: &[u8]) -> Vec<u8> {
+    let padding = NAME_CIPHER_BLOCK_SIZE - data.len() % NAME_CIPHER_BLOCK_SIZE;
+
+    let mut buffer = Vec::with_capacity(data.len() + padding);
+    buffer.extend_from_slice(data);
+    buffer.resize(data.len() + padding, padding as u8);
+    buffer
+}
+
+/// Returns the length of `data` without its Pkcs7 padding, if the padding is valid.
+///
+/// The check doesn't branch on the contents of `data`, only on its length, which must be
+/// a non-zero multiple of [`NAME_CIPHER_BLOCK_SIZE`](NAME_CIPHER_BLOCK_SIZE).
+fn pkcs7_unpad_len(data: &[u8]) -> CtOption<usize> {
+    let last_block = &data[data.len() - NAME_CIPHER_BLOCK_SIZE..];
+    let padding = last_block[NAME_CIPHER_BLOCK_SIZE - 1];
+
+    // 1 <= padding <= NAME_CIPHER_BLOCK_SIZE
+    let mut valid = padding.ct_gt(&0) & !padding.ct_gt(&(NAME_CIPHER_BLOCK_SIZE as u8));
+
+    // Every byte which is part of the padding must be equal to the padding length
+    for (i, byte) in last_block.iter().rev().enumerate() {
+        let in_padding = padding.ct_gt(&(i as u8));
+        valid &= !in_padding | byte.ct_eq(&padding);
+    }
+
+    let padding = u8::conditional_select(&0, &padding, valid);
+    CtOption::new(data.len() - padding as usize, valid)
+}
+
+/// What listings do with names which don't decrypt, like rclone's `strict_names` option.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NamePolicy {
+    /// Fail the whole listing, like rclone with `strict_names = true`.
+    Fail,
+    /// Leave the entry out, like rclone by default, reporting it as [`ListedName::Skipped`](ListedName::Skipped).
+    #[default]
+    Skip,
+    /// Keep the entry under its raw name, reporting it as [`ListedName::Undecryptable`](ListedName::Undecryptable).
+    PassThrough,
+}
+
+/// The result for a single name of [`Cipher::decrypt_listing()`](Cipher::decrypt_listing).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ListedName {
+    /// The name decrypted to a plaintext name.
+    Decrypted {
+        encrypted: String,
+        /// The plaintext name.
+        name: String,
+    },
+    /// The name doesn't decrypt and was left out by [`NamePolicy::Skip`](NamePolicy::Skip).
+    Skipped { encrypted: String },
+    /// The name doesn't decrypt and was kept by [`NamePolicy::PassThrough`](NamePolicy::PassThrough).
+    Undecryptable { encrypted: String },
+}
+
+impl ListedName {
+    /// Returns the name to list: the decrypted name, the raw name of undecryptable entries,
+    /// or nothing for skipped entries.
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            ListedName::Decrypted { name, .. } => Some(name),
+            ListedName::Skipped { .. } => None,
+            ListedName::Undecryptable { encrypted } => Some(encrypted),
+        }
+    }
+
+    /// Returns the name as it was listed.
+    pub fn encrypted(&self) -> &str {
+        match self {
+            ListedName::Decrypted { encrypted, .. }
+            | ListedName::Skipped { encrypted }
+            | ListedName::Undecryptable { encrypted } => encrypted,
+        }
+    }
+
+    pub fn is_decrypted(&self) -> bool {
+        matches!(self, ListedName::Decrypted { .. })
+    }
+}
+
 impl Cipher {
     pub fn new(password: &str, salt: Option<&str>) -> Result<Self> {
         let salt_bytes = salt.map_or(&RCLONE_DEFAULT_SALT[..], str::as_bytes);
@@ -70,65 +184,91 @@ impl Cipher {
 
         Ok(Cipher {
             file_key: keys.0,
-            tweak_key: keys.2,
-            eme: AesEme::new(keys.1)?,
+            tweak: Tweak(keys.2),
+            eme: AesEme::new_from_slice(&keys.1)?,
+            data_encryption: true,
         })
     }
 
-    fn encrypt_segment(&self, segment: &str) -> Result<String> {
-        if segment.is_empty() {
-            return Ok(String::new());
-        }
+    /// Turns the encryption of file data on or off, like rclone's `no_data_encryption` option.
+    ///
+    /// Without data encryption only names are encrypted: the stream readers, writers and codecs
+    /// pass data through as-is, without a file header or blocks.
+    pub fn with_data_encryption(mut self, enabled: bool) -> Self {
+        self.data_encryption = enabled;
+        self
+    }
 
-        let rounded_up_size =
-            ((segment.len() / NAME_CIPHER_BLOCK_SIZE) + 1) * NAME_CIPHER_BLOCK_SIZE;
+    /// Returns whether file data is encrypted, see [`with_data_encryption()`](Cipher::with_data_encryption).
+    pub fn encrypts_data(&self) -> bool {
+        self.data_encryption
+    }
 
-        let mut buffer = vec![0u8; rounded_up_size];
-        buffer[..segment.len()].copy_from_slice(segment.as_bytes());
+    /// Fails if file data isn't encrypted, for operations on the encrypted format itself like
+    /// authenticating blocks, which have nothing to work on otherwise.
+    pub fn require_data_encryption(&self) -> Result<()> {
+        if self.data_encryption {
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "File data isn't encrypted (no_data_encryption), only names are"
+            ))
+        }
+    }
 
-        if let Err(_error) = Pkcs7::pad(&mut buffer, segment.len(), NAME_CIPHER_BLOCK_SIZE) {
-            return Err(anyhow!(
-                "Pkcs7 padding failed on message of size {}",
-                segment.len()
-            ));
+    pub(crate) fn encrypt_segment(&self, segment: &str) -> Result<String> {
+        if segment.is_empty() {
+            return Ok(String::new());
         }
 
-        let encrypted = self.eme.encrypt(&self.tweak_key, &buffer)?;
-        let encoded = encode_segment(&encrypted);
+        let mut buffer = pkcs7_pad(segment.as_bytes());
+        self.eme.encrypt(&self.tweak, &mut buffer)?;
+        let encoded = encode_segment(&buffer);
 
         Ok(encoded)
     }
 
-    fn decrypt_segment(&self, segment: &str) -> Result<String> {
-        let decoded = decode_segment(segment)?;
+    pub(crate) fn decrypt_segment(&self, segment: &str) -> Result<String> {
+        let mut decoded = decode_segment(segment)?;
 
         if decoded.is_empty() || decoded.len() % NAME_CIPHER_BLOCK_SIZE != 0 {
             return Err(anyhow!("Decoded name is not a multiple of block size"));
         }
 
-        if decoded.len() > 2048 {
+        if decoded.len() > MAX_ENCRYPTED_NAME_SIZE {
             return Err(anyhow!("Decoded name is too long"));
         }
 
         // paddedPlaintext := eme.Transform(c.block, c.nameTweak[:], rawCiphertext, eme.DirectionDecrypt)
-        let padded_plaintext = self.eme.decrypt(&self.tweak_key, &decoded)?;
+        self.eme.decrypt(&self.tweak, &mut decoded)?;
+        let padded_plaintext = decoded;
 
         // plaintext, err := pkcs7.Unpad(nameCipherBlockSize, paddedPlaintext)
-        let plaintext = match Pkcs7::unpad(&padded_plaintext) {
-            Ok(x) => x,
-            Err(_) => return Err(anyhow!("Failed to unpad padded plaintext")),
-        };
+        // Only the padding is checked in constant time. The UTF-8 check below returns early,
+        // so it only runs on plaintext whose padding is valid, and fails the same way.
+        let unpadded_len = Option::<usize>::from(pkcs7_unpad_len(&padded_plaintext))
+            .ok_or_else(|| anyhow!("Failed to decrypt name"))?;
+
+        let mut plaintext = padded_plaintext;
+        plaintext.truncate(unpadded_len);
 
-        let plaintext = String::from_utf8(plaintext.to_vec())?;
+        let plaintext =
+            String::from_utf8(plaintext).map_err(|_| anyhow!("Failed to decrypt name"))?;
         Ok(plaintext)
     }
 
+    #[cfg(feature = "std")]
     pub fn encrypt_path(&self, path: &Path) -> Result<PathBuf> {
         let mut result = PathBuf::new();
 
         for component in path.components() {
             match component {
-                Component::Normal(p) => result.push(self.encrypt_segment(p.to_str().unwrap())?),
+                Component::Normal(p) => {
+                    let segment = p
+                        .to_str()
+                        .ok_or_else(|| anyhow!("Path is not valid UTF-8"))?;
+                    result.push(self.encrypt_segment(segment)?)
+                }
                 c => result.push(c),
             }
         }
@@ -136,12 +276,19 @@ impl Cipher {
         Ok(result)
     }
 
+    #[cfg(feature = "std")]
     pub fn decrypt_path(&self, path: &Path) -> Result<PathBuf> {
         let mut result = PathBuf::new();
 
         for component in path.components() {
             match component {
-                Component::Normal(p) => result.push(self.decrypt_segment(p.to_str().unwrap())?),
+                Component::Normal(p) => {
+                    let segment = p
+                        .to_str()
+                        .ok_or_else(|| anyhow!("Path is not valid UTF-8"))?;
+                    // Crafted names could otherwise replace or leave the path
+                    result.push(self.decrypt_plain_segment(segment)?)
+                }
                 c => result.push(c),
             }
         }
@@ -158,6 +305,21 @@ impl Cipher {
         Ok(segments.join("/"))
     }
 
+    /// Decrypts a single `segment` of a path, failing unless it decrypts to a plain name which
+    /// can be joined to a path without leaving it, see [`is_plain_segment()`](is_plain_segment).
+    #[cfg(feature = "std")]
+    pub(crate) fn decrypt_plain_segment(&self, segment: &str) -> Result<String> {
+        let name = self.decrypt_segment(segment)?;
+        if is_plain_segment(&name) {
+            Ok(name)
+        } else {
+            Err(anyhow!(
+                "Name decrypts to {:?}, which is not a plain name",
+                name
+            ))
+        }
+    }
+
     pub fn decrypt_file_name(&self, name: &str) -> Result<String> {
         let segments = name
             .split('/')
@@ -167,7 +329,156 @@ impl Cipher {
         Ok(segments.join("/"))
     }
 
+    /// Decrypts the names of a directory listing, with one result per name in the same order.
+    /// Names which don't decrypt are handled by `policy`, so listings of remotes holding stray
+    /// plaintext or foreign files stay usable.
+    ///
+    /// With [`NamePolicy::Fail`](NamePolicy::Fail) the first name which doesn't decrypt fails
+    /// the listing, naming it in the error.
+    pub fn decrypt_listing<'a>(
+        &self,
+        names: impl IntoIterator<Item = &'a str>,
+        policy: NamePolicy,
+    ) -> Result<Vec<ListedName>> {
+        names
+            .into_iter()
+            .map(|encrypted| {
+                let name = match self.decrypt_file_name(encrypted) {
+                    Ok(name) => name,
+                    Err(e) => {
+                        let encrypted = String::from(encrypted);
+                        return match policy {
+                            NamePolicy::Fail => Err(e.context(alloc::format!(
+                                "Failed to decrypt listed name {:?}",
+                                encrypted
+                            ))),
+                            NamePolicy::Skip => Ok(ListedName::Skipped { encrypted }),
+                            NamePolicy::PassThrough => Ok(ListedName::Undecryptable { encrypted }),
+                        };
+                    }
+                };
+                Ok(ListedName::Decrypted {
+                    encrypted: String::from(encrypted),
+                    name,
+                })
+            })
+            .collect()
+    }
+
     pub fn get_file_key(&self) -> FileKey {
         self.file_key
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::cipher::{pkcs7_pad, pkcs7_unpad_len, Cipher, ListedName, NamePolicy};
+    use crate::dudect::{leakage_t, T_THRESHOLD};
+    use crate::eme::NAME_CIPHER_BLOCK_SIZE;
+
+    fn unpad_len(data: &[u8]) -> Option<usize> {
+        pkcs7_unpad_len(data).into()
+    }
+
+    #[test]
+    fn pkcs7_should_roundtrip() {
+        for len in 0..40 {
+            let data = vec![b'x'; len];
+            let padded = pkcs7_pad(&data);
+
+            assert_eq!(padded.len() % NAME_CIPHER_BLOCK_SIZE, 0);
+            assert!(padded.len() > len);
+            assert_eq!(unpad_len(&padded), Some(len));
+        }
+    }
+
+    #[test]
+    fn pkcs7_should_reject_bad_padding() {
+        let mut block = [b'x'; 16];
+
+        block[15] = 0;
+        assert_eq!(unpad_len(&block), None);
+
+        block[15] = 17;
+        assert_eq!(unpad_len(&block), None);
+
+        block[15] = 3;
+        block[14] = 3;
+        assert_eq!(unpad_len(&block), None);
+
+        block[13] = 3;
+        assert_eq!(unpad_len(&block), Some(13));
+    }
+
+    #[test]
+    fn decrypt_failures_should_be_indistinguishable() {
+        let cipher = Cipher::new("test", Some("test")).unwrap();
+        let other = Cipher::new("other", Some("test")).unwrap();
+
+        // Valid base32 of a whole number of blocks, but garbage after decryption
+        let encrypted = other.encrypt_file_name("test.txt").unwrap();
+        let error = cipher.decrypt_file_name(&encrypted).unwrap_err();
+
+        assert_eq!(error.to_string(), "Failed to decrypt name");
+    }
+
+    #[test]
+    fn listings_should_follow_the_name_policy() {
+        let cipher = Cipher::new("test", Some("test")).unwrap();
+        let names = ["g83ktjf47lcm0bprvp034uulhg", "README.md"];
+
+        let listed = cipher.decrypt_listing(names, NamePolicy::Skip).unwrap();
+        assert_eq!(
+            listed,
+            [
+                ListedName::Decrypted {
+                    encrypted: names[0].to_string(),
+                    name: "test.txt".to_string()
+                },
+                ListedName::Skipped {
+                    encrypted: names[1].to_string()
+                }
+            ]
+        );
+        let listed: Vec<_> = listed.iter().filter_map(ListedName::name).collect();
+        assert_eq!(listed, ["test.txt"]);
+
+        let listed = cipher
+            .decrypt_listing(names, NamePolicy::PassThrough)
+            .unwrap();
+        assert!(!listed[1].is_decrypted());
+        assert_eq!(listed[1].name(), Some("README.md"));
+        assert_eq!(listed[1].encrypted(), "README.md");
+
+        let error = cipher.decrypt_listing(names, NamePolicy::Fail).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Failed to decrypt listed name \"README.md\""
+        );
+    }
+
+    #[test]
+    #[ignore = "timing test; run with `cargo test --release -- --ignored`"]
+    fn pkcs7_unpad_should_be_constant_time() {
+        // Class 0 has valid padding, class 1 is random and (almost always) invalid
+        let t = leakage_t(
+            |class, rng| {
+                let mut block = [0u8; 32];
+                rng.fill(&mut block);
+
+                if !class {
+                    let padding = (block[0] % 16 + 1) as usize;
+                    for byte in &mut block[32 - padding..] {
+                        *byte = padding as u8;
+                    }
+                }
+                block
+            },
+            |block| {
+                std::hint::black_box(pkcs7_unpad_len(block));
+            },
+        );
+
+        assert!(t.abs() < T_THRESHOLD, "timing leak detected, t = {}", t);
+    }
+}
@@ -0,0 +1,258 @@
+/// Reading `crypt` remotes from rclone config files.
+///
+/// Only plain-text configs are supported; configs encrypted with `rclone config encryption`
+/// are rejected.
+use anyhow::{anyhow, Context, Result};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::cipher::Cipher;
+use crate::obscure::reveal;
+
+/// A `crypt` remote as configured in an rclone config file.
+///
+/// The password and salt are stored revealed, ready to be passed to [`Cipher::new()`](Cipher::new).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CryptRemote {
+    /// Name of the section in the config file.
+    pub name: String,
+    /// The wrapped remote, e.g. `local:/backup`.
+    pub remote: String,
+    /// The revealed `password`.
+    pub password: String,
+    /// The revealed `password2`, or `None` if rclone's default salt is used.
+    pub salt: Option<String>,
+    /// `filename_encryption`: `standard`, `obfuscate` or `off`.
+    pub filename_encryption: String,
+    /// `directory_name_encryption`
+    pub directory_name_encryption: bool,
+    /// `no_data_encryption`: only names are encrypted, file data is stored as-is.
+    pub no_data_encryption: bool,
+}
+
+impl CryptRemote {
+    /// Creates a [`Cipher`](Cipher) using the keys and the data encryption of this remote.
+    ///
+    /// # Errors
+    /// Fails if the remote uses a name encryption other than the standard one, see
+    /// [`check_supported()`](CryptRemote::check_supported).
+    pub fn cipher(&self) -> Result<Cipher> {
+        self.check_supported()?;
+        let cipher = Cipher::new(&self.password, self.salt.as_deref())?;
+        Ok(cipher.with_data_encryption(!self.no_data_encryption))
+    }
+
+    /// Fails if names of the remote aren't encrypted the standard way, with every directory
+    /// name encrypted too: those names would be written in a form rclone can't read.
+    pub fn check_supported(&self) -> Result<()> {
+        if self.filename_encryption != "standard" {
+            return Err(anyhow!(
+                "Remote \"{}\" uses filename_encryption = {}, only standard is supported",
+                self.name,
+                self.filename_encryption
+            ));
+        }
+        if !self.directory_name_encryption {
+            return Err(anyhow!(
+                "Remote \"{}\" uses directory_name_encryption = false, which is not supported",
+                self.name
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Returns the path of the rclone config file, looked up the same way rclone does:
+/// `$RCLONE_CONFIG`, `$XDG_CONFIG_HOME/rclone/rclone.conf`, `~/.config/rclone/rclone.conf`
+/// and finally the legacy `~/.rclone.conf`.
+pub fn default_config_path() -> Option<PathBuf> {
+    if let Some(path) = env::var_os("RCLONE_CONFIG") {
+        return Some(PathBuf::from(path));
+    }
+
+    let home = env::var_os("HOME").map(PathBuf::from);
+    let config_dir = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| home.as_ref().map(|home| home.join(".config")));
+
+    let candidates: Vec<PathBuf> = vec![
+        config_dir.map(|dir| dir.join("rclone").join("rclone.conf")),
+        home.map(|home| home.join(".rclone.conf")),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+
+    // rclone falls back to the first location when none of them exist yet
+    candidates
+        .iter()
+        .find(|path| path.exists())
+        .or_else(|| candidates.first())
+        .cloned()
+}
+
+/// Reads the `crypt` remote called `name` from the config file at `path`.
+pub fn read_crypt_remote(path: &Path, name: &str) -> Result<CryptRemote> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read rclone config {}", path.display()))?;
+    parse_crypt_remote(&contents, name)
+}
+
+/// Parses the `crypt` remote called `name` from the contents of an rclone config file.
+pub fn parse_crypt_remote(contents: &str, name: &str) -> Result<CryptRemote> {
+    if contents.trim_start().starts_with("RCLONE_ENCRYPT_V0:") {
+        return Err(anyhow!("Encrypted rclone configs are not supported"));
+    }
+
+    let mut in_section = false;
+    let mut found = false;
+    let mut values = Vec::new();
+
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+
+        if let Some(section) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            in_section = section.trim() == name;
+            found |= in_section;
+            continue;
+        }
+
+        if in_section {
+            if let Some((key, value)) = line.split_once('=') {
+                values.push((key.trim(), value.trim()));
+            }
+        }
+    }
+
+    if !found {
+        return Err(anyhow!("Remote \"{}\" not found in rclone config", name));
+    }
+
+    let get = |key: &str| {
+        values
+            .iter()
+            .rev()
+            .find(|(k, _)| *k == key)
+            .map(|(_, v)| *v)
+    };
+
+    if get("type") != Some("crypt") {
+        return Err(anyhow!("Remote \"{}\" is not a crypt remote", name));
+    }
+
+    let password = get("password")
+        .filter(|p| !p.is_empty())
+        .ok_or_else(|| anyhow!("Remote \"{}\" has no password", name))?;
+    let password = reveal(password).context("Failed to reveal password")?;
+
+    let salt = match get("password2").filter(|p| !p.is_empty()) {
+        Some(salt) => Some(reveal(salt).context("Failed to reveal password2")?),
+        None => None,
+    };
+
+    let flag = |key: &str, default: bool| match get(key) {
+        None => Ok(default),
+        Some(value) => parse_bool(value)
+            .ok_or_else(|| anyhow!("Invalid value {:?} for {} of \"{}\"", value, key, name)),
+    };
+
+    Ok(CryptRemote {
+        name: name.to_string(),
+        remote: get("remote").unwrap_or_default().to_string(),
+        password,
+        salt,
+        filename_encryption: get("filename_encryption").unwrap_or("standard").to_string(),
+        directory_name_encryption: flag("directory_name_encryption", true)?,
+        no_data_encryption: flag("no_data_encryption", false)?,
+    })
+}
+
+/// Parses a boolean the way rclone (Go's `strconv.ParseBool`) does.
+fn parse_bool(value: &str) -> Option<bool> {
+    match value {
+        "1" | "t" | "T" | "TRUE" | "true" | "True" => Some(true),
+        "0" | "f" | "F" | "FALSE" | "false" | "False" => Some(false),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_crypt_remote;
+
+    const CONFIG: &str = "
+[local]
+type = local
+
+[secret]
+type = crypt
+remote = local:/backup
+password = je8bffZYIlfYtaJszmAb96fua5e11rwU4esR
+password2 = up00wKh4M9ObK0B28jCBv-jDuvZxtP8NvwhR
+directory_name_encryption = false
+no_data_encryption = true
+
+[nosalt]
+type = crypt
+remote = local:/other
+password = BGB9FDhquBjXI9D8IJNySNgOgbpHqxo-Gxql
+";
+
+    #[test]
+    fn config_should_parse_crypt_remote() {
+        let remote = parse_crypt_remote(CONFIG, "secret").unwrap();
+
+        assert_eq!(remote.remote, "local:/backup");
+        assert_eq!(remote.password, "hello_world");
+        assert_eq!(remote.salt.as_deref(), Some("hello_world"));
+        assert_eq!(remote.filename_encryption, "standard");
+        assert!(!remote.directory_name_encryption);
+        assert!(remote.no_data_encryption);
+        assert!(remote
+            .cipher()
+            .unwrap_err()
+            .to_string()
+            .contains("directory_name_encryption"));
+
+        let remote = parse_crypt_remote(CONFIG, "nosalt").unwrap();
+        assert_eq!(remote.salt, None);
+        assert!(remote.directory_name_encryption);
+        assert!(!remote.no_data_encryption);
+        remote.cipher().unwrap();
+
+        let obfuscated = CONFIG.replace("[nosalt]", "[nosalt]\nfilename_encryption = obfuscate");
+        let remote = parse_crypt_remote(&obfuscated, "nosalt").unwrap();
+        assert!(remote.cipher().is_err());
+    }
+
+    #[test]
+    fn config_should_parse_bools_like_rclone() {
+        for (value, expected) in [("1", true), ("T", true), ("False", false), ("f", false)] {
+            let config = CONFIG.replace(
+                "no_data_encryption = true",
+                &format!("no_data_encryption = {}", value),
+            );
+            let remote = parse_crypt_remote(&config, "secret").unwrap();
+            assert_eq!(remote.no_data_encryption, expected);
+        }
+
+        for value in ["yes", "", "tRUE"] {
+            let config = CONFIG.replace(
+                "directory_name_encryption = false",
+                &format!("directory_name_encryption = {}", value),
+            );
+            assert!(parse_crypt_remote(&config, "secret").is_err());
+        }
+    }
+
+    #[test]
+    fn config_should_reject_other_remotes() {
+        assert!(parse_crypt_remote(CONFIG, "local").is_err());
+        assert!(parse_crypt_remote(CONFIG, "missing").is_err());
+        assert!(parse_crypt_remote("RCLONE_ENCRYPT_V0:\nabc", "secret").is_err());
+    }
+}
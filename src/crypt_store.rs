@@ -0,0 +1,725 @@
+/// An `object_store` wrapper encrypting paths and data like an rclone crypt remote.
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::future::ready;
+use futures::stream::{self, BoxStream};
+use futures::{FutureExt, StreamExt, TryStreamExt};
+use object_store::path::{Path, PathPart};
+use object_store::{
+    Error, GetOptions, GetResult, GetResultPayload, ListResult, MultipartUpload, ObjectMeta,
+    ObjectStore, PutMultipartOptions, PutOptions, PutPayload, PutResult, Result, UploadPart,
+};
+use percent_encoding::percent_decode_str;
+use std::borrow::Cow;
+use std::fmt;
+use std::ops::Range;
+
+use crate::cipher::Cipher;
+use crate::decrypter::Decrypter;
+use crate::encrypter::Encrypter;
+use crate::{decrypted_size, encrypted_range, BLOCK_DATA_SIZE, BLOCK_SIZE, FILE_HEADER_SIZE};
+
+const STORE: &str = "CryptObjectStore";
+
+/// Returns `part` as it was given to the [`Path`](Path), i.e. without percent-encoding.
+fn decode<'a>(part: &'a PathPart<'_>) -> Result<Cow<'a, str>> {
+    percent_decode_str(part.as_ref())
+        .decode_utf8()
+        .map_err(generic)
+}
+
+fn generic(source: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> Error {
+    Error::Generic {
+        store: STORE,
+        source: source.into(),
+    }
+}
+
+/// Wraps an [`ObjectStore`](ObjectStore), encrypting every path segment with a [`Cipher`](Cipher)
+/// and object data in the rclone crypt format, so objects written through it can be read by
+/// rclone and vice versa.
+///
+/// Ranged reads only fetch the blocks holding the requested range, which are decrypted one at a
+/// time as the result is streamed, and sizes are plaintext sizes. Objects whose path doesn't decrypt, or whose size isn't a valid encrypted
+/// size, are left out of listings. If the cipher doesn't encrypt data, only paths are encrypted.
+///
+/// Path segments are encrypted as they were given, i.e. without the percent-encoding of
+/// [`Path`](Path), so `a#b` is stored under the same name as by rclone.
+#[derive(Debug)]
+pub struct CryptObjectStore<T: ObjectStore> {
+    inner: T,
+    cipher: Cipher,
+}
+
+impl<T: ObjectStore> CryptObjectStore<T> {
+    pub fn new(inner: T, cipher: Cipher) -> Self {
+        Self { inner, cipher }
+    }
+
+    /// Returns the wrapped store.
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    /// Returns the path of the encrypted object backing `location`.
+    pub fn encrypt_path(&self, location: &Path) -> Result<Path> {
+        let parts = location
+            .parts()
+            .map(|part| {
+                self.cipher
+                    .encrypt_file_name(&decode(&part)?)
+                    .map(PathPart::from)
+                    .map_err(|e| generic(e.to_string()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(parts.into_iter().collect())
+    }
+
+    fn encrypter(&self) -> Result<Encrypter> {
+        Encrypter::new(&self.cipher.get_file_key()).map_err(|e| generic(e.to_string()))
+    }
+}
+
+fn decrypt_path(cipher: &Cipher, location: &Path) -> Option<Path> {
+    let parts = location
+        .parts()
+        .map(|part| {
+            let name = cipher.decrypt_segment(&decode(&part).ok()?).ok()?;
+            Some(PathPart::from(name))
+        })
+        .collect::<Option<Vec<_>>>()?;
+    Some(parts.into_iter().collect())
+}
+
+fn decrypt_meta(cipher: &Cipher, meta: ObjectMeta) -> Option<ObjectMeta> {
+    let size = if cipher.encrypts_data() {
+        decrypted_size(meta.size)?
+    } else {
+        meta.size
+    };
+    Some(ObjectMeta {
+        location: decrypt_path(cipher, &meta.location)?,
+        size,
+        ..meta
+    })
+}
+
+/// Decrypts the blocks streamed from the inner store one at a time, so reading a large object
+/// doesn't hold all of it in memory.
+struct BlockStream {
+    inner: BoxStream<'static, Result<Bytes>>,
+    cipher: Cipher,
+    /// `None` until the header is read from `inner`.
+    decrypter: Option<Decrypter>,
+    buffer: Vec<u8>,
+    block_id: u64,
+    /// The plaintext range to yield.
+    range: Range<u64>,
+    location: Path,
+}
+
+impl BlockStream {
+    /// Returns the part of the next block in the range, if any is left.
+    async fn next(mut self) -> Result<Option<(Bytes, Self)>> {
+        loop {
+            if self.block_id * BLOCK_DATA_SIZE as u64 >= self.range.end {
+                return Ok(None);
+            }
+
+            let wanted = match self.decrypter {
+                Some(_) => BLOCK_SIZE,
+                None => FILE_HEADER_SIZE,
+            };
+            while self.buffer.len() < wanted {
+                match self.inner.next().await {
+                    Some(bytes) => self.buffer.extend_from_slice(&bytes?),
+                    None => break,
+                }
+            }
+            let failed = |e: anyhow::Error| generic(format!("{}: {}", self.location, e));
+
+            let decrypter = match &self.decrypter {
+                Some(decrypter) => decrypter,
+                None => {
+                    let decrypter = Decrypter::new(&self.cipher.get_file_key(), &self.buffer)
+                        .map_err(failed)?;
+                    self.buffer.drain(..FILE_HEADER_SIZE);
+                    self.decrypter = Some(decrypter);
+                    continue;
+                }
+            };
+            if self.buffer.is_empty() {
+                return Ok(None);
+            }
+
+            let len = wanted.min(self.buffer.len());
+            let data = decrypter
+                .decrypt_range(self.block_id, &self.buffer[..len], self.range.clone())
+                .map_err(failed)?;
+            self.buffer.drain(..len);
+            self.block_id += 1;
+            if !data.is_empty() {
+                return Ok(Some((Bytes::from(data), self)));
+            }
+        }
+    }
+}
+
+impl<T: ObjectStore> fmt::Display for CryptObjectStore<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}({})", STORE, self.inner)
+    }
+}
+
+#[async_trait]
+impl<T: ObjectStore> ObjectStore for CryptObjectStore<T> {
+    async fn put_opts(
+        &self,
+        location: &Path,
+        payload: PutPayload,
+        opts: PutOptions,
+    ) -> Result<PutResult> {
+        let location = self.encrypt_path(location)?;
+        if !self.cipher.encrypts_data() {
+            return self.inner.put_opts(&location, payload, opts).await;
+        }
+
+        let encrypted = self.encrypter()?.encrypt_all(&Bytes::from(payload));
+        self.inner.put_opts(&location, encrypted.into(), opts).await
+    }
+
+    async fn put_multipart_opts(
+        &self,
+        location: &Path,
+        opts: PutMultipartOptions,
+    ) -> Result<Box<dyn MultipartUpload>> {
+        let location = self.encrypt_path(location)?;
+        if !self.cipher.encrypts_data() {
+            return self.inner.put_multipart_opts(&location, opts).await;
+        }
+
+        let encrypter = self.encrypter()?;
+        let inner = self.inner.put_multipart_opts(&location, opts).await?;
+
+        Ok(Box::new(CryptUpload {
+            inner,
+            encrypter,
+            block_id: 0,
+            buffer: Vec::new(),
+        }))
+    }
+
+    async fn get_opts(&self, location: &Path, options: GetOptions) -> Result<GetResult> {
+        let encrypted_location = self.encrypt_path(location)?;
+        if !self.cipher.encrypts_data() {
+            let mut result = self.inner.get_opts(&encrypted_location, options).await?;
+            result.meta.location = location.clone();
+            return Ok(result);
+        }
+
+        // The plaintext size is needed to resolve the range, and the conditions are checked on the way
+        let head = GetOptions {
+            range: None,
+            head: true,
+            ..options.clone()
+        };
+        let result = self.inner.get_opts(&encrypted_location, head).await?;
+        let size = decrypted_size(result.meta.size).ok_or_else(|| {
+            generic(format!(
+                "{} bytes is not a valid encrypted size",
+                result.meta.size
+            ))
+        })?;
+        let encrypted_size = result.meta.size;
+        // The blocks are fetched from the same version of the object as the header
+        let e_tag = result.meta.e_tag.clone();
+        let version = result.meta.version.clone();
+        let fetch = |range: Range<u64>| GetOptions {
+            range: Some(range.into()),
+            if_match: e_tag.clone(),
+            version: version.clone(),
+            ..GetOptions::default()
+        };
+        let meta = ObjectMeta {
+            location: location.clone(),
+            size,
+            ..result.meta
+        };
+
+        let range = match &options.range {
+            Some(range) => range.as_range(size).map_err(generic)?,
+            None => 0..size,
+        };
+        if options.head || range.is_empty() {
+            return Ok(GetResult {
+                payload: GetResultPayload::Stream(stream::empty().boxed()),
+                meta,
+                range,
+                attributes: result.attributes,
+            });
+        }
+
+        let (first_block, blocks) = encrypted_range(range.clone());
+        let blocks = blocks.start..blocks.end.min(encrypted_size);
+        // Reading from the start, the header comes with the blocks
+        let (decrypter, blocks) = if first_block == 0 {
+            (None, 0..blocks.end)
+        } else {
+            let header = self
+                .inner
+                .get_opts(&encrypted_location, fetch(0..FILE_HEADER_SIZE as u64))
+                .await?
+                .bytes()
+                .await?;
+            let decrypter = Decrypter::new(&self.cipher.get_file_key(), &header)
+                .map_err(|e| generic(format!("{}: {}", location, e)))?;
+            (Some(decrypter), blocks)
+        };
+        let encrypted = self
+            .inner
+            .get_opts(&encrypted_location, fetch(blocks))
+            .await?
+            .into_stream();
+
+        let blocks = BlockStream {
+            inner: encrypted,
+            cipher: self.cipher.clone(),
+            decrypter,
+            buffer: Vec::new(),
+            block_id: first_block,
+            range: range.clone(),
+            location: location.clone(),
+        };
+        Ok(GetResult {
+            payload: GetResultPayload::Stream(
+                stream::try_unfold(blocks, BlockStream::next).boxed(),
+            ),
+            meta,
+            range,
+            attributes: result.attributes,
+        })
+    }
+
+    async fn delete(&self, location: &Path) -> Result<()> {
+        self.inner.delete(&self.encrypt_path(location)?).await
+    }
+
+    fn list(&self, prefix: Option<&Path>) -> BoxStream<'static, Result<ObjectMeta>> {
+        let prefix = match prefix.map(|prefix| self.encrypt_path(prefix)).transpose() {
+            Ok(prefix) => prefix,
+            Err(e) => return stream::once(ready(Err(e))).boxed(),
+        };
+
+        let cipher = self.cipher.clone();
+        self.inner
+            .list(prefix.as_ref())
+            .try_filter_map(move |meta| ready(Ok(decrypt_meta(&cipher, meta))))
+            .boxed()
+    }
+
+    async fn list_with_delimiter(&self, prefix: Option<&Path>) -> Result<ListResult> {
+        let prefix = prefix.map(|prefix| self.encrypt_path(prefix)).transpose()?;
+        let result = self.inner.list_with_delimiter(prefix.as_ref()).await?;
+
+        Ok(ListResult {
+            common_prefixes: result
+                .common_prefixes
+                .iter()
+                .filter_map(|prefix| decrypt_path(&self.cipher, prefix))
+                .collect(),
+            objects: result
+                .objects
+                .into_iter()
+                .filter_map(|meta| decrypt_meta(&self.cipher, meta))
+                .collect(),
+        })
+    }
+
+    async fn copy(&self, from: &Path, to: &Path) -> Result<()> {
+        self.inner
+            .copy(&self.encrypt_path(from)?, &self.encrypt_path(to)?)
+            .await
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        self.inner
+            .rename(&self.encrypt_path(from)?, &self.encrypt_path(to)?)
+            .await
+    }
+
+    async fn copy_if_not_exists(&self, from: &Path, to: &Path) -> Result<()> {
+        self.inner
+            .copy_if_not_exists(&self.encrypt_path(from)?, &self.encrypt_path(to)?)
+            .await
+    }
+
+    async fn rename_if_not_exists(&self, from: &Path, to: &Path) -> Result<()> {
+        self.inner
+            .rename_if_not_exists(&self.encrypt_path(from)?, &self.encrypt_path(to)?)
+            .await
+    }
+}
+
+/// Multipart upload of [`CryptObjectStore`](CryptObjectStore). Every part is encrypted as far
+/// as it fills complete blocks, the rest is carried over to the next part.
+struct CryptUpload {
+    inner: Box<dyn MultipartUpload>,
+    encrypter: Encrypter,
+    block_id: u64,
+    buffer: Vec<u8>,
+}
+
+impl CryptUpload {
+    /// Encrypts the buffered data, up to the last complete block unless `last` is set.
+    fn encrypt_buffer(&mut self, last: bool) -> Vec<u8> {
+        let mut encrypted = Vec::new();
+        if self.block_id == 0 && (last || self.buffer.len() >= BLOCK_DATA_SIZE) {
+            encrypted.extend(self.encrypter.get_file_header());
+        }
+
+        let complete = if last {
+            self.buffer.len()
+        } else {
+            self.buffer.len() - self.buffer.len() % BLOCK_DATA_SIZE
+        };
+        for block in self.buffer[..complete].chunks(BLOCK_DATA_SIZE) {
+            encrypted.extend(self.encrypter.encrypt_block(self.block_id, block));
+            self.block_id += 1;
+        }
+        self.buffer.drain(..complete);
+
+        encrypted
+    }
+}
+
+impl fmt::Debug for CryptUpload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CryptUpload")
+            .field("inner", &self.inner)
+            .field("block_id", &self.block_id)
+            .field("buffered", &self.buffer.len())
+            .finish()
+    }
+}
+
+#[async_trait]
+impl MultipartUpload for CryptUpload {
+    fn put_part(&mut self, data: PutPayload) -> UploadPart {
+        for bytes in data.iter() {
+            self.buffer.extend_from_slice(bytes);
+        }
+
+        let encrypted = self.encrypt_buffer(false);
+        if encrypted.is_empty() {
+            return ready(Ok(())).boxed();
+        }
+        self.inner.put_part(encrypted.into())
+    }
+
+    async fn complete(&mut self) -> Result<PutResult> {
+        let encrypted = self.encrypt_buffer(true);
+        if !encrypted.is_empty() {
+            self.inner.put_part(encrypted.into()).await?;
+        }
+        self.inner.complete().await
+    }
+
+    async fn abort(&mut self) -> Result<()> {
+        self.inner.abort().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CryptObjectStore;
+    use crate::cipher::Cipher;
+    use crate::stream::EncryptedReader;
+    use crate::BLOCK_DATA_SIZE;
+    use async_trait::async_trait;
+    use bytes::Bytes;
+    use futures::executor::block_on;
+    use futures::stream::{self, BoxStream};
+    use futures::{StreamExt, TryStreamExt};
+    use object_store::local::LocalFileSystem;
+    use object_store::memory::InMemory;
+    use object_store::path::Path;
+    use object_store::{
+        Error, GetOptions, GetRange, GetResult, GetResultPayload, ListResult, MultipartUpload,
+        ObjectMeta, ObjectStore, PutMultipartOptions, PutOptions, PutPayload, PutResult, Result,
+    };
+    use std::collections::HashMap;
+    use std::fmt;
+    use std::io::Read;
+    use std::sync::Mutex;
+
+    /// Keeps every version of the objects put into it, like a bucket with versioning enabled.
+    /// The ETag of a version is its number.
+    #[derive(Debug, Default)]
+    struct Versioned {
+        inner: InMemory,
+        versions: Mutex<HashMap<Path, Vec<Bytes>>>,
+    }
+
+    impl fmt::Display for Versioned {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "Versioned")
+        }
+    }
+
+    #[async_trait]
+    impl ObjectStore for Versioned {
+        async fn put_opts(
+            &self,
+            location: &Path,
+            payload: PutPayload,
+            opts: PutOptions,
+        ) -> Result<PutResult> {
+            let data = Bytes::from(payload);
+            self.inner
+                .put_opts(location, data.clone().into(), opts)
+                .await?;
+            let mut versions = self.versions.lock().unwrap();
+            let versions = versions.entry(location.clone()).or_default();
+            versions.push(data);
+            let version = (versions.len() - 1).to_string();
+            Ok(PutResult {
+                e_tag: Some(version.clone()),
+                version: Some(version),
+            })
+        }
+
+        async fn put_multipart_opts(
+            &self,
+            location: &Path,
+            opts: PutMultipartOptions,
+        ) -> Result<Box<dyn MultipartUpload>> {
+            self.inner.put_multipart_opts(location, opts).await
+        }
+
+        async fn get_opts(&self, location: &Path, options: GetOptions) -> Result<GetResult> {
+            let meta = self.inner.head(location).await?;
+            let (version, data) = {
+                let versions = self.versions.lock().unwrap();
+                let versions = &versions[location];
+                let version = match &options.version {
+                    Some(version) => version.parse().unwrap(),
+                    None => versions.len() - 1,
+                };
+                (version.to_string(), versions[version].clone())
+            };
+            if options
+                .if_match
+                .as_ref()
+                .is_some_and(|e_tag| *e_tag != version)
+            {
+                return Err(Error::Precondition {
+                    path: location.to_string(),
+                    source: "ETag doesn't match".into(),
+                });
+            }
+
+            let range = match &options.range {
+                Some(range) => range.as_range(data.len() as u64).unwrap(),
+                None => 0..data.len() as u64,
+            };
+            let payload = data.slice(range.start as usize..range.end as usize);
+            Ok(GetResult {
+                payload: GetResultPayload::Stream(stream::once(async { Ok(payload) }).boxed()),
+                meta: ObjectMeta {
+                    size: data.len() as u64,
+                    e_tag: Some(version.clone()),
+                    version: Some(version),
+                    ..meta
+                },
+                range,
+                attributes: Default::default(),
+            })
+        }
+
+        async fn delete(&self, location: &Path) -> Result<()> {
+            self.inner.delete(location).await
+        }
+
+        fn list(&self, prefix: Option<&Path>) -> BoxStream<'static, Result<ObjectMeta>> {
+            self.inner.list(prefix)
+        }
+
+        async fn list_with_delimiter(&self, prefix: Option<&Path>) -> Result<ListResult> {
+            self.inner.list_with_delimiter(prefix).await
+        }
+
+        async fn copy(&self, from: &Path, to: &Path) -> Result<()> {
+            self.inner.copy(from, to).await
+        }
+
+        async fn copy_if_not_exists(&self, from: &Path, to: &Path) -> Result<()> {
+            self.inner.copy_if_not_exists(from, to).await
+        }
+    }
+
+    fn check_store<T: ObjectStore>(inner: T, cipher: Cipher) {
+        let store = CryptObjectStore::new(inner, cipher.clone());
+        let data: Vec<u8> = (0..2 * BLOCK_DATA_SIZE + 100).map(|i| i as u8).collect();
+        let location = Path::from("dir/data.bin");
+
+        block_on(async {
+            store.put(&location, data.clone().into()).await.unwrap();
+            store
+                .put(&Path::from("dir/sub/b.txt"), "b".into())
+                .await
+                .unwrap();
+
+            // rclone can read what was written
+            let encrypted_location = store.encrypt_path(&location).unwrap();
+            assert_eq!(
+                encrypted_location.as_ref(),
+                cipher.encrypt_file_name("dir/data.bin").unwrap()
+            );
+            let encrypted = store
+                .inner()
+                .get(&encrypted_location)
+                .await
+                .unwrap()
+                .bytes()
+                .await
+                .unwrap();
+            let mut decrypted = Vec::new();
+            EncryptedReader::new_with_cipher(&encrypted[..], cipher.clone())
+                .unwrap()
+                .read_to_end(&mut decrypted)
+                .unwrap();
+            assert_eq!(decrypted, data);
+
+            // Segments are encrypted without the percent-encoding of `Path`
+            let special = Path::from("dir").child("50% #1?.txt");
+            store.put(&special, "special".into()).await.unwrap();
+            assert_eq!(
+                store.encrypt_path(&special).unwrap().as_ref(),
+                cipher.encrypt_file_name("dir/50% #1?.txt").unwrap()
+            );
+            let bytes = store.get(&special).await.unwrap().bytes().await.unwrap();
+            assert_eq!(bytes, "special");
+            assert_eq!(store.head(&special).await.unwrap().location, special);
+            store.delete(&special).await.unwrap();
+
+            let meta = store.head(&location).await.unwrap();
+            assert_eq!(meta.size, data.len() as u64);
+            assert_eq!(meta.location, location);
+
+            let all = store.get(&location).await.unwrap().bytes().await.unwrap();
+            assert_eq!(all, data);
+
+            let block = BLOCK_DATA_SIZE as u64;
+            for range in [0..10, block - 5..block + 5, 2 * block..2 * block + 100] {
+                let bytes = store.get_range(&location, range.clone()).await.unwrap();
+                assert_eq!(bytes, data[range.start as usize..range.end as usize]);
+            }
+            let options = GetOptions {
+                range: Some(GetRange::Suffix(50)),
+                ..GetOptions::default()
+            };
+            let result = store.get_opts(&location, options).await.unwrap();
+            assert_eq!(result.range.end, data.len() as u64);
+            assert_eq!(result.bytes().await.unwrap(), data[data.len() - 50..]);
+
+            let mut listed: Vec<_> = store
+                .list(Some(&Path::from("dir")))
+                .map_ok(|meta| (meta.location.to_string(), meta.size))
+                .try_collect()
+                .await
+                .unwrap();
+            listed.sort();
+            assert_eq!(
+                listed,
+                [
+                    ("dir/data.bin".to_string(), data.len() as u64),
+                    ("dir/sub/b.txt".to_string(), 1)
+                ]
+            );
+
+            let result = store
+                .list_with_delimiter(Some(&Path::from("dir")))
+                .await
+                .unwrap();
+            assert_eq!(result.common_prefixes, [Path::from("dir/sub")]);
+            assert_eq!(result.objects.len(), 1);
+            assert_eq!(result.objects[0].location, location);
+
+            let moved = Path::from("moved.bin");
+            store.rename(&location, &moved).await.unwrap();
+            assert!(store.head(&location).await.is_err());
+            store.delete(&moved).await.unwrap();
+            assert!(store.head(&moved).await.is_err());
+
+            let mut upload = store.put_multipart(&location).await.unwrap();
+            for part in data.chunks(100_000) {
+                upload.put_part(part.to_vec().into()).await.unwrap();
+            }
+            upload.complete().await.unwrap();
+            let all = store.get(&location).await.unwrap().bytes().await.unwrap();
+            assert_eq!(all, data);
+        });
+    }
+
+    #[test]
+    fn crypt_object_store_should_read_versions_block_by_block() {
+        let cipher = Cipher::new("test", Some("salt")).unwrap();
+        let store = CryptObjectStore::new(Versioned::default(), cipher);
+        let location = Path::from("data.bin");
+        let old: Vec<u8> = (0..3 * BLOCK_DATA_SIZE).map(|i| i as u8).collect();
+        let new = vec![7u8; 3 * BLOCK_DATA_SIZE];
+
+        block_on(async {
+            store.put(&location, old.clone().into()).await.unwrap();
+            store.put(&location, new.clone().into()).await.unwrap();
+
+            let block = BLOCK_DATA_SIZE as u64;
+            for range in [None, Some(GetRange::Bounded(block + 5..2 * block + 5))] {
+                let options = GetOptions {
+                    version: Some("0".to_string()),
+                    range: range.clone(),
+                    ..GetOptions::default()
+                };
+                let result = store.get_opts(&location, options).await.unwrap();
+                let range = result.range.clone();
+                // One chunk per block
+                let chunks: Vec<Bytes> = result.into_stream().try_collect().await.unwrap();
+                assert_eq!(chunks.len(), if range.start == 0 { 3 } else { 2 });
+                assert_eq!(
+                    chunks.concat(),
+                    old[range.start as usize..range.end as usize]
+                );
+            }
+
+            let all = store.get(&location).await.unwrap().bytes().await.unwrap();
+            assert_eq!(all, new);
+        });
+    }
+
+    #[test]
+    fn crypt_object_store_should_work_in_memory() {
+        check_store(InMemory::new(), Cipher::new("test", Some("salt")).unwrap());
+    }
+
+    #[test]
+    fn crypt_object_store_should_work_without_data_encryption() {
+        let cipher = Cipher::new("test", Some("salt"))
+            .unwrap()
+            .with_data_encryption(false);
+        check_store(InMemory::new(), cipher.clone());
+        let root = tempfile::tempdir().unwrap();
+        check_store(
+            LocalFileSystem::new_with_prefix(root.path()).unwrap(),
+            cipher,
+        );
+    }
+
+    #[test]
+    fn crypt_object_store_should_work_on_local_file_system() {
+        let root = tempfile::tempdir().unwrap();
+        check_store(
+            LocalFileSystem::new_with_prefix(root.path()).unwrap(),
+            Cipher::new("test", Some("salt")).unwrap(),
+        );
+    }
+}
@@ -0,0 +1,429 @@
+/// Checking plaintext files against their encrypted copies, like `rclone cryptcheck`.
+///
+/// The plaintext is encrypted again with the nonce read from the header of the encrypted file,
+/// which gives the exact same ciphertext if the data matches. It can then be compared against
+/// the encrypted file itself, or against a hash of it (e.g. the MD5 or SHA-1 reported by the
+/// remote backing a crypt remote), which only requires the header to be downloaded.
+use anyhow::{anyhow, Context, Result};
+use data_encoding::HEXLOWER_PERMISSIVE;
+use md5::Md5;
+use sha1::{Digest, Sha1};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::cipher::Cipher;
+use crate::decrypter::read_nonce;
+use crate::stream::{read_full, EncryptedWriter};
+use crate::{FileNonce, FILE_HEADER_SIZE};
+
+/// A known hash of an encrypted file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CiphertextHash {
+    Md5([u8; 16]),
+    Sha1([u8; 20]),
+}
+
+impl CiphertextHash {
+    /// Parses a hex encoded MD5 or SHA-1 hash, told apart by their length.
+    pub fn from_hex(hex: &str) -> Result<Self> {
+        let bytes = HEXLOWER_PERMISSIVE
+            .decode(hex.as_bytes())
+            .map_err(|e| anyhow!("Invalid hash {}: {}", hex, e))?;
+
+        match bytes.len() {
+            16 => Ok(Self::Md5(bytes.try_into().unwrap())),
+            20 => Ok(Self::Sha1(bytes.try_into().unwrap())),
+            _ => Err(anyhow!("Invalid hash {}: neither MD5 nor SHA-1", hex)),
+        }
+    }
+}
+
+/// Parses the output of `md5sum`, `sha1sum`, or `rclone md5sum`/`rclone sha1sum` run on the remote
+/// backing a crypt remote, into a map of encrypted paths to their hashes.
+pub fn parse_hash_list(contents: &str) -> Result<HashMap<PathBuf, CiphertextHash>> {
+    let mut hashes = HashMap::new();
+
+    for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+        let (hash, path) = line
+            .split_once(' ')
+            .ok_or_else(|| anyhow!("Invalid hash list line: {}", line))?;
+        // `md5sum` marks binary mode with a `*` before the path
+        let path = path.trim_start_matches(' ').trim_start_matches('*');
+
+        hashes.insert(PathBuf::from(path), CiphertextHash::from_hex(hash)?);
+    }
+
+    Ok(hashes)
+}
+
+/// Encrypts `plaintext` with the given nonce into `output`.
+fn encrypt_with_nonce<R: Read, W: Write>(
+    cipher: &Cipher,
+    mut plaintext: R,
+    nonce: &FileNonce,
+    output: W,
+) -> io::Result<()> {
+    let mut writer = EncryptedWriter::new_with_nonce(output, cipher.clone(), nonce)?;
+    io::copy(&mut plaintext, &mut writer)?;
+    writer.finish()
+}
+
+/// Returns whether `plaintext` encrypts to the same data as `encrypted`, which must start with
+/// the file header.
+pub fn check_file<P: Read, E: Read>(
+    cipher: &Cipher,
+    plaintext: P,
+    mut encrypted: E,
+) -> Result<bool> {
+    cipher.require_data_encryption()?;
+    let mut header = [0u8; FILE_HEADER_SIZE];
+    encrypted
+        .read_exact(&mut header)
+        .context("Failed to read file header")?;
+    let nonce = read_nonce(&header)?;
+
+    let mut comparison = Comparison {
+        expected: (&header[..]).chain(encrypted),
+        equal: true,
+    };
+    encrypt_with_nonce(cipher, plaintext, &nonce, &mut comparison)?;
+
+    // The encrypted file mustn't have any data left
+    let mut byte = [0u8];
+    Ok(comparison.equal && read_full(&mut comparison.expected, &mut byte)? == 0)
+}
+
+/// Returns whether `plaintext`, encrypted with `nonce`, has the `expected` hash.
+///
+/// The nonce can be read from the file header with [`read_nonce()`](read_nonce).
+pub fn check_file_hash<P: Read>(
+    cipher: &Cipher,
+    plaintext: P,
+    nonce: &FileNonce,
+    expected: &CiphertextHash,
+) -> Result<bool> {
+    cipher.require_data_encryption()?;
+    Ok(match expected {
+        CiphertextHash::Md5(expected) => {
+            hash_encrypted::<Md5, _>(cipher, plaintext, nonce)?[..] == expected[..]
+        }
+        CiphertextHash::Sha1(expected) => {
+            hash_encrypted::<Sha1, _>(cipher, plaintext, nonce)?[..] == expected[..]
+        }
+    })
+}
+
+fn hash_encrypted<D: Digest, P: Read>(
+    cipher: &Cipher,
+    plaintext: P,
+    nonce: &FileNonce,
+) -> Result<Vec<u8>> {
+    let mut hasher = HashWriter(D::new());
+    encrypt_with_nonce(cipher, plaintext, nonce, &mut hasher)?;
+    Ok(hasher.0.finalize().to_vec())
+}
+
+/// The result of checking a single file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CheckStatus {
+    /// The plaintext matches the encrypted file.
+    Match,
+    /// The plaintext doesn't match the encrypted file.
+    Differ,
+    /// The file only exists in the plaintext tree.
+    MissingEncrypted,
+    /// The file only exists in the encrypted tree.
+    MissingPlaintext,
+    /// The file couldn't be checked.
+    Error(String),
+}
+
+/// A checked file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CheckResult {
+    /// Path relative to the plaintext directory, or `None` if it only exists in the encrypted
+    /// tree and its name can't be decrypted.
+    pub path: Option<PathBuf>,
+    /// Path relative to the encrypted directory.
+    pub encrypted_path: PathBuf,
+    pub status: CheckStatus,
+}
+
+/// The outcome of [`check_tree()`](check_tree).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CheckReport {
+    /// Every checked file, sorted by encrypted path.
+    pub results: Vec<CheckResult>,
+}
+
+impl CheckReport {
+    /// Returns whether every file matches.
+    pub fn is_ok(&self) -> bool {
+        self.results
+            .iter()
+            .all(|result| result.status == CheckStatus::Match)
+    }
+}
+
+/// Options for [`check_tree()`](check_tree).
+#[derive(Clone, Debug, Default)]
+pub struct CheckOptions {
+    /// Known hashes of encrypted files, by path relative to the encrypted directory
+    /// (see [`parse_hash_list()`](parse_hash_list)).
+    ///
+    /// Files with a known hash are checked against it, so only their header is read from the
+    /// encrypted tree and the rest of the file doesn't have to be there.
+    pub hashes: HashMap<PathBuf, CiphertextHash>,
+}
+
+/// Checks every file of the plaintext tree at `plaintext` against its encrypted copy in `encrypted`.
+///
+/// # Errors
+/// Only fails if one of the directories can't be listed, or if `cipher` doesn't encrypt data.
+/// Files which can't be checked are reported as [`CheckStatus::Error`](CheckStatus::Error).
+pub fn check_tree(
+    cipher: &Cipher,
+    plaintext: &Path,
+    encrypted: &Path,
+    options: &CheckOptions,
+) -> Result<CheckReport> {
+    cipher.require_data_encryption()?;
+    // Encrypted path -> (plaintext path, in plaintext tree, in encrypted tree)
+    let mut files = BTreeMap::new();
+    list_files(plaintext, Path::new(""), &mut |path| {
+        let encrypted_path = cipher.encrypt_path(path)?;
+        files.insert(encrypted_path, (Some(path.to_path_buf()), true, false));
+        Ok(())
+    })?;
+    list_files(encrypted, Path::new(""), &mut |encrypted_path| {
+        match files.get_mut(encrypted_path) {
+            Some((_, _, in_encrypted)) => *in_encrypted = true,
+            None => {
+                let path = cipher.decrypt_path(encrypted_path).ok();
+                files.insert(encrypted_path.to_path_buf(), (path, false, true));
+            }
+        }
+        Ok(())
+    })?;
+
+    let results = files
+        .into_iter()
+        .map(|(encrypted_path, (path, in_plaintext, in_encrypted))| {
+            let status = match (&path, in_plaintext, in_encrypted) {
+                (Some(path), true, true) => {
+                    let check = check_path(
+                        cipher,
+                        &plaintext.join(path),
+                        &encrypted.join(&encrypted_path),
+                        options.hashes.get(&encrypted_path),
+                    );
+                    match check {
+                        Ok(true) => CheckStatus::Match,
+                        Ok(false) => CheckStatus::Differ,
+                        Err(e) => CheckStatus::Error(format!("{:#}", e)),
+                    }
+                }
+                (_, true, _) => CheckStatus::MissingEncrypted,
+                _ => CheckStatus::MissingPlaintext,
+            };
+
+            CheckResult {
+                path,
+                encrypted_path,
+                status,
+            }
+        })
+        .collect();
+
+    Ok(CheckReport { results })
+}
+
+fn check_path(
+    cipher: &Cipher,
+    plaintext: &Path,
+    encrypted: &Path,
+    hash: Option<&CiphertextHash>,
+) -> Result<bool> {
+    let plaintext = BufReader::new(
+        File::open(plaintext).with_context(|| format!("Failed to open {}", plaintext.display()))?,
+    );
+    let mut encrypted = BufReader::new(
+        File::open(encrypted).with_context(|| format!("Failed to open {}", encrypted.display()))?,
+    );
+
+    match hash {
+        Some(hash) => {
+            let mut header = [0u8; FILE_HEADER_SIZE];
+            encrypted
+                .read_exact(&mut header)
+                .context("Failed to read file header")?;
+            check_file_hash(cipher, plaintext, &read_nonce(&header)?, hash)
+        }
+        None => check_file(cipher, plaintext, encrypted),
+    }
+}
+
+/// Calls `f` with the path (relative to `root`) of every file under `root.join(path)`.
+fn list_files(root: &Path, path: &Path, f: &mut impl FnMut(&Path) -> Result<()>) -> Result<()> {
+    let dir = root.join(path);
+    for entry in fs::read_dir(&dir).with_context(|| format!("Failed to read {}", dir.display()))? {
+        let entry = entry?;
+        let entry_path = path.join(entry.file_name());
+        let metadata = fs::metadata(entry.path())?;
+
+        if metadata.is_dir() {
+            list_files(root, &entry_path, f)?;
+        } else if metadata.is_file() {
+            f(&entry_path)?;
+        }
+    }
+    Ok(())
+}
+
+/// A writer comparing everything written to it against the data of a reader.
+struct Comparison<R: Read> {
+    expected: R,
+    equal: bool,
+}
+
+impl<R: Read> Write for Comparison<R> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.equal {
+            let mut expected = vec![0u8; buf.len()];
+            let read = read_full(&mut self.expected, &mut expected)?;
+            self.equal = read == buf.len() && expected == buf;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct HashWriter<D: Digest>(D);
+
+impl<D: Digest> Write for HashWriter<D> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        check_file, check_file_hash, check_tree, parse_hash_list, CheckOptions, CheckStatus,
+        CiphertextHash,
+    };
+    use crate::cipher::Cipher;
+    use crate::decrypter::read_nonce;
+    use crate::tests::encrypt;
+    use md5::{Digest, Md5};
+    use sha1::Sha1;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn cryptcheck_should_compare_against_encrypted_data() {
+        let cipher = Cipher::new("test", Some("test")).unwrap();
+        let plaintext = vec![3u8; 150_000];
+        let encrypted = encrypt(&cipher, &plaintext);
+
+        assert!(check_file(&cipher, &plaintext[..], &encrypted[..]).unwrap());
+        assert!(!check_file(&cipher, &plaintext[1..], &encrypted[..]).unwrap());
+        assert!(!check_file(&cipher, &plaintext[..], &encrypted[..encrypted.len() - 1]).unwrap());
+
+        let mut extended = plaintext.clone();
+        extended.push(3);
+        assert!(!check_file(&cipher, &extended[..], &encrypted[..]).unwrap());
+
+        let empty = encrypt(&cipher, b"");
+        assert!(check_file(&cipher, &b""[..], &empty[..]).unwrap());
+    }
+
+    #[test]
+    fn cryptcheck_should_compare_against_hashes() {
+        let cipher = Cipher::new("test", Some("test")).unwrap();
+        let plaintext = b"hello world";
+        let encrypted = encrypt(&cipher, plaintext);
+        let nonce = read_nonce(&encrypted).unwrap();
+
+        let md5 = CiphertextHash::Md5(Md5::digest(&encrypted).into());
+        let sha1 = CiphertextHash::Sha1(Sha1::digest(&encrypted).into());
+
+        assert!(check_file_hash(&cipher, &plaintext[..], &nonce, &md5).unwrap());
+        assert!(check_file_hash(&cipher, &plaintext[..], &nonce, &sha1).unwrap());
+        assert!(!check_file_hash(&cipher, &b"hello World"[..], &nonce, &md5).unwrap());
+    }
+
+    #[test]
+    fn cryptcheck_should_check_trees() {
+        let cipher = Cipher::new("test", Some("test")).unwrap();
+        let plaintext = tempfile::tempdir().unwrap();
+        let encrypted = tempfile::tempdir().unwrap();
+
+        let encrypted_path = |path: &str| cipher.encrypt_path(Path::new(path)).unwrap();
+        let add = |path: &str, plaintext_data: Option<&[u8]>, encrypted_data: Option<&[u8]>| {
+            if let Some(data) = plaintext_data {
+                fs::write(plaintext.path().join(path), data).unwrap();
+            }
+            if let Some(data) = encrypted_data {
+                fs::write(
+                    encrypted.path().join(encrypted_path(path)),
+                    encrypt(&cipher, data),
+                )
+                .unwrap();
+            }
+        };
+
+        fs::create_dir(plaintext.path().join("dir")).unwrap();
+        fs::create_dir(encrypted.path().join(encrypted_path("dir"))).unwrap();
+        add("dir/same.txt", Some(b"same"), Some(b"same"));
+        add("changed.txt", Some(b"new"), Some(b"old"));
+        add("local.txt", Some(b"local"), None);
+        add("remote.txt", None, Some(b"remote"));
+
+        // Only the header of hashed.txt is needed when its hash is known
+        add("hashed.txt", Some(b"hashed"), Some(b"hashed"));
+        let hashed = encrypted.path().join(encrypted_path("hashed.txt"));
+        let data = fs::read(&hashed).unwrap();
+        let hashes = format!(
+            "{:x}  {}\n",
+            Md5::digest(&data),
+            encrypted_path("hashed.txt").display()
+        );
+        fs::write(&hashed, &data[..32]).unwrap();
+
+        let options = CheckOptions {
+            hashes: parse_hash_list(&hashes).unwrap(),
+        };
+        let report = check_tree(&cipher, plaintext.path(), encrypted.path(), &options).unwrap();
+
+        let mut results: Vec<_> = report
+            .results
+            .iter()
+            .map(|result| (result.path.clone().unwrap(), result.status.clone()))
+            .collect();
+        results.sort_by(|a, b| a.0.cmp(&b.0));
+
+        assert!(!report.is_ok());
+        assert_eq!(
+            results,
+            [
+                (PathBuf::from("changed.txt"), CheckStatus::Differ),
+                (PathBuf::from("dir/same.txt"), CheckStatus::Match),
+                (PathBuf::from("hashed.txt"), CheckStatus::Match),
+                (PathBuf::from("local.txt"), CheckStatus::MissingEncrypted),
+                (PathBuf::from("remote.txt"), CheckStatus::MissingPlaintext),
+            ]
+        );
+    }
+}
@@ -0,0 +1,534 @@
+/// A filesystem view of a local directory holding rclone crypt data, addressed by plaintext paths.
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::cipher::{is_plain_segment, Cipher, ListedName, NamePolicy};
+use crate::decrypted_size;
+use crate::stream::{EncryptedReader, EncryptedWriter};
+
+/// Gives access to the encrypted directory at its root as if it were plaintext, like rclone's
+/// crypt backend over a local remote.
+///
+/// All paths are plaintext paths relative to the root; a leading `/` is ignored.
+/// Errors are [`std::io::Error`](std::io::Error)s like the ones of [`std::fs`](std::fs), with
+/// [`ErrorKind::InvalidData`](ErrorKind::InvalidData) for names and data which fail to decrypt.
+#[derive(Clone, Debug)]
+pub struct CryptFs {
+    root: PathBuf,
+    cipher: Cipher,
+}
+
+/// Metadata of a file or directory of a [`CryptFs`](CryptFs).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Metadata {
+    pub is_dir: bool,
+    /// Plaintext size of files, 0 for directories.
+    pub len: u64,
+    pub modified: Option<SystemTime>,
+}
+
+/// An entry returned by [`CryptFs::read_dir()`](CryptFs::read_dir).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DirEntry {
+    /// The decrypted name.
+    pub name: String,
+    /// The plaintext path, relative to the root.
+    pub path: PathBuf,
+    pub metadata: Metadata,
+    /// Set for names which don't decrypt and files with an invalid encrypted size, listed
+    /// under their raw name by [`NamePolicy::PassThrough`](NamePolicy::PassThrough), with the
+    /// raw size. Their path can't be opened.
+    pub undecryptable: bool,
+}
+
+impl CryptFs {
+    /// Creates a filesystem over the existing directory `root`.
+    pub fn new(root: impl Into<PathBuf>, cipher: Cipher) -> Result<Self> {
+        let root = root.into();
+        if !fs::metadata(&root)?.is_dir() {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("{} is not a directory", root.display()),
+            ));
+        }
+        Ok(Self { root, cipher })
+    }
+
+    /// Returns the path of the encrypted file or directory backing `path`.
+    pub fn encrypted_path(&self, path: &Path) -> Result<PathBuf> {
+        let encrypted = self
+            .cipher
+            .encrypt_path(&relative_path(path)?)
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e.to_string()))?;
+        Ok(self.root.join(encrypted))
+    }
+
+    /// Opens the file at `path` for reading.
+    pub fn open(&self, path: &Path) -> Result<CryptFile> {
+        let file = File::open(self.encrypted_path(path)?)?;
+        let reader = EncryptedReader::new_with_cipher(BufReader::new(file), self.cipher.clone())?;
+        Ok(CryptFile {
+            inner: FileInner::Read(reader),
+            partial: None,
+        })
+    }
+
+    /// Creates the file at `path` for writing, replacing it if it exists.
+    ///
+    /// The data is written to a temporary file next to it, which only replaces `path` once
+    /// [`CryptFile::finish()`](CryptFile::finish) succeeds, and is removed if the
+    /// [`CryptFile`](CryptFile) is dropped before.
+    pub fn create(&self, path: &Path) -> Result<CryptFile> {
+        let target = self.encrypted_path(path)?;
+        let (temporary, file) = create_partial(&target)?;
+        let writer = EncryptedWriter::new_with_cipher(BufWriter::new(file), self.cipher.clone());
+        let writer = match writer {
+            Ok(writer) => writer,
+            Err(e) => {
+                let _ = fs::remove_file(&temporary);
+                return Err(e);
+            }
+        };
+        Ok(CryptFile {
+            inner: FileInner::Write(Some(writer)),
+            partial: Some(Partial { temporary, target }),
+        })
+    }
+
+    /// Lists the directory at `path`, sorted by name. Entries whose name doesn't decrypt or
+    /// whose size is invalid are left out, like rclone does.
+    pub fn read_dir(&self, path: &Path) -> Result<Vec<DirEntry>> {
+        self.read_dir_with_policy(path, NamePolicy::Skip)
+    }
+
+    /// Lists the directory at `path`, sorted by name, handling entries whose name doesn't
+    /// decrypt or whose size isn't a valid encrypted size by `policy`. [`NamePolicy::Fail`](NamePolicy::Fail) fails with
+    /// [`ErrorKind::InvalidData`](ErrorKind::InvalidData).
+    pub fn read_dir_with_policy(&self, path: &Path, policy: NamePolicy) -> Result<Vec<DirEntry>> {
+        let mut entries = Vec::new();
+
+        let dir = relative_path(path)?;
+        for entry in fs::read_dir(self.encrypted_path(path)?)? {
+            let entry = entry?;
+            // Names which aren't UTF-8 can't be encrypted names either
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            let listed = self
+                .cipher
+                .decrypt_listing([file_name.as_str()], policy)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{:#}", e)))?;
+            let (mut name, mut undecryptable) = match listed.into_iter().next() {
+                // Crafted names could otherwise escape the root
+                Some(ListedName::Decrypted { name, .. }) if !is_plain_segment(&name) => {
+                    match policy {
+                        NamePolicy::Fail => {
+                            return Err(Error::new(
+                                ErrorKind::InvalidData,
+                                format!(
+                                    "{:?} decrypts to {:?}, which is not a plain name",
+                                    file_name, name
+                                ),
+                            ))
+                        }
+                        NamePolicy::Skip => continue,
+                        NamePolicy::PassThrough => (file_name.clone(), true),
+                    }
+                }
+                Some(ListedName::Decrypted { name, .. }) => (name, false),
+                Some(ListedName::Undecryptable { encrypted }) => (encrypted, true),
+                _ => continue,
+            };
+
+            // Entries which aren't ours don't have encrypted data either
+            let encrypted = self.cipher.encrypts_data() && !undecryptable;
+            let metadata = match fs::metadata(entry.path()) {
+                Ok(metadata) => metadata,
+                // Dangling symlinks, and entries deleted since they were listed
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            // A truncated file is handled like a name which doesn't decrypt
+            if encrypted && !metadata.is_dir() && decrypted_size(metadata.len()).is_none() {
+                match policy {
+                    NamePolicy::Fail => return Err(invalid_size(metadata.len())),
+                    NamePolicy::Skip => continue,
+                    NamePolicy::PassThrough => {
+                        name = file_name;
+                        undecryptable = true;
+                    }
+                }
+            }
+            entries.push(DirEntry {
+                path: dir.join(&name),
+                name,
+                metadata: to_metadata(&metadata, encrypted && !undecryptable)?,
+                undecryptable,
+            });
+        }
+
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(entries)
+    }
+
+    pub fn metadata(&self, path: &Path) -> Result<Metadata> {
+        let metadata = fs::metadata(self.encrypted_path(path)?)?;
+        to_metadata(&metadata, self.cipher.encrypts_data())
+    }
+
+    /// Renames a file or directory, replacing `to` if it's a file.
+    pub fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        fs::rename(self.encrypted_path(from)?, self.encrypted_path(to)?)
+    }
+
+    pub fn remove_file(&self, path: &Path) -> Result<()> {
+        fs::remove_file(self.encrypted_path(path)?)
+    }
+
+    pub fn create_dir(&self, path: &Path) -> Result<()> {
+        fs::create_dir(self.encrypted_path(path)?)
+    }
+
+    /// Removes an empty directory.
+    pub fn remove_dir(&self, path: &Path) -> Result<()> {
+        fs::remove_dir(self.encrypted_path(path)?)
+    }
+
+    /// Removes a directory with everything in it, including entries whose name doesn't decrypt.
+    pub fn remove_dir_all(&self, path: &Path) -> Result<()> {
+        fs::remove_dir_all(self.encrypted_path(path)?)
+    }
+}
+
+/// Returns `path` relative to the root, without any `/` or `.`.
+fn relative_path(path: &Path) -> Result<PathBuf> {
+    let mut relative = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(segment) => relative.push(segment),
+            Component::RootDir | Component::CurDir => {}
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("{} is not a plain path", path.display()),
+                ))
+            }
+        }
+    }
+    Ok(relative)
+}
+
+/// Converts the metadata of an encrypted file, or of a plain one if `encrypted` is `false`.
+fn to_metadata(metadata: &fs::Metadata, encrypted: bool) -> Result<Metadata> {
+    let len = if metadata.is_dir() {
+        0
+    } else if !encrypted {
+        metadata.len()
+    } else {
+        decrypted_size(metadata.len()).ok_or_else(|| invalid_size(metadata.len()))?
+    };
+
+    Ok(Metadata {
+        is_dir: metadata.is_dir(),
+        len,
+        modified: metadata.modified().ok(),
+    })
+}
+
+fn invalid_size(len: u64) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("{} bytes is not a valid encrypted size", len),
+    )
+}
+
+/// Creates a temporary file next to `target`, named like rclone's partial uploads so that
+/// listings skip it as a name which doesn't decrypt.
+pub(crate) fn create_partial(target: &Path) -> Result<(PathBuf, File)> {
+    let name = target.file_name().unwrap_or_default().to_string_lossy();
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.subsec_nanos());
+    let mut attempt = nanos ^ std::process::id();
+    loop {
+        let temporary = target.with_file_name(format!("{}.{:08x}.partial", name, attempt));
+        match File::options()
+            .write(true)
+            .create_new(true)
+            .open(&temporary)
+        {
+            Ok(file) => return Ok((temporary, file)),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => attempt = attempt.wrapping_add(1),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// A file of a [`CryptFs`](CryptFs), open either for reading and seeking or for writing.
+///
+/// Using it the other way round returns an [`ErrorKind::Unsupported`](ErrorKind::Unsupported) error.
+pub struct CryptFile {
+    inner: FileInner,
+    /// Set while the data of a created file is still written to its temporary file.
+    partial: Option<Partial>,
+}
+
+enum FileInner {
+    Read(EncryptedReader<BufReader<File>>),
+    /// `None` once finished.
+    Write(Option<EncryptedWriter<BufWriter<File>>>),
+}
+
+struct Partial {
+    temporary: PathBuf,
+    target: PathBuf,
+}
+
+impl CryptFile {
+    /// Writes the last block and moves the file into place. Does nothing for files open for
+    /// reading, or if already finished.
+    pub fn finish(&mut self) -> Result<()> {
+        let writer = match &mut self.inner {
+            FileInner::Read(_) => return Ok(()),
+            FileInner::Write(writer) => writer,
+        };
+        if let Some(writer) = writer.as_mut() {
+            writer.finish()?;
+        }
+        // Closed before the rename, as open files can't be replaced on some platforms
+        *writer = None;
+
+        match &self.partial {
+            Some(partial) => {
+                fs::rename(&partial.temporary, &partial.target)?;
+                self.partial = None;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn reader(&mut self) -> Result<&mut EncryptedReader<BufReader<File>>> {
+        match &mut self.inner {
+            FileInner::Read(reader) => Ok(reader),
+            FileInner::Write(_) => Err(unsupported("File is open for writing")),
+        }
+    }
+
+    fn writer(&mut self) -> Result<&mut EncryptedWriter<BufWriter<File>>> {
+        match &mut self.inner {
+            FileInner::Write(Some(writer)) => Ok(writer),
+            FileInner::Write(None) => Err(unsupported("File is already finished")),
+            FileInner::Read(_) => Err(unsupported("File is open for reading")),
+        }
+    }
+}
+
+impl Drop for CryptFile {
+    fn drop(&mut self) {
+        if let Some(partial) = self.partial.take() {
+            // Close the file first, it can't be removed while open on some platforms. The data
+            // is thrown away anyway, and finishing it could fail again, e.g. on a full disk.
+            if let FileInner::Write(writer) = &mut self.inner {
+                if let Some(mut writer) = writer.take() {
+                    writer.discard();
+                }
+            }
+            let _ = fs::remove_file(partial.temporary);
+        }
+    }
+}
+
+fn unsupported(message: &str) -> Error {
+    Error::new(ErrorKind::Unsupported, message)
+}
+
+impl Read for CryptFile {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.reader()?.read(buf)
+    }
+}
+
+impl Seek for CryptFile {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        self.reader()?.seek(pos)
+    }
+}
+
+impl Write for CryptFile {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.writer()?.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.writer()?.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CryptFs;
+    use crate::cipher::{Cipher, NamePolicy};
+    use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+    use std::path::Path;
+    use tempfile::TempDir;
+
+    fn crypt_fs() -> (TempDir, Cipher, CryptFs) {
+        let root = tempfile::tempdir().unwrap();
+        let cipher = Cipher::new("test", Some("salt")).unwrap();
+        let fs = CryptFs::new(root.path(), cipher.clone()).unwrap();
+        (root, cipher, fs)
+    }
+
+    fn write(fs: &CryptFs, path: &str, data: &[u8]) {
+        let mut file = fs.create(Path::new(path)).unwrap();
+        file.write_all(data).unwrap();
+        file.finish().unwrap();
+    }
+
+    #[test]
+    fn crypt_fs_should_create_and_read_files() {
+        let (root, cipher, fs) = crypt_fs();
+        let data: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+
+        fs.create_dir(Path::new("dir")).unwrap();
+        write(&fs, "/dir/a.bin", &data);
+        assert!(root
+            .path()
+            .join(cipher.encrypt_path(Path::new("dir/a.bin")).unwrap())
+            .is_file());
+
+        let mut file = fs.open(Path::new("dir/a.bin")).unwrap();
+        file.seek(SeekFrom::Start(70_000)).unwrap();
+        let mut tail = Vec::new();
+        file.read_to_end(&mut tail).unwrap();
+        assert_eq!(tail, data[70_000..]);
+        assert_eq!(file.write(b"x").unwrap_err().kind(), ErrorKind::Unsupported);
+
+        let metadata = fs.metadata(Path::new("dir/a.bin")).unwrap();
+        assert!(!metadata.is_dir);
+        assert_eq!(metadata.len, data.len() as u64);
+        assert_eq!(
+            fs.open(Path::new("../x")).err().unwrap().kind(),
+            ErrorKind::InvalidInput
+        );
+    }
+
+    #[test]
+    fn crypt_fs_should_only_replace_finished_files() {
+        let (_root, _, fs) = crypt_fs();
+        fs.create_dir(Path::new("dir")).unwrap();
+        write(&fs, "dir/a.bin", b"old");
+
+        let mut file = fs.create(Path::new("dir/a.bin")).unwrap();
+        file.write_all(b"new data").unwrap();
+        assert_eq!(fs.metadata(Path::new("dir/a.bin")).unwrap().len, 3);
+        assert_eq!(fs.read_dir(Path::new("dir")).unwrap().len(), 1);
+        drop(file);
+        assert_eq!(fs.metadata(Path::new("dir/a.bin")).unwrap().len, 3);
+        let encrypted_dir = fs.encrypted_path(Path::new("dir")).unwrap();
+        assert_eq!(std::fs::read_dir(&encrypted_dir).unwrap().count(), 1);
+
+        write(&fs, "dir/a.bin", b"new data");
+        assert_eq!(fs.metadata(Path::new("dir/a.bin")).unwrap().len, 8);
+        assert_eq!(std::fs::read_dir(&encrypted_dir).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn crypt_fs_should_rename_and_remove() {
+        let (_root, _, fs) = crypt_fs();
+        fs.create_dir(Path::new("dir")).unwrap();
+        write(&fs, "dir/a.bin", b"data");
+
+        fs.rename(Path::new("dir/a.bin"), Path::new("b.bin"))
+            .unwrap();
+        assert!(fs.read_dir(Path::new("dir")).unwrap().is_empty());
+        assert_eq!(fs.metadata(Path::new("b.bin")).unwrap().len, 4);
+
+        fs.remove_dir(Path::new("dir")).unwrap();
+        fs.remove_file(Path::new("b.bin")).unwrap();
+        assert_eq!(
+            fs.metadata(Path::new("b.bin")).unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+        assert!(fs.read_dir(Path::new("/")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn crypt_fs_should_list_according_to_the_name_policy() {
+        let (root, cipher, fs) = crypt_fs();
+        fs.create_dir(Path::new("dir")).unwrap();
+        write(&fs, "b.bin", b"data");
+
+        std::fs::write(root.path().join("not-encrypted"), b"").unwrap();
+        let crafted = cipher.encrypt_segment("../escaped").unwrap();
+        std::fs::write(root.path().join(&crafted), b"").unwrap();
+        let truncated = cipher.encrypt_segment("truncated.bin").unwrap();
+        std::fs::write(root.path().join(&truncated), b"RCLONE").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(
+            root.path().join("missing"),
+            root.path()
+                .join(cipher.encrypt_segment("dangling").unwrap()),
+        )
+        .unwrap();
+
+        let entries = fs.read_dir(Path::new("/")).unwrap();
+        let names: Vec<_> = entries.iter().map(|entry| entry.name.as_str()).collect();
+        assert_eq!(names, ["b.bin", "dir"]);
+        assert_eq!(entries[0].metadata.len, 4);
+        assert!(entries[1].metadata.is_dir);
+        assert_eq!(entries[1].path, Path::new("dir"));
+
+        let entries = fs
+            .read_dir_with_policy(Path::new("/"), NamePolicy::PassThrough)
+            .unwrap();
+        let names: Vec<_> = entries
+            .iter()
+            .map(|entry| (entry.name.as_str(), entry.undecryptable))
+            .collect();
+        let mut expected = [
+            (crafted.as_str(), true),
+            (truncated.as_str(), true),
+            ("b.bin", false),
+            ("dir", false),
+            ("not-encrypted", true),
+        ];
+        expected.sort();
+        assert_eq!(names, expected);
+        let entry = entries
+            .iter()
+            .find(|entry| entry.name == truncated)
+            .unwrap();
+        assert_eq!(entry.metadata.len, 6);
+
+        // Each kind of bad entry fails the listing on its own
+        std::fs::remove_file(root.path().join(&truncated)).unwrap();
+        std::fs::remove_file(root.path().join(&crafted)).unwrap();
+        let error = fs
+            .read_dir_with_policy(Path::new("/"), NamePolicy::Fail)
+            .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        std::fs::remove_file(root.path().join("not-encrypted")).unwrap();
+
+        std::fs::write(root.path().join(&crafted), b"").unwrap();
+        let error = fs
+            .read_dir_with_policy(Path::new("/"), NamePolicy::Fail)
+            .unwrap_err();
+        assert!(error.to_string().contains("not a plain name"));
+        std::fs::remove_file(root.path().join(&crafted)).unwrap();
+
+        std::fs::write(root.path().join(&truncated), b"RCLONE").unwrap();
+        let error = fs
+            .read_dir_with_policy(Path::new("/"), NamePolicy::Fail)
+            .unwrap_err();
+        assert!(error.to_string().contains("not a valid encrypted size"));
+        std::fs::remove_file(root.path().join(&truncated)).unwrap();
+
+        // Only the dangling symlink is left besides the good entries
+        let entries = fs
+            .read_dir_with_policy(Path::new("/"), NamePolicy::Fail)
+            .unwrap();
+        assert_eq!(entries.len(), 2);
+    }
+}
@@ -1,32 +1,53 @@
-use crate::{calculate_nonce, cipher::FileKey, FILE_MAGIC};
+use crate::backend::{self, Key, Nonce};
+use crate::{
+    calculate_nonce, cipher::FileKey, FileNonce, BLOCK_DATA_SIZE, BLOCK_SIZE, FILE_HEADER_SIZE,
+    FILE_MAGIC, FILE_NONCE_SIZE,
+};
+use alloc::vec::Vec;
 use anyhow::{anyhow, Result};
 use arrayref::array_ref;
-use sodiumoxide::crypto::secretbox;
+use core::ops::Range;
 
 /// Decrypter instance for a single file.
 /// This is not a managed reader; it must be assisted with a separate reader that passes
 /// it blocks of encrypted data of interest.
 pub struct Decrypter {
-    key: secretbox::Key,
-    initial_nonce: secretbox::Nonce,
+    key: Key,
+    initial_nonce: Nonce,
+}
+
+/// Returns the nonce stored in a file header.
+///
+/// `file_header` must start with the [`FILE_HEADER_SIZE`](FILE_HEADER_SIZE) bytes of the header,
+/// any data after it is ignored.
+pub fn read_nonce(file_header: &[u8]) -> Result<FileNonce> {
+    if file_header.len() < FILE_HEADER_SIZE {
+        return Err(anyhow!("File header is too short"));
+    }
+    if &file_header[..FILE_MAGIC.len()] != FILE_MAGIC {
+        return Err(anyhow!("Invalid file magic in file"));
+    }
+
+    Ok(*array_ref!(file_header, FILE_MAGIC.len(), FILE_NONCE_SIZE))
 }
 
 impl Decrypter {
     pub fn new(file_key: &FileKey, file_header: &[u8]) -> Result<Self> {
-        sodiumoxide::init().map_err(|_| anyhow!("Could not initialize sodiumoxide"))?;
-        if &file_header[..FILE_MAGIC.len()] != FILE_MAGIC {
-            return Err(anyhow!("Invalid file magic in file"));
-        }
-
-        let nonce = secretbox::Nonce(*array_ref!(file_header, FILE_MAGIC.len(), 24));
+        backend::init()?;
+        let nonce = read_nonce(file_header)?;
 
         Ok(Decrypter {
-            key: secretbox::Key(*file_key),
+            key: *file_key,
             initial_nonce: nonce,
         })
     }
 
-    fn calculate_nonce(&self, block_id: u64) -> secretbox::Nonce {
+    /// Returns the nonce of the file, as read from its header.
+    pub fn get_nonce(&self) -> FileNonce {
+        self.initial_nonce
+    }
+
+    fn calculate_nonce(&self, block_id: u64) -> Nonce {
         calculate_nonce(self.initial_nonce, block_id)
     }
 
@@ -36,7 +57,43 @@ impl Decrypter {
     pub fn decrypt_block(&self, block_id: u64, block: &[u8]) -> Result<Vec<u8>> {
         let nonce = self.calculate_nonce(block_id);
 
-        secretbox::open(block, &nonce, &self.key)
+        backend::open(block, &nonce, &self.key)
             .map_err(|_| anyhow!("Failed to decrypt block of size {}", block.len()))
     }
+
+    /// Decrypts consecutive blocks, the first of which has the id `first_block`.
+    /// Every block but the last one of `data` must be exactly [`BLOCK_SIZE`](BLOCK_SIZE) bytes.
+    pub fn decrypt_blocks(&self, first_block: u64, data: &[u8]) -> Result<Vec<u8>> {
+        let mut plaintext = Vec::with_capacity(data.len());
+        for (i, block) in data.chunks(BLOCK_SIZE).enumerate() {
+            let block_id = first_block + i as u64;
+            let decrypted = self
+                .decrypt_block(block_id, block)
+                .map_err(|_| anyhow!("Block {} failed authentication", block_id))?;
+            plaintext.extend(decrypted);
+        }
+        Ok(plaintext)
+    }
+
+    /// Decrypts consecutive blocks like [`decrypt_blocks()`](Self::decrypt_blocks) and returns
+    /// the part of them in `range`, a plaintext range of the whole file such as the one given
+    /// to [`encrypted_range()`](crate::encrypted_range). The range may extend past the data.
+    pub fn decrypt_range(
+        &self,
+        first_block: u64,
+        data: &[u8],
+        range: Range<u64>,
+    ) -> Result<Vec<u8>> {
+        let mut plaintext = self.decrypt_blocks(first_block, data)?;
+
+        let offset = first_block * BLOCK_DATA_SIZE as u64;
+        let start = range
+            .start
+            .saturating_sub(offset)
+            .min(plaintext.len() as u64) as usize;
+        let end = range.end.saturating_sub(offset).min(plaintext.len() as u64) as usize;
+        plaintext.truncate(end.max(start));
+        plaintext.drain(..start);
+        Ok(plaintext)
+    }
 }
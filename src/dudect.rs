@@ -0,0 +1,86 @@
+//! A small dudect-style leakage detector used by the timing tests.
+//!
+//! Based on "Dude, is my code constant time?" (Reparaz, Balasch, Verbauwhede): inputs are
+//! split into two classes, the operation is timed on randomly interleaved inputs of both
+//! classes, and Welch's t-test tells whether the two timing distributions differ.
+//! These tests are noisy by nature, so they are `#[ignore]`d and meant to be run with
+//! `cargo test --release -- --ignored`.
+use std::hint::black_box;
+use std::time::Instant;
+
+/// Number of timed measurements.
+const MEASUREMENTS: usize = 200_000;
+/// Number of calls per measurement, to get above the timer resolution.
+const CALLS_PER_MEASUREMENT: usize = 16;
+/// Measurements above this percentile are discarded (interrupts, context switches...).
+const CROP_PERCENTILE: f64 = 0.9;
+/// dudect considers |t| above 10 as a definite leak.
+pub(crate) const T_THRESHOLD: f64 = 10.0;
+
+/// Tiny xorshift generator, good enough to pick classes and build inputs.
+pub(crate) struct XorShift(u64);
+
+impl XorShift {
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    pub(crate) fn fill(&mut self, buf: &mut [u8]) {
+        for byte in buf {
+            *byte = self.next_u64() as u8;
+        }
+    }
+}
+
+/// Times `op` on inputs built by `make_input(class, rng)` and returns Welch's t statistic
+/// between the two classes.
+pub(crate) fn leakage_t<I>(
+    mut make_input: impl FnMut(bool, &mut XorShift) -> I,
+    mut op: impl FnMut(&I),
+) -> f64 {
+    let mut rng = XorShift(0x2545_f491_4f6c_dd1d);
+
+    let classes: Vec<bool> = (0..MEASUREMENTS).map(|_| rng.next_u64() & 1 == 1).collect();
+    let inputs: Vec<I> = classes
+        .iter()
+        .map(|&class| make_input(class, &mut rng))
+        .collect();
+
+    let timings: Vec<f64> = inputs
+        .iter()
+        .map(|input| {
+            let start = Instant::now();
+            for _ in 0..CALLS_PER_MEASUREMENT {
+                op(black_box(input));
+            }
+            start.elapsed().as_nanos() as f64
+        })
+        .collect();
+
+    let mut sorted = timings.clone();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let cutoff = sorted[(sorted.len() as f64 * CROP_PERCENTILE) as usize];
+
+    let mut stats = [(0f64, 0f64, 0f64); 2]; // (n, mean, m2) per class
+    for (&class, &timing) in classes.iter().zip(&timings) {
+        if timing > cutoff {
+            continue;
+        }
+
+        // Welford's online mean/variance
+        let (n, mean, m2) = &mut stats[class as usize];
+        *n += 1.0;
+        let delta = timing - *mean;
+        *mean += delta / *n;
+        *m2 += delta * (timing - *mean);
+    }
+
+    let [(n0, mean0, m20), (n1, mean1, m21)] = stats;
+    let var0 = m20 / (n0 - 1.0);
+    let var1 = m21 / (n1 - 1.0);
+
+    (mean0 - mean1) / (var0 / n0 + var1 / n1).sqrt()
+}
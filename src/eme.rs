@@ -1,238 +1,416 @@
-/// AES-EME that works with 16-byte blocks up to 2048 bytes.
+/// EME (ECB-Mix-ECB) wide-block encryption, as described by Halevi and Rogaway in
+/// "A Parallelizable Enciphering Mode". Works with 16-byte blocks up to 2048 bytes.
 /// Adapted from the Golang version: https://github.com/rfjakob/eme
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, Result};
 
-extern crate aes;
 use aes::Aes256;
-use block_modes::block_padding::NoPadding;
-use block_modes::{BlockMode, Ecb};
+use arrayref::{array_mut_ref, array_ref};
+use cipher::consts::U16;
+use cipher::generic_array::GenericArray;
+use cipher::{BlockCipher, BlockDecrypt, BlockEncrypt, BlockSizeUser, KeyInit};
+use core::fmt;
 
-type Aes256Ecb = Ecb<Aes256, NoPadding>;
+/// Size of a single block of the underlying block cipher.
+pub const NAME_CIPHER_BLOCK_SIZE: usize = 16;
 
-pub const NAME_CIPHER_BLOCK_SIZE: usize = 16; // AES block size
+/// EME is only defined for up to 16 * 8 blocks (2048 bytes).
+pub const EME_MAX_BLOCKS: usize = 16 * 8;
 
-// AES-256 is used
-pub const EME_KEY_LENGTH: usize = 32;
+type Block = [u8; NAME_CIPHER_BLOCK_SIZE];
+
+/// EME construct using AES-256, as used by rclone for file name encryption.
+pub type AesEme = Eme<Aes256>;
+
+/// The 16-byte tweak of an EME transformation.
+///
+/// Encrypting the same data with different tweaks produces unrelated ciphertexts.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Tweak(pub [u8; NAME_CIPHER_BLOCK_SIZE]);
+
+impl From<[u8; NAME_CIPHER_BLOCK_SIZE]> for Tweak {
+    fn from(tweak: [u8; NAME_CIPHER_BLOCK_SIZE]) -> Self {
+        Self(tweak)
+    }
+}
 
 pub enum TransformDirection {
     Encrypt,
     Decrypt,
 }
 
-#[derive(Clone, Debug)]
-pub struct AesEme {
-    key: [u8; EME_KEY_LENGTH],
+/// EME wide-block cipher on top of any 128-bit block cipher `C`.
+///
+/// The whole message (1 to [`EME_MAX_BLOCKS`](EME_MAX_BLOCKS) blocks) is enciphered as a single unit,
+/// so changing any input byte changes every output byte.
+///
+/// # Example
+/// ```
+/// use rclone_crypt::eme::{AesEme, Tweak};
+///
+/// let eme = AesEme::new_from_slice(&[0u8; 32]).unwrap();
+/// let tweak = Tweak([0u8; 16]);
+///
+/// let mut data = *b"0123456789abcdef0123456789abcdef";
+/// eme.encrypt(&tweak, &mut data).unwrap();
+/// assert_ne!(&data, b"0123456789abcdef0123456789abcdef");
+///
+/// eme.decrypt(&tweak, &mut data).unwrap();
+/// assert_eq!(&data, b"0123456789abcdef0123456789abcdef");
+/// ```
+#[derive(Clone)]
+pub struct Eme<C> {
+    /// Block cipher with an already expanded key schedule.
+    cipher: C,
+    /// `L * 2^j` for every block index `j`, up to the maximum of 128 blocks.
+    ltable: [Block; EME_MAX_BLOCKS],
 }
 
-fn mult_by_two(data: &mut [u8]) {
+impl<C> fmt::Debug for Eme<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Don't leak the key schedule into logs
+        f.debug_struct("Eme").finish_non_exhaustive()
+    }
+}
+
+/// Multiplies `data` by two in GF(2^128).
+///
+/// Runs in constant time: the carry bits are applied with shifts and masks instead of branches.
+fn mult_by_two(data: &mut Block) {
     let mut tmp = [0u8; 16];
-    tmp[0] = data[0].wrapping_mul(2);
 
-    if data[15] >= 128 {
-        tmp[0] ^= 135;
-    }
+    // 0xff if the top bit is set, 0x00 otherwise
+    let carry_mask = 0u8.wrapping_sub(data[15] >> 7);
+    tmp[0] = (data[0] << 1) ^ (carry_mask & 135);
 
     for i in 1..16 {
-        tmp[i] = data[i].wrapping_mul(2);
-
-        if data[i - 1] >= 128 {
-            tmp[i] += 1;
-        }
+        tmp[i] = (data[i] << 1) | (data[i - 1] >> 7);
     }
 
-    data.copy_from_slice(&tmp);
+    *data = tmp;
 }
 
-fn xor_blocks(out: &mut [u8], in1: &[u8], in2: &[u8]) {
-    if in1.len() != in2.len() {
-        panic!("xorBlocks length must be the same");
+fn xor_blocks(out: &mut Block, in1: &Block, in2: &Block) {
+    for i in 0..out.len() {
+        out[i] = in1[i] ^ in2[i];
     }
+}
 
-    for i in 0..in1.len() {
-        out[i] = in1[i] ^ in2[i];
+impl<C> Eme<C>
+where
+    C: BlockCipher + BlockEncrypt + BlockDecrypt + KeyInit + BlockSizeUser<BlockSize = U16>,
+{
+    /// Creates a new instance from a raw key for `C`.
+    ///
+    /// # Errors
+    /// Fails if `key` is not a valid key length for `C`.
+    pub fn new_from_slice(key: &[u8]) -> Result<Self> {
+        let cipher = C::new_from_slice(key).map_err(|_| anyhow!("Invalid EME key length"))?;
+        Ok(Self::new(cipher))
     }
 }
 
-impl AesEme {
-    pub fn new(key: [u8; EME_KEY_LENGTH]) -> Result<Self> {
-        Ok(AesEme { key })
+impl<C> Eme<C>
+where
+    C: BlockCipher + BlockEncrypt + BlockDecrypt + BlockSizeUser<BlockSize = U16>,
+{
+    /// Creates a new instance using an already keyed block cipher.
+    pub fn new(cipher: C) -> Self {
+        let ltable = Self::tabulate_l(&cipher);
+
+        Eme { cipher, ltable }
     }
 
-    fn tabulate_l(&self, m: usize) -> Result<Vec<Vec<u8>>> {
+    fn tabulate_l(cipher: &C) -> [Block; EME_MAX_BLOCKS] {
         // set L0 = 2*AESenc(K; 0)
         let mut li = [0u8; 16];
+        cipher.encrypt_block(GenericArray::from_mut_slice(&mut li));
 
-        let cipher = Aes256Ecb::new_from_slices(&self.key, Default::default())?;
-        let length = li.len();
-        cipher.encrypt(&mut li, length)?;
-
-        // Must be only 16 bytes...
-        let mut li: Vec<u8> = li.iter().take(16).cloned().collect();
-
-        let mut ltable = Vec::new();
+        let mut ltable = [[0u8; 16]; EME_MAX_BLOCKS];
 
-        for _i in 0..m {
+        for entry in ltable.iter_mut() {
             mult_by_two(&mut li);
-            ltable.push(li.clone());
+            *entry = li;
         }
 
-        Ok(ltable)
+        ltable
     }
 
-    fn aes_transform(&self, data: &[u8], direction: &TransformDirection) -> Result<Vec<u8>> {
-        let mut data = data.to_vec();
-
-        let cipher = Aes256Ecb::new_from_slices(&self.key, Default::default())?;
-        let length = data.len();
+    fn block_transform(&self, block: &mut Block, direction: &TransformDirection) {
+        let block = GenericArray::from_mut_slice(block);
 
         match direction {
-            TransformDirection::Encrypt => cipher.encrypt(&mut data, length)?,
-            TransformDirection::Decrypt => cipher.decrypt(&mut data)?,
-        };
-
-        Ok(data)
+            TransformDirection::Encrypt => self.cipher.encrypt_block(block),
+            TransformDirection::Decrypt => self.cipher.decrypt_block(block),
+        }
     }
 
-    pub fn decrypt(&self, tweak: &[u8; NAME_CIPHER_BLOCK_SIZE], data: &[u8]) -> Result<Vec<u8>> {
-        let result = self.transform(tweak, data, TransformDirection::Decrypt)?;
-        Ok(result)
+    /// Decrypts `data` in place.
+    ///
+    /// # Errors
+    /// Fails if the length of `data` isn't a multiple of 16 bytes or is outside
+    /// of the 16 to 2048 bytes range.
+    pub fn decrypt(&self, tweak: &Tweak, data: &mut [u8]) -> Result<()> {
+        self.transform(tweak, data, TransformDirection::Decrypt)
     }
 
-    pub fn encrypt(&self, tweak: &[u8; NAME_CIPHER_BLOCK_SIZE], data: &[u8]) -> Result<Vec<u8>> {
-        let result = self.transform(tweak, data, TransformDirection::Encrypt)?;
-        Ok(result)
+    /// Encrypts `data` in place.
+    ///
+    /// # Errors
+    /// Same as [`decrypt()`](Self::decrypt).
+    pub fn encrypt(&self, tweak: &Tweak, data: &mut [u8]) -> Result<()> {
+        self.transform(tweak, data, TransformDirection::Encrypt)
     }
 
+    /// Encrypts or decrypts `data` in place, depending on `direction`.
     pub fn transform(
         &self,
-        tweak: &[u8; NAME_CIPHER_BLOCK_SIZE],
-        data: &[u8],
+        tweak: &Tweak,
+        data: &mut [u8],
         direction: TransformDirection,
-    ) -> Result<Vec<u8>> {
+    ) -> Result<()> {
         // In the paper, the tweak is just called "t". Call it the same here to
         // make following the paper easy.
-        let t = tweak;
+        let t = &tweak.0;
 
         // In the paper, the plaintext data is called "p" and the ciphertext is
         // called "c". Because encryption and decryption are virtually identical,
         // we share the code and always call the input data "p" and the output data
-        // "c", regardless of the direction.
-        let p = data;
-
-        if tweak.len() != 16 {
-            return Err(anyhow!("Tweak must be of size 16"));
-        }
-
-        if p.len() % 16 != 0 {
+        // "c", regardless of the direction. Here the transformation happens in place,
+        // so "p" is overwritten by "c" block by block.
+        if !data.len().is_multiple_of(16) {
             return Err(anyhow!("Data size must be a multiple of 16"));
         }
 
-        let num_blocks = p.len() / 16;
-        if num_blocks == 0 || num_blocks > 16 * 8 {
+        let num_blocks = data.len() / 16;
+        if num_blocks == 0 || num_blocks > EME_MAX_BLOCKS {
             return Err(anyhow!(
                 "EME operates on 1 to {} blocks; you passed {}",
-                16 * 8,
+                EME_MAX_BLOCKS,
                 num_blocks
             ));
         }
 
-        let mut c = vec![0u8; p.len()];
+        let ltable = &self.ltable[..num_blocks];
 
-        let ltable = self.tabulate_l(num_blocks)?;
-
-        let mut ppj = [0u8; 16];
-        for i in 0..num_blocks {
-            // AES block
-            let pj = &p[i * 16..(i + 1) * 16];
+        for (j, block) in data.chunks_exact_mut(16).enumerate() {
+            let pj = array_mut_ref!(block, 0, 16);
             // ppj = 2**(j-1)*L xor pj
-            // xorBlocks(ppj, pj, ltable[j])
-            xor_blocks(&mut ppj, pj, &ltable[i]);
+            let in1 = *pj;
+            xor_blocks(pj, &in1, &ltable[j]);
             // PPPj = AESenc(K; ppj)
-            // aes_transform(c[j*16:(j+1)*16], ppj, direction, bc)
-            let result = self
-                .aes_transform(&ppj, &direction)
-                .with_context(|| format!("L131 i = {}", i))?;
-            c[i * 16..(i + 1) * 16].copy_from_slice(&result);
+            self.block_transform(pj, &direction);
         }
 
         // mp =(xorSum PPPj) xor t
         let mut mp = [0u8; 16];
-        // xorBlocks(mp, c[0:16], t)
-        xor_blocks(&mut mp, &c[0..16], t);
-        for i in 1..num_blocks {
+        xor_blocks(&mut mp, array_ref!(data, 0, 16), t);
+        for block in data[16..].chunks_exact(16) {
             let in1 = mp;
-            xor_blocks(&mut mp, &in1, &c[i * 16..(i + 1) * 16]);
+            xor_blocks(&mut mp, &in1, array_ref!(block, 0, 16));
         }
 
         // mc = AESenc(K; mp)
-        // aes_transform(mc, mp, direction, bc)
-        let mc = self
-            .aes_transform(&mp, &direction)
-            .with_context(|| "test2".to_string())?;
+        let mut mc = mp;
+        self.block_transform(&mut mc, &direction);
 
         // m = mp xor mc
         let mut m = [0u8; 16];
         xor_blocks(&mut m, &mp, &mc);
-        let mut cccj = [0u8; 16];
-        for i in 1..num_blocks {
+        for block in data[16..].chunks_exact_mut(16) {
             mult_by_two(&mut m);
             // cccj = 2**(j-1)*m xor PPPj
-            xor_blocks(&mut cccj, &c[i * 16..(i + 1) * 16], &m);
-            c[i * 16..(i + 1) * 16].copy_from_slice(&cccj);
+            let cj = array_mut_ref!(block, 0, 16);
+            let in1 = *cj;
+            xor_blocks(cj, &in1, &m);
         }
 
         // ccc1 = (xorSum cccj) xor t xor mc
         let mut ccc1 = [0u8; 16];
         xor_blocks(&mut ccc1, &mc, t);
-        for i in 1..num_blocks {
+        for block in data[16..].chunks_exact(16) {
             let in1 = ccc1;
-            xor_blocks(&mut ccc1, &in1, &c[i * 16..(i + 1) * 16]);
+            xor_blocks(&mut ccc1, &in1, array_ref!(block, 0, 16));
         }
-        c[0..16].copy_from_slice(&ccc1);
+        data[..16].copy_from_slice(&ccc1);
 
-        for i in 0..num_blocks {
+        for (j, block) in data.chunks_exact_mut(16).enumerate() {
+            let cj = array_mut_ref!(block, 0, 16);
             // CCj = AES-enc(K; cccj)
-            let block = &mut c[i * 16..(i + 1) * 16];
-            // aes_transform(c[j*16:(j+1)*16], c[j*16:(j+1)*16], direction, bc)
-            let result = self
-                .aes_transform(block, &direction)
-                .with_context(|| "test3".to_string())?;
-            block.copy_from_slice(&result);
+            self.block_transform(cj, &direction);
             // Cj = 2**(j-1)*L xor CCj
-            // xorBlocks(c[j*16:(j+1)*16], c[j*16:(j+1)*16], ltable[j])
-            let in1 = block.to_vec();
-            xor_blocks(block, &in1, &ltable[i]);
+            let in1 = *cj;
+            xor_blocks(cj, &in1, &ltable[j]);
         }
 
-        Ok(c)
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::eme::{AesEme, TransformDirection};
+    use crate::dudect::{leakage_t, T_THRESHOLD};
+    use crate::eme::{mult_by_two, AesEme, Eme, TransformDirection, Tweak};
+    use aes::{Aes128, Aes256};
     use anyhow::Result;
+    use arrayref::array_ref;
     use data_encoding::HEXLOWER;
 
     #[test]
     fn eme_should_encrypt_16_bytes_encrypts_successfully() -> Result<()> {
         let key = [0u8; 32];
-        let tweak = [0u8; 16];
-        let plaintext = [0u8; 16];
+        let tweak = Tweak([0u8; 16]);
+        let mut data = [0u8; 16];
 
-        let eme = AesEme::new(key)?;
-        let ciphertext = eme.transform(&tweak, &plaintext, TransformDirection::Encrypt)?;
-        let back_to_plaintext = eme.transform(&tweak, &ciphertext, TransformDirection::Decrypt)?;
+        let eme = AesEme::new_from_slice(&key)?;
+        eme.transform(&tweak, &mut data, TransformDirection::Encrypt)?;
+        assert_eq!("f1b9ce8ca15a4ba9fb476905434b9fd3", HEXLOWER.encode(&data));
 
-        assert_eq!(
-            "f1b9ce8ca15a4ba9fb476905434b9fd3",
-            HEXLOWER.encode(&ciphertext)
+        eme.transform(&tweak, &mut data, TransformDirection::Decrypt)?;
+        assert_eq!("00000000000000000000000000000000", HEXLOWER.encode(&data));
+
+        Ok(())
+    }
+
+    #[test]
+    fn mult_by_two_should_double_in_gf128() {
+        let mut block = [0u8; 16];
+        block[0] = 1;
+        mult_by_two(&mut block);
+        assert_eq!(block[0], 2);
+
+        // x^127 * x = x^7 + x^2 + x + 1
+        let mut block = [0u8; 16];
+        block[15] = 0x80;
+        mult_by_two(&mut block);
+        assert_eq!(block[0], 0x87);
+        assert!(block[1..].iter().all(|&b| b == 0));
+
+        // Carry between bytes
+        let mut block = [0u8; 16];
+        block[3] = 0x81;
+        mult_by_two(&mut block);
+        assert_eq!(&block[3..5], &[0x02, 0x01]);
+    }
+
+    #[test]
+    #[ignore = "timing test; run with `cargo test --release -- --ignored`"]
+    fn mult_by_two_should_be_constant_time() {
+        // Class 0 never carries out of the top bit, class 1 always does
+        let t = leakage_t(
+            |class, rng| {
+                let mut block = [0u8; 16];
+                rng.fill(&mut block);
+                block[15] = if class {
+                    block[15] | 0x80
+                } else {
+                    block[15] & 0x7f
+                };
+                block
+            },
+            |block| {
+                let mut block = *block;
+                mult_by_two(&mut block);
+                std::hint::black_box(block);
+            },
         );
+
+        assert!(t.abs() < T_THRESHOLD, "timing leak detected, t = {}", t);
+    }
+
+    #[test]
+    fn eme_should_roundtrip_every_block_count() -> Result<()> {
+        let eme = AesEme::new_from_slice(&[7u8; 32])?;
+        let tweak = Tweak([3u8; 16]);
+
+        for num_blocks in 1..=128 {
+            let plaintext: Vec<u8> = (0..num_blocks * 16).map(|i| i as u8).collect();
+            let mut data = plaintext.clone();
+
+            eme.encrypt(&tweak, &mut data)?;
+            assert_ne!(data, plaintext);
+            eme.decrypt(&tweak, &mut data)?;
+            assert_eq!(data, plaintext);
+        }
+
+        assert!(eme.encrypt(&tweak, &mut [0u8; 129 * 16]).is_err());
+        assert!(eme.encrypt(&tweak, &mut [0u8; 17]).is_err());
+        assert!(eme.encrypt(&tweak, &mut []).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn eme_should_work_with_other_block_ciphers() -> Result<()> {
+        let eme = Eme::<Aes128>::new_from_slice(&[1u8; 16])?;
+        let tweak = Tweak([2u8; 16]);
+        let mut data = [5u8; 64];
+
+        eme.encrypt(&tweak, &mut data)?;
+        assert_ne!(data, [5u8; 64]);
+        eme.decrypt(&tweak, &mut data)?;
+        assert_eq!(data, [5u8; 64]);
+
+        assert!(Eme::<Aes128>::new_from_slice(&[1u8; 32]).is_err());
+
+        Ok(())
+    }
+
+    /// Runs the chained EME-32-AES test vectors published by the EME authors on the
+    /// IEEE P1619 mailing list: the first vector transforms 512 zero bytes with an
+    /// all-zero key and tweak. Every following vector takes the previous output as
+    /// input and tweak, keeps the key taken from the first output, and applies the
+    /// transformation 100 times.
+    fn run_chained_vectors(vectors: &[u8], direction: fn() -> TransformDirection) -> Result<()> {
+        let mut vectors = vectors.chunks(512);
+
+        let mut data = [0u8; 512];
+        let eme = Eme::<Aes256>::new_from_slice(&[0u8; 32])?;
+        eme.transform(&Tweak([0u8; 16]), &mut data, direction())?;
+        assert_eq!(&data[..], vectors.next().unwrap());
+
+        let eme = Eme::<Aes256>::new_from_slice(&data[..32])?;
+        for expected in vectors {
+            let tweak = Tweak(*array_ref!(data, 32, 16));
+            for _ in 0..100 {
+                eme.transform(&tweak, &mut data, direction())?;
+            }
+            assert_eq!(&data[..], expected);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn eme_should_match_p1619_encryption_vectors() -> Result<()> {
+        run_chained_vectors(
+            include_bytes!("../testdata/eme/eme-512-aes256-enc.ciphertext.bin"),
+            || TransformDirection::Encrypt,
+        )
+    }
+
+    #[test]
+    fn eme_should_match_p1619_decryption_vectors() -> Result<()> {
+        run_chained_vectors(
+            include_bytes!("../testdata/eme/eme-512-aes256-dec.ciphertext.bin"),
+            || TransformDirection::Decrypt,
+        )
+    }
+
+    #[test]
+    fn eme_should_match_2048_byte_vector() -> Result<()> {
+        // Known answer from the Go implementation (github.com/rfjakob/eme)
+        let eme = AesEme::new_from_slice(&[0u8; 32])?;
+        let mut data = [0u8; 2048];
+
+        eme.encrypt(&Tweak([0u8; 16]), &mut data)?;
         assert_eq!(
-            "00000000000000000000000000000000",
-            HEXLOWER.encode(&back_to_plaintext)
+            &data[..],
+            &include_bytes!("../testdata/eme/eme-2048-aes256.ciphertext.bin")[..]
         );
 
+        eme.decrypt(&Tweak([0u8; 16]), &mut data)?;
+        assert_eq!(data, [0u8; 2048]);
+
         Ok(())
     }
 }
@@ -1,35 +1,54 @@
-use anyhow::{anyhow, Result};
-use sodiumoxide::crypto::secretbox;
+use alloc::vec::Vec;
+use anyhow::Result;
 
-use crate::{calculate_nonce, cipher::FileKey, FILE_HEADER_SIZE, FILE_MAGIC};
+use crate::backend::{self, Key, Nonce};
+use crate::{
+    calculate_nonce, cipher::FileKey, FileNonce, BLOCK_DATA_SIZE, FILE_HEADER_SIZE, FILE_MAGIC,
+};
 
 /// Encrypter instance for a single file.
 /// This is not a managed writer; it must be assisted with a separate reader that passes
 /// it blocks of data to encrypt.
 pub struct Encrypter {
-    key: secretbox::Key,
-    initial_nonce: secretbox::Nonce,
+    key: Key,
+    initial_nonce: Nonce,
 }
 
 impl Encrypter {
     pub fn new(file_key: &FileKey) -> Result<Self> {
-        sodiumoxide::init().map_err(|_| anyhow!("Could not initialize sodiumoxide"))?;
-        let initial_nonce = secretbox::gen_nonce();
+        backend::init()?;
+        let mut initial_nonce = FileNonce::default();
+        backend::random_bytes(&mut initial_nonce)?;
+
+        Self::new_with_nonce(file_key, &initial_nonce)
+    }
+
+    /// Same as [`new()`](Self::new), but uses the given nonce instead of a random one.
+    ///
+    /// Reusing a nonce with the same key leaks information about the plaintext, so this is only meant
+    /// for reproducing an existing encrypted file, e.g. to compare it against its plaintext.
+    pub fn new_with_nonce(file_key: &FileKey, nonce: &FileNonce) -> Result<Self> {
+        backend::init()?;
 
         Ok(Encrypter {
-            key: secretbox::Key(*file_key),
-            initial_nonce,
+            key: *file_key,
+            initial_nonce: *nonce,
         })
     }
 
-    fn calculate_nonce(&self, block_id: u64) -> secretbox::Nonce {
+    fn calculate_nonce(&self, block_id: u64) -> Nonce {
         calculate_nonce(self.initial_nonce, block_id)
     }
 
+    /// Returns the nonce of the file, which is part of its header.
+    pub fn get_nonce(&self) -> FileNonce {
+        self.initial_nonce
+    }
+
     pub fn get_file_header(&self) -> Vec<u8> {
         let mut out = Vec::with_capacity(FILE_HEADER_SIZE);
         out.extend_from_slice(FILE_MAGIC);
-        out.extend_from_slice(&self.initial_nonce.0);
+        out.extend_from_slice(&self.initial_nonce);
 
         out
     }
@@ -37,6 +56,15 @@ impl Encrypter {
     pub fn encrypt_block(&self, block_id: u64, block: &[u8]) -> Vec<u8> {
         let nonce = self.calculate_nonce(block_id);
 
-        secretbox::seal(block, &nonce, &self.key)
+        backend::seal(block, &nonce, &self.key)
+    }
+
+    /// Encrypts a whole file held in memory, returning its header and blocks.
+    pub fn encrypt_all(&self, data: &[u8]) -> Vec<u8> {
+        let mut encrypted = self.get_file_header();
+        for (block_id, block) in data.chunks(BLOCK_DATA_SIZE).enumerate() {
+            encrypted.extend(self.encrypt_block(block_id as u64, block));
+        }
+        encrypted
     }
 }
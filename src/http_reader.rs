@@ -0,0 +1,413 @@
+/// A seekable reader over HTTP(S) range requests, e.g. to decrypt parts of remote files.
+use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom};
+use std::ops::Range;
+use std::thread;
+use std::time::Duration;
+
+use crate::fetch_window;
+
+/// How failed requests are retried.
+///
+/// Connection errors, truncated responses and the statuses 408, 429, 500, 502, 503 and 504 are
+/// retried, after a backoff which doubles with every attempt.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt, 0 to fail right away.
+    pub max_retries: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+impl RetryPolicy {
+    fn backoff(&self, retry: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(self.max_backoff)
+    }
+}
+
+/// Options of [`HttpRangeReader::open()`](HttpRangeReader::open).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FetchOptions {
+    /// Encrypted blocks to fetch per request. The first request also fetches the file header.
+    pub blocks_per_fetch: u64,
+    pub retry: RetryPolicy,
+}
+
+impl Default for FetchOptions {
+    fn default() -> Self {
+        Self {
+            // About 1 MiB
+            blocks_per_fetch: 16,
+            retry: RetryPolicy::default(),
+        }
+    }
+}
+
+/// A [`Read`](Read) + [`Seek`](Seek) view of a remote file, fetched with HTTP `Range` requests.
+///
+/// Fetches cover `FILE_HEADER_SIZE + n * BLOCK_SIZE` bytes for the first one and `n * BLOCK_SIZE`
+/// bytes after it, aligned to encrypted blocks, so an [`EncryptedReader`](crate::stream::EncryptedReader)
+/// on top of it never needs two requests for a block. The last fetch is kept, so reading
+/// sequentially makes one request per `n` blocks.
+///
+/// The server must support range requests, e.g. object stores, pre-signed URLs or
+/// `rclone serve http` of the encrypted remote.
+pub struct HttpRangeReader {
+    agent: ureq::Agent,
+    url: String,
+    options: FetchOptions,
+    len: u64,
+    position: u64,
+    buffer: Vec<u8>,
+    buffer_start: u64,
+}
+
+impl HttpRangeReader {
+    /// Opens `url` with a default [`ureq::Agent`](ureq::Agent).
+    ///
+    /// The first range is fetched right away, which also tells the size of the file.
+    pub fn open(url: &str, options: FetchOptions) -> Result<Self> {
+        Self::open_with_agent(ureq::Agent::new(), url, options)
+    }
+
+    /// Opens `url` with an agent configured for timeouts, proxies or TLS.
+    pub fn open_with_agent(agent: ureq::Agent, url: &str, options: FetchOptions) -> Result<Self> {
+        if options.blocks_per_fetch == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "At least one block must be fetched per request",
+            ));
+        }
+
+        let mut reader = Self {
+            agent,
+            url: url.to_string(),
+            options,
+            len: 0,
+            position: 0,
+            buffer: Vec::new(),
+            buffer_start: 0,
+        };
+        let (len, buffer) = reader.fetch_with_retries(fetch_window(0, options.blocks_per_fetch))?;
+        reader.len = len;
+        reader.buffer = buffer;
+        Ok(reader)
+    }
+
+    /// Size of the remote file.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn fetch_with_retries(&self, range: Range<u64>) -> Result<(u64, Vec<u8>)> {
+        let retry = &self.options.retry;
+        let mut attempt = 0;
+        loop {
+            match self.fetch(range.clone()) {
+                Err(Fetch::Retry(_)) if attempt < retry.max_retries => {
+                    thread::sleep(retry.backoff(attempt));
+                    attempt += 1;
+                }
+                Err(Fetch::Retry(e)) | Err(Fetch::Fail(e)) => return Err(e),
+                Ok(fetched) => return Ok(fetched),
+            }
+        }
+    }
+
+    /// Fetches `start..end`, or the part of it within the file, and returns the file size too.
+    fn fetch(
+        &self,
+        Range { start, end }: Range<u64>,
+    ) -> std::result::Result<(u64, Vec<u8>), Fetch> {
+        let response = match self
+            .agent
+            .get(&self.url)
+            .set("Range", &format!("bytes={}-{}", start, end - 1))
+            .call()
+        {
+            Ok(response) => response,
+            // An empty file has no satisfiable ranges
+            Err(ureq::Error::Status(416, response)) => {
+                return match content_range(&response) {
+                    Some((None, len)) if start >= len => Ok((len, Vec::new())),
+                    _ => Err(Fetch::Fail(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Range {}-{} is not satisfiable", start, end),
+                    ))),
+                };
+            }
+            Err(ureq::Error::Status(code, _)) => {
+                let kind = match code {
+                    404 => ErrorKind::NotFound,
+                    401 | 403 => ErrorKind::PermissionDenied,
+                    _ => ErrorKind::Other,
+                };
+                let e = Error::new(kind, format!("{} returned status {}", self.url, code));
+                return Err(match code {
+                    408 | 429 | 500 | 502 | 503 | 504 => Fetch::Retry(e),
+                    _ => Fetch::Fail(e),
+                });
+            }
+            Err(ureq::Error::Transport(e)) => return Err(Fetch::Retry(Error::other(e))),
+        };
+
+        let len = match (response.status(), content_range(&response)) {
+            (206, Some((Some(range_start), len))) if range_start == start => len,
+            _ => {
+                return Err(Fetch::Fail(Error::new(
+                    ErrorKind::Unsupported,
+                    format!("{} doesn't support range requests", self.url),
+                )))
+            }
+        };
+
+        let expected = end.min(len).saturating_sub(start);
+        let mut data = Vec::with_capacity(expected as usize);
+        response
+            .into_reader()
+            .take(expected)
+            .read_to_end(&mut data)
+            .map_err(Fetch::Retry)?;
+        if (data.len() as u64) < expected {
+            return Err(Fetch::Retry(Error::new(
+                ErrorKind::UnexpectedEof,
+                format!("Response of {} was cut off", self.url),
+            )));
+        }
+
+        Ok((len, data))
+    }
+}
+
+/// A failed fetch, and whether it's worth retrying.
+enum Fetch {
+    Retry(Error),
+    Fail(Error),
+}
+
+/// Parses `Content-Range: bytes start-end/len` and `bytes */len`.
+fn content_range(response: &ureq::Response) -> Option<(Option<u64>, u64)> {
+    let value = response
+        .header("Content-Range")?
+        .trim()
+        .strip_prefix("bytes ")?;
+    let (range, len) = value.split_once('/')?;
+    let len = len.trim().parse().ok()?;
+
+    if range.trim() == "*" {
+        return Some((None, len));
+    }
+    let (start, _) = range.split_once('-')?;
+    Some((Some(start.trim().parse().ok()?), len))
+}
+
+impl Read for HttpRangeReader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.position >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let buffer_end = self.buffer_start + self.buffer.len() as u64;
+        if self.position < self.buffer_start || self.position >= buffer_end {
+            let window = fetch_window(self.position, self.options.blocks_per_fetch);
+            self.buffer_start = window.start;
+            let (_, buffer) = self.fetch_with_retries(window)?;
+            self.buffer = buffer;
+        }
+
+        let offset = (self.position - self.buffer_start) as usize;
+        let available = &self.buffer[offset..];
+        let n = buf.len().min(available.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for HttpRangeReader {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::End(n) => self.len.checked_add_signed(n),
+            SeekFrom::Current(n) => self.position.checked_add_signed(n),
+        };
+
+        self.position = position.ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                "Invalid seek to a negative position",
+            )
+        })?;
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FetchOptions, HttpRangeReader, RetryPolicy};
+    use crate::cipher::Cipher;
+    use crate::stream::EncryptedReader;
+    use crate::tests::encrypt;
+    use std::io::{ErrorKind, Read, Seek, SeekFrom};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+    use tiny_http::{Header, Response, Server};
+
+    /// Serves `data` with range support, failing the first `failures` requests with 503.
+    struct TestServer {
+        server: Arc<Server>,
+        ranges: Arc<Mutex<Vec<String>>>,
+        failures: Arc<AtomicUsize>,
+    }
+
+    impl TestServer {
+        fn start(data: Vec<u8>) -> Self {
+            let server = Arc::new(Server::http("127.0.0.1:0").unwrap());
+            let ranges = Arc::new(Mutex::new(Vec::new()));
+            let failures = Arc::new(AtomicUsize::new(0));
+
+            thread::spawn({
+                let (server, ranges, failures) = (server.clone(), ranges.clone(), failures.clone());
+                move || {
+                    for request in server.incoming_requests() {
+                        if failures.load(Ordering::SeqCst) > 0 {
+                            failures.fetch_sub(1, Ordering::SeqCst);
+                            request.respond(Response::empty(503)).unwrap();
+                            continue;
+                        }
+
+                        let range = request
+                            .headers()
+                            .iter()
+                            .find(|header| header.field.equiv("Range"))
+                            .unwrap()
+                            .value
+                            .to_string();
+                        ranges.lock().unwrap().push(range.clone());
+                        let (start, end) = range["bytes=".len()..].split_once('-').unwrap();
+                        let start: usize = start.parse().unwrap();
+                        let end = (end.parse::<usize>().unwrap() + 1).min(data.len());
+
+                        let response = if start >= data.len() {
+                            let content_range = format!("bytes */{}", data.len());
+                            Response::from_data(Vec::new())
+                                .with_status_code(416)
+                                .with_header(
+                                    Header::from_bytes("Content-Range", content_range).unwrap(),
+                                )
+                        } else {
+                            let content_range =
+                                format!("bytes {}-{}/{}", start, end - 1, data.len());
+                            Response::from_data(data[start..end].to_vec())
+                                .with_status_code(206)
+                                .with_header(
+                                    Header::from_bytes("Content-Range", content_range).unwrap(),
+                                )
+                        };
+                        request.respond(response).unwrap();
+                    }
+                }
+            });
+
+            Self {
+                server,
+                ranges,
+                failures,
+            }
+        }
+
+        fn url(&self) -> String {
+            format!("http://{}/file", self.server.server_addr().to_ip().unwrap())
+        }
+    }
+
+    impl Drop for TestServer {
+        fn drop(&mut self) {
+            self.server.unblock();
+        }
+    }
+
+    fn options(max_retries: u32) -> FetchOptions {
+        FetchOptions {
+            blocks_per_fetch: 1,
+            retry: RetryPolicy {
+                max_retries,
+                initial_backoff: Duration::from_millis(1),
+                max_backoff: Duration::from_millis(10),
+            },
+        }
+    }
+
+    #[test]
+    fn http_range_reader_should_feed_encrypted_readers() {
+        let cipher = Cipher::new("test", Some("salt")).unwrap();
+        let data: Vec<u8> = (0..200_000).map(|i| i as u8).collect();
+        let encrypted = encrypt(&cipher, &data);
+        let server = TestServer::start(encrypted.clone());
+
+        let source = HttpRangeReader::open(&server.url(), options(0)).unwrap();
+        assert_eq!(source.len(), encrypted.len() as u64);
+        let mut reader = EncryptedReader::new_with_cipher(source, cipher).unwrap();
+        reader.seek(SeekFrom::Start(150_000)).unwrap();
+        let mut tail = Vec::new();
+        reader.read_to_end(&mut tail).unwrap();
+        assert_eq!(tail, data[150_000..]);
+
+        // Fetches are aligned to blocks, and the blocks before the seek were never fetched
+        assert_eq!(
+            *server.ranges.lock().unwrap(),
+            [
+                "bytes=0-65583",
+                "bytes=131136-196687",
+                "bytes=196688-262239"
+            ]
+        );
+    }
+
+    #[test]
+    fn http_range_reader_should_retry() {
+        let server = TestServer::start(b"hello world".to_vec());
+
+        server.failures.store(2, Ordering::SeqCst);
+        let mut reader = HttpRangeReader::open(&server.url(), options(2)).unwrap();
+        let mut data = String::new();
+        reader.read_to_string(&mut data).unwrap();
+        assert_eq!(data, "hello world");
+        reader.seek(SeekFrom::End(-5)).unwrap();
+        data.clear();
+        reader.read_to_string(&mut data).unwrap();
+        assert_eq!(data, "world");
+
+        server.failures.store(2, Ordering::SeqCst);
+        let e = HttpRangeReader::open(&server.url(), options(1))
+            .err()
+            .unwrap();
+        assert!(e.to_string().contains("503"), "{}", e);
+
+        let empty = TestServer::start(Vec::new());
+        let mut reader = HttpRangeReader::open(&empty.url(), options(0)).unwrap();
+        assert!(reader.is_empty());
+        assert_eq!(reader.read(&mut [0; 10]).unwrap(), 0);
+        assert_eq!(
+            reader.seek(SeekFrom::Current(-1)).unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+    }
+}
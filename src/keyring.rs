@@ -0,0 +1,258 @@
+/// Decrypting names and data with whichever of several keys they were encrypted with.
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::cipher::Cipher;
+use crate::decrypter::Decrypter;
+use crate::stream::{read_full, EncryptedReader};
+use crate::{BLOCK_SIZE, FILE_HEADER_SIZE};
+
+/// A set of labeled [`Cipher`](Cipher)s, e.g. the old and new keys during a password rotation.
+///
+/// Keys are tried in the order they were added, except that the key which last matched in
+/// a directory is tried first for everything else in that directory.
+///
+/// # Notes
+/// Names are only protected by their padding, so a wrong key can occasionally appear to decrypt
+/// a name. Data is authenticated, so a matching key for data is always the right one.
+#[derive(Debug, Default)]
+pub struct KeyRing {
+    keys: Vec<(String, Cipher)>,
+    /// Index of the key which last matched in each (encrypted) directory.
+    matches: Mutex<HashMap<PathBuf, usize>>,
+}
+
+/// The key of a [`KeyRing`](KeyRing) which decrypted something.
+#[derive(Clone, Copy, Debug)]
+pub struct KeyMatch<'a> {
+    /// Position of the key in the ring.
+    pub index: usize,
+    /// Label the key was added with.
+    pub label: &'a str,
+    /// The key itself.
+    pub cipher: &'a Cipher,
+}
+
+impl KeyRing {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a key with the given label, to be tried after all keys added before.
+    pub fn add(&mut self, label: impl Into<String>, cipher: Cipher) {
+        self.keys.push((label.into(), cipher));
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Returns the key with the given label.
+    pub fn get(&self, label: &str) -> Option<&Cipher> {
+        self.keys
+            .iter()
+            .find(|(key_label, _)| key_label == label)
+            .map(|(_, cipher)| cipher)
+    }
+
+    /// Returns the key which last matched in the encrypted directory `dir`.
+    pub fn cached(&self, dir: &Path) -> Option<KeyMatch<'_>> {
+        let index = *self.matches.lock().unwrap().get(dir)?;
+        Some(self.key_match(index))
+    }
+
+    /// Forgets which keys matched in which directories.
+    pub fn clear_cache(&self) {
+        self.matches.lock().unwrap().clear();
+    }
+
+    /// Decrypts a single name of an entry in the encrypted directory `dir`.
+    pub fn decrypt_segment(&self, dir: &Path, segment: &str) -> Result<(String, KeyMatch<'_>)> {
+        self.try_keys(dir, |cipher| cipher.decrypt_segment(segment))
+    }
+
+    /// Decrypts every segment of `path`, which must all be encrypted with the same key.
+    pub fn decrypt_path(&self, path: &Path) -> Result<(PathBuf, KeyMatch<'_>)> {
+        let dir = match path.components().next_back() {
+            Some(Component::Normal(_)) => path.parent().unwrap_or(Path::new("")),
+            _ => path,
+        };
+        self.try_keys(dir, |cipher| cipher.decrypt_path(path))
+    }
+
+    /// Opens an [`EncryptedReader`](EncryptedReader) for a file in the encrypted directory `dir`
+    /// with the first key its first block authenticates with.
+    ///
+    /// The file has to start at the start of `inner`, as the reader seeks to absolute offsets.
+    /// Its header and first block are read to find the key, then `inner` is rewound. A file
+    /// without any blocks matches any key whose cipher encrypts data.
+    pub fn open_reader<R: Read + Seek>(
+        &self,
+        dir: &Path,
+        mut inner: R,
+    ) -> Result<(EncryptedReader<R>, KeyMatch<'_>)> {
+        inner.seek(SeekFrom::Start(0))?;
+        let mut prefix = vec![0u8; FILE_HEADER_SIZE + BLOCK_SIZE];
+        let read = read_full(&mut inner, &mut prefix)?;
+        if read < FILE_HEADER_SIZE {
+            return Err(anyhow!("File is too short to be encrypted"));
+        }
+        let (header, block) = prefix[..read].split_at(FILE_HEADER_SIZE);
+
+        let ((), key) = self.try_keys(dir, |cipher| {
+            cipher.require_data_encryption()?;
+            let decrypter = Decrypter::new(&cipher.get_file_key(), header)?;
+            if !block.is_empty() {
+                decrypter.decrypt_block(0, block)?;
+            }
+            Ok(())
+        })?;
+
+        inner.seek(SeekFrom::Start(0))?;
+        let reader = EncryptedReader::new_with_cipher(inner, key.cipher.clone())?;
+        Ok((reader, key))
+    }
+
+    /// Returns the result of `f` for the first key it succeeds with, starting with the cached
+    /// key of `dir`, and caches that key.
+    fn try_keys<T>(
+        &self,
+        dir: &Path,
+        mut f: impl FnMut(&Cipher) -> Result<T>,
+    ) -> Result<(T, KeyMatch<'_>)> {
+        let cached = self.matches.lock().unwrap().get(dir).copied();
+        let order = cached
+            .into_iter()
+            .chain((0..self.keys.len()).filter(|&index| Some(index) != cached));
+
+        for index in order {
+            if let Ok(value) = f(&self.keys[index].1) {
+                if cached != Some(index) {
+                    self.matches
+                        .lock()
+                        .unwrap()
+                        .insert(dir.to_path_buf(), index);
+                }
+                return Ok((value, self.key_match(index)));
+            }
+        }
+
+        Err(anyhow!("None of the {} keys matched", self.keys.len()))
+    }
+
+    fn key_match(&self, index: usize) -> KeyMatch<'_> {
+        let (label, cipher) = &self.keys[index];
+        KeyMatch {
+            index,
+            label,
+            cipher,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::KeyRing;
+    use crate::cipher::Cipher;
+    use crate::tests::encrypt;
+    use std::io::{Cursor, Read, Seek, SeekFrom};
+    use std::path::Path;
+
+    fn key_ring() -> KeyRing {
+        let mut keys = KeyRing::new();
+        keys.add("old", Cipher::new("old", Some("salt")).unwrap());
+        keys.add("new", Cipher::new("new", None).unwrap());
+        keys
+    }
+
+    #[test]
+    fn key_ring_should_decrypt_names_with_any_key() {
+        let keys = key_ring();
+        let new = keys.get("new").unwrap();
+        let dir = new.encrypt_path(Path::new("dir")).unwrap();
+        let path = new.encrypt_path(Path::new("dir/a.txt")).unwrap();
+
+        let (decrypted, key) = keys.decrypt_path(&path).unwrap();
+        assert_eq!(decrypted, Path::new("dir/a.txt"));
+        assert_eq!(key.label, "new");
+        assert_eq!(keys.cached(&dir).unwrap().index, 1);
+
+        let name = new.encrypt_file_name("b.txt").unwrap();
+        let (decrypted, key) = keys.decrypt_segment(&dir, &name).unwrap();
+        assert_eq!(decrypted, "b.txt");
+        assert_eq!(key.label, "new");
+
+        let old = keys.get("old").unwrap();
+        let name = old.encrypt_file_name("c.txt").unwrap();
+        let (decrypted, key) = keys.decrypt_segment(&dir, &name).unwrap();
+        assert_eq!(decrypted, "c.txt");
+        assert_eq!(key.label, "old");
+        assert_eq!(keys.cached(&dir).unwrap().label, "old");
+
+        let other = Cipher::new("other", None).unwrap();
+        let name = other.encrypt_file_name("d.txt").unwrap();
+        assert!(keys.decrypt_segment(&dir, &name).is_err());
+
+        keys.clear_cache();
+        assert!(keys.cached(&dir).is_none());
+    }
+
+    #[test]
+    fn key_ring_should_open_readers_with_any_key() {
+        let keys = key_ring();
+
+        for (label, data) in [("old", vec![1u8; 100_000]), ("new", Vec::new())] {
+            let encrypted = encrypt(keys.get(label).unwrap(), &data);
+            let mut inner = Cursor::new(encrypted);
+            inner.seek(SeekFrom::End(0)).unwrap();
+            let (mut reader, key) = keys.open_reader(Path::new(""), inner).unwrap();
+            // An empty file matches the cached key
+            assert_eq!(key.label, "old");
+
+            let mut decrypted = Vec::new();
+            reader.read_to_end(&mut decrypted).unwrap();
+            assert_eq!(decrypted, data);
+
+            let position = data.len().min(70_000);
+            reader.seek(SeekFrom::Start(position as u64)).unwrap();
+            decrypted.clear();
+            reader.read_to_end(&mut decrypted).unwrap();
+            assert_eq!(decrypted, data[position..]);
+        }
+
+        let other = Cipher::new("other", None).unwrap();
+        let encrypted = encrypt(&other, b"data");
+        assert!(keys
+            .open_reader(Path::new(""), Cursor::new(encrypted))
+            .is_err());
+    }
+
+    #[test]
+    fn key_ring_should_skip_keys_without_data_encryption() {
+        let mut keys = KeyRing::new();
+        keys.add(
+            "names",
+            Cipher::new("new", None)
+                .unwrap()
+                .with_data_encryption(false),
+        );
+        keys.add("new", Cipher::new("new", None).unwrap());
+
+        let encrypted = encrypt(keys.get("new").unwrap(), b"data");
+        let (mut reader, key) = keys
+            .open_reader(Path::new(""), Cursor::new(encrypted))
+            .unwrap();
+        assert_eq!(key.label, "new");
+
+        let mut decrypted = Vec::new();
+        reader.read_to_end(&mut decrypted).unwrap();
+        assert_eq!(decrypted, b"data");
+    }
+}
@@ -1,33 +1,145 @@
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
 #![allow(clippy::unused_io_amount)]
-use sodiumoxide::crypto::secretbox;
 
+extern crate alloc;
+
+use core::ops::Range;
+
+mod backend;
 /// Provides a [`Cipher`](cipher::Cipher) for encrypting
 /// and decrypting file names and paths
 pub mod cipher;
+/// Reading crypt remotes from rclone config files
+#[cfg(feature = "std")]
+pub mod config;
+/// Provides a [`CryptObjectStore`](crypt_store::CryptObjectStore) wrapping an `object_store::ObjectStore`
+#[cfg(feature = "object_store")]
+pub mod crypt_store;
+/// Checking plaintext files against their encrypted copies, like `rclone cryptcheck`
+#[cfg(feature = "cryptcheck")]
+pub mod cryptcheck;
+/// Provides a [`CryptFs`](cryptfs::CryptFs) for accessing an encrypted directory by plaintext paths
+#[cfg(feature = "std")]
+pub mod cryptfs;
 /// Provides a [`Decrypter`](decrypter::Decrypter) for decrypting data
 pub mod decrypter;
-mod eme;
+/// Provides the [`Eme`](eme::Eme) wide-block cipher mode used for file name encryption
+pub mod eme;
 /// Provides a [`Encrypter`](encrypter::Encrypter) for encrypting data
 pub mod encrypter;
+/// Provides a [`HttpRangeReader`](http_reader::HttpRangeReader) reading remote files with HTTP range requests
+#[cfg(feature = "http-reader")]
+pub mod http_reader;
+/// Provides a [`KeyRing`](keyring::KeyRing) for decrypting with several keys
+#[cfg(feature = "std")]
+pub mod keyring;
 /// Obscuring secrects
 pub mod obscure;
+/// Decrypting the complete blocks of incomplete downloads
+#[cfg(feature = "std")]
+pub mod partial;
+/// Checking a password and salt against existing encrypted names and files
+#[cfg(feature = "std")]
+pub mod password;
+/// Provides a [`RangeCache`](range_cache::RangeCache) keeping fetched ciphertext ranges on disk
+#[cfg(feature = "std")]
+pub mod range_cache;
+/// Re-encrypting files, names and trees from one password to another
+#[cfg(feature = "std")]
+pub mod rekey;
+/// Restoring an encrypted directory tree to plaintext
+#[cfg(feature = "std")]
+pub mod restore;
+/// Provides [`HttpServer`](server::HttpServer) and [`WebDavServer`](server::WebDavServer) serving an encrypted directory by plaintext paths
+#[cfg(feature = "server")]
+pub mod server;
+/// Provides the [`Storage`](storage::Storage) backend trait, with local, in-memory and crypt implementations
+#[cfg(feature = "std")]
+pub mod storage;
 /// Provides streaming interfaces
+#[cfg(feature = "std")]
 pub mod stream;
+/// One-way sync of a plaintext directory tree into an encrypted tree
+#[cfg(feature = "std")]
+pub mod sync;
+/// Verifying that encrypted files and trees authenticate
+#[cfg(feature = "std")]
+pub mod verify;
 
 /// Rclone file header magic
 pub const FILE_MAGIC: &[u8] = b"RCLONE\x00\x00";
 /// Nonce size
 pub const FILE_NONCE_SIZE: usize = 24;
+/// Nonce of a file, stored in its header
+pub type FileNonce = [u8; FILE_NONCE_SIZE];
 /// Header size
 pub const FILE_HEADER_SIZE: usize = FILE_MAGIC.len() + FILE_NONCE_SIZE;
 
 // Each block has an authenticated header
-pub const BLOCK_HEADER_SIZE: usize = secretbox::MACBYTES;
+pub const BLOCK_HEADER_SIZE: usize = backend::MAC_SIZE;
 pub const BLOCK_DATA_SIZE: usize = 64 * 1024;
 pub const BLOCK_SIZE: usize = BLOCK_HEADER_SIZE + BLOCK_DATA_SIZE;
 
-fn calculate_nonce(initial_nonce: secretbox::Nonce, block_id: u64) -> secretbox::Nonce {
-    let mut nonce = secretbox::Nonce(initial_nonce.0);
+/// Returns the size of a file with `size` bytes of plaintext once it's encrypted.
+pub fn encrypted_size(size: u64) -> u64 {
+    let blocks = size.div_ceil(BLOCK_DATA_SIZE as u64);
+    FILE_HEADER_SIZE as u64 + blocks * BLOCK_HEADER_SIZE as u64 + size
+}
+
+/// Returns the size of the plaintext of an encrypted file of `size` bytes,
+/// or `None` if an encrypted file can't be this size.
+pub fn decrypted_size(size: u64) -> Option<u64> {
+    let data = size.checked_sub(FILE_HEADER_SIZE as u64)?;
+    let blocks = data / BLOCK_SIZE as u64;
+    let residue = data % BLOCK_SIZE as u64;
+
+    if residue == 0 {
+        return Some(blocks * BLOCK_DATA_SIZE as u64);
+    }
+    // A block always has some data after its MAC
+    if residue <= BLOCK_HEADER_SIZE as u64 {
+        return None;
+    }
+    Some(blocks * BLOCK_DATA_SIZE as u64 + residue - BLOCK_HEADER_SIZE as u64)
+}
+
+/// Maps a range of plaintext bytes to the blocks which hold them. Returns the id of the
+/// first block and the range of encrypted bytes covering those blocks.
+///
+/// The end of the encrypted range may be past the end of the file, as it assumes full blocks,
+/// and saturates at `u64::MAX` for open-ended ranges.
+pub fn encrypted_range(range: Range<u64>) -> (u64, Range<u64>) {
+    let first_block = range.start / BLOCK_DATA_SIZE as u64;
+    let end_block = if range.is_empty() {
+        first_block
+    } else {
+        range.end.div_ceil(BLOCK_DATA_SIZE as u64)
+    };
+    let offset = |block: u64| {
+        block
+            .saturating_mul(BLOCK_SIZE as u64)
+            .saturating_add(FILE_HEADER_SIZE as u64)
+    };
+
+    (first_block, offset(first_block)..offset(end_block))
+}
+
+/// Returns the range of encrypted bytes fetched along with `position` when fetching `blocks`
+/// blocks at a time: the header and the first `blocks` blocks, or later groups of `blocks` blocks.
+///
+/// Every block is within a single window, so a block never needs two fetches.
+#[cfg(feature = "std")]
+pub(crate) fn fetch_window(position: u64, blocks: u64) -> Range<u64> {
+    let header = FILE_HEADER_SIZE as u64;
+    let size = blocks.max(1).saturating_mul(BLOCK_SIZE as u64);
+
+    let index = position.saturating_sub(header) / size;
+    let start = if index == 0 { 0 } else { header + index * size };
+    start..header.saturating_add((index + 1).saturating_mul(size))
+}
+
+fn calculate_nonce(initial_nonce: backend::Nonce, block_id: u64) -> backend::Nonce {
+    let mut nonce = initial_nonce;
 
     if block_id == 0 {
         return nonce;
@@ -36,21 +148,20 @@ fn calculate_nonce(initial_nonce: secretbox::Nonce, block_id: u64) -> secretbox:
     let mut x = block_id;
     let mut carry: u16 = 0;
 
-    for i in 0..8 {
-        let digit = nonce.0[i];
+    for digit in nonce.iter_mut().take(8) {
         let x_digit = x as u8;
         x >>= 8;
-        carry += digit as u16 + x_digit as u16;
-        nonce.0[i] = carry as u8;
+        carry += *digit as u16 + x_digit as u16;
+        *digit = carry as u8;
         carry >>= 8;
     }
 
     if carry != 0 {
-        for i in carry as usize..FILE_NONCE_SIZE {
-            let digit = nonce.0[i];
-            let new_digit = digit + 1;
-            nonce.0[i] = new_digit;
-            if new_digit >= digit {
+        // Propagate the carry into the upper 16 bytes, like rclone's `nonce.carry(8)`
+        for digit in nonce.iter_mut().skip(8) {
+            let new_digit = digit.wrapping_add(1);
+            *digit = new_digit;
+            if new_digit != 0 {
                 // no carry
                 break;
             }
@@ -60,5 +171,7 @@ fn calculate_nonce(initial_nonce: secretbox::Nonce, block_id: u64) -> secretbox:
     nonce
 }
 
+#[cfg(test)]
+mod dudect;
 #[cfg(test)]
 mod tests;
@@ -1,14 +1,15 @@
-use aes::{
-    cipher::{NewCipher, StreamCipher},
-    Aes256Ctr,
-};
+use aes::Aes256;
 /// Rclone's "obscure" implementation
 /// AKA base64(aes-ctr(val, static_key)) :-(
 use anyhow::{anyhow, Result};
 use base64::engine::general_purpose::URL_SAFE_NO_PAD;
 use base64::Engine;
 use cipher::generic_array::GenericArray;
-use sodiumoxide::randombytes::randombytes;
+use cipher::{KeyIvInit, StreamCipher};
+
+use crate::backend;
+use alloc::string::String;
+use alloc::vec::Vec;
 
 // the static key used for obscuring
 const OBSCURE_CRYPT_KEY: [u8; 32] = [
@@ -16,6 +17,8 @@ const OBSCURE_CRYPT_KEY: [u8; 32] = [
     0xd3, 0x90, 0x19, 0x8e, 0xb8, 0x12, 0x8a, 0xfb, 0xf4, 0xde, 0x16, 0x2b, 0x8b, 0x95, 0xf6, 0x38,
 ];
 
+type Aes256Ctr = ctr::Ctr128BE<Aes256>;
+
 // block size for the cipher
 const OBSCURE_BLOCK_SIZE: usize = 16;
 
@@ -33,7 +36,7 @@ fn crypt(data: &[u8], iv: &[u8]) -> Result<Vec<u8>> {
 
 pub fn reveal(text: &str) -> Result<String> {
     // text is basically: base64(iv + ciphertext)
-    let ciphertext = URL_SAFE_NO_PAD.decode(text)?;
+    let ciphertext = URL_SAFE_NO_PAD.decode(text).map_err(|e| anyhow!("{}", e))?;
 
     if ciphertext.len() < OBSCURE_BLOCK_SIZE {
         return Err(anyhow!(
@@ -53,7 +56,9 @@ pub fn reveal(text: &str) -> Result<String> {
 
 pub fn obscure(plaintext: &str) -> Result<String> {
     let plaintext = plaintext.as_bytes();
-    let iv = randombytes(OBSCURE_BLOCK_SIZE);
+    backend::init()?;
+    let mut iv = [0u8; OBSCURE_BLOCK_SIZE];
+    backend::random_bytes(&mut iv)?;
     let ciphertext = crypt(plaintext, &iv)?;
 
     // inefficient... rclone uses in-place crypt... obscure is only used for configs
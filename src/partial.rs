@@ -0,0 +1,215 @@
+/// Decrypting the complete blocks of interrupted or partial downloads.
+use anyhow::{anyhow, Context, Result};
+use std::io::{Read, Write};
+
+use crate::cipher::Cipher;
+use crate::decrypter::Decrypter;
+use crate::stream::read_full;
+use crate::{decrypted_size, BLOCK_HEADER_SIZE, BLOCK_SIZE, FILE_HEADER_SIZE};
+
+/// How the ciphertext prefix ends.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Tail {
+    /// The prefix ends right after the header or a complete block.
+    BlockBoundary,
+    /// The prefix ends with a short block which authenticated, so it's the final block of the file.
+    FinalBlock { size: usize },
+    /// The prefix ends with the first `size` bytes of a block (or of the header) which was cut off.
+    Incomplete { size: usize },
+}
+
+/// The outcome of [`decrypt_partial()`](decrypt_partial).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PartialReport {
+    /// Number of plaintext bytes which were decrypted and authenticated.
+    pub available: u64,
+    /// How the prefix ends after the decrypted blocks.
+    pub tail: Tail,
+    /// Whether the prefix is the whole file, or `None` if that can't be told: a prefix ending on
+    /// a block boundary may or may not be followed by more blocks.
+    pub complete: Option<bool>,
+    /// Size of the whole plaintext, if the size of the whole encrypted file was given.
+    pub plaintext_size: Option<u64>,
+}
+
+/// Decrypts every complete block of the ciphertext prefix read from `input` into `output`.
+///
+/// `expected_size` is the size of the whole encrypted file, if known. With it, a trailing short
+/// block is only decrypted if the prefix is actually complete. Without it, a trailing short block
+/// which authenticates is taken as the final block of the file, and as cut off otherwise.
+///
+/// # Errors
+/// Fails on I/O errors, if the header is invalid, if a complete block fails authentication
+/// (which means the data is corrupted or the key is wrong, not just incomplete), or if the prefix
+/// doesn't fit `expected_size`. Also fails if `cipher` doesn't encrypt data.
+pub fn decrypt_partial<R: Read, W: Write>(
+    cipher: &Cipher,
+    mut input: R,
+    mut output: W,
+    expected_size: Option<u64>,
+) -> Result<PartialReport> {
+    cipher.require_data_encryption()?;
+    let plaintext_size = match expected_size {
+        Some(size) => Some(
+            decrypted_size(size)
+                .ok_or_else(|| anyhow!("{} bytes is not a valid encrypted file size", size))?,
+        ),
+        None => None,
+    };
+    let mut report = PartialReport {
+        available: 0,
+        tail: Tail::BlockBoundary,
+        complete: None,
+        plaintext_size,
+    };
+
+    let mut header = [0u8; FILE_HEADER_SIZE];
+    let read = read_full(&mut input, &mut header)?;
+    if read < FILE_HEADER_SIZE {
+        report.tail = Tail::Incomplete { size: read };
+        report.complete = Some(false);
+        return Ok(report);
+    }
+    let decrypter = Decrypter::new(&cipher.get_file_key(), &header)?;
+
+    let mut offset = FILE_HEADER_SIZE as u64;
+    let mut block = vec![0u8; BLOCK_SIZE];
+
+    for block_id in 0.. {
+        let size = read_full(&mut input, &mut block)?;
+        let end = offset + size as u64;
+        if expected_size.is_some_and(|expected| end > expected) {
+            return Err(anyhow!(
+                "Data is longer than the expected {} bytes",
+                expected_size.unwrap()
+            ));
+        }
+
+        if size == 0 {
+            report.complete = expected_size.map(|expected| offset == expected);
+            break;
+        }
+
+        if size < BLOCK_SIZE {
+            // A short block can only be the final block of the file
+            let is_final = match expected_size {
+                Some(expected) => end == expected,
+                None => size > BLOCK_HEADER_SIZE,
+            };
+            let decrypted = if is_final {
+                decrypter.decrypt_block(block_id, &block[..size]).ok()
+            } else {
+                None
+            };
+
+            match decrypted {
+                Some(decrypted) => {
+                    output
+                        .write_all(&decrypted)
+                        .context("Failed to write plaintext")?;
+                    report.available += decrypted.len() as u64;
+                    report.tail = Tail::FinalBlock { size };
+                    report.complete = Some(true);
+                }
+                None if expected_size.is_some() && is_final => {
+                    return Err(anyhow!("Final block {} failed authentication", block_id));
+                }
+                None => {
+                    report.tail = Tail::Incomplete { size };
+                    report.complete = Some(false);
+                }
+            }
+            break;
+        }
+
+        let decrypted = decrypter
+            .decrypt_block(block_id, &block)
+            .map_err(|_| anyhow!("Block {} failed authentication", block_id))?;
+        output
+            .write_all(&decrypted)
+            .context("Failed to write plaintext")?;
+        report.available += decrypted.len() as u64;
+        offset = end;
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decrypt_partial, PartialReport, Tail};
+    use crate::cipher::Cipher;
+    use crate::tests::encrypt;
+    use crate::{BLOCK_DATA_SIZE, BLOCK_SIZE, FILE_HEADER_SIZE};
+
+    fn partial(
+        cipher: &Cipher,
+        prefix: &[u8],
+        expected_size: Option<u64>,
+    ) -> (Vec<u8>, PartialReport) {
+        let mut plaintext = Vec::new();
+        let report = decrypt_partial(cipher, prefix, &mut plaintext, expected_size).unwrap();
+        assert_eq!(report.available, plaintext.len() as u64);
+        (plaintext, report)
+    }
+
+    #[test]
+    fn partial_should_decrypt_complete_blocks() {
+        let cipher = Cipher::new("test", Some("test")).unwrap();
+        let data: Vec<u8> = (0..BLOCK_DATA_SIZE * 2 + 1000).map(|i| i as u8).collect();
+        let encrypted = encrypt(&cipher, &data);
+        let size = Some(encrypted.len() as u64);
+
+        // Cut off in the middle of the second block
+        let cut = FILE_HEADER_SIZE + BLOCK_SIZE + 500;
+        for expected_size in [None, size].iter() {
+            let (plaintext, report) = partial(&cipher, &encrypted[..cut], *expected_size);
+            assert_eq!(plaintext, data[..BLOCK_DATA_SIZE]);
+            assert_eq!(report.tail, Tail::Incomplete { size: 500 });
+            assert_eq!(report.complete, Some(false));
+        }
+
+        // Cut off on a block boundary
+        let cut = FILE_HEADER_SIZE + 2 * BLOCK_SIZE;
+        let (plaintext, report) = partial(&cipher, &encrypted[..cut], None);
+        assert_eq!(plaintext, data[..2 * BLOCK_DATA_SIZE]);
+        assert_eq!(report.tail, Tail::BlockBoundary);
+        assert_eq!(report.complete, None);
+        let (_, report) = partial(&cipher, &encrypted[..cut], size);
+        assert_eq!(report.complete, Some(false));
+        assert_eq!(report.plaintext_size, Some(data.len() as u64));
+
+        // The whole file
+        for expected_size in [None, size].iter() {
+            let (plaintext, report) = partial(&cipher, &encrypted, *expected_size);
+            assert_eq!(plaintext, data);
+            assert_eq!(report.tail, Tail::FinalBlock { size: 1016 });
+            assert_eq!(report.complete, Some(true));
+        }
+
+        // Not even the whole header
+        let (plaintext, report) = partial(&cipher, &encrypted[..10], None);
+        assert!(plaintext.is_empty());
+        assert_eq!(report.tail, Tail::Incomplete { size: 10 });
+    }
+
+    #[test]
+    fn partial_should_reject_corrupted_blocks() {
+        let cipher = Cipher::new("test", Some("test")).unwrap();
+        let mut encrypted = encrypt(&cipher, &vec![1u8; BLOCK_DATA_SIZE + 10]);
+        let size = Some(encrypted.len() as u64);
+
+        // Larger than expected
+        assert!(decrypt_partial(&cipher, &encrypted[..], Vec::new(), Some(100)).is_err());
+
+        // A corrupted final block is only detected when the size is known
+        let last = encrypted.len() - 1;
+        encrypted[last] ^= 1;
+        assert!(decrypt_partial(&cipher, &encrypted[..], Vec::new(), size).is_err());
+        let (_, report) = partial(&cipher, &encrypted, None);
+        assert_eq!(report.tail, Tail::Incomplete { size: 26 });
+
+        encrypted[FILE_HEADER_SIZE] ^= 1;
+        assert!(decrypt_partial(&cipher, &encrypted[..], Vec::new(), None).is_err());
+    }
+}
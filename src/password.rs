@@ -0,0 +1,337 @@
+/// Checking a password and salt against existing encrypted names and files.
+use anyhow::{Context, Result};
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+
+use crate::cipher::{is_encrypted_segment, Cipher};
+use crate::decrypter::Decrypter;
+use crate::stream::read_full;
+use crate::{decrypted_size, BLOCK_SIZE, FILE_HEADER_SIZE, FILE_MAGIC};
+
+/// Encrypted names and file prefixes to check keys against.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Sample {
+    /// Single encrypted names, without any `/`.
+    pub names: Vec<String>,
+    /// The start of encrypted files: the header and (at least) the first block.
+    /// Longer data is fine, but only the first block is checked.
+    pub files: Vec<Vec<u8>>,
+}
+
+impl Sample {
+    /// Collects up to `limit` names and `limit` file prefixes from the encrypted tree at `root`,
+    /// breadth first.
+    ///
+    /// # Errors
+    /// Fails if a directory can't be listed or a file can't be read.
+    pub fn from_tree(root: &Path, limit: usize) -> Result<Self> {
+        let mut sample = Sample::default();
+        let mut dirs = VecDeque::from([root.to_path_buf()]);
+
+        while let Some(dir) = dirs.pop_front() {
+            let mut entries = fs::read_dir(&dir)
+                .with_context(|| format!("Failed to read {}", dir.display()))?
+                .collect::<io::Result<Vec<_>>>()?;
+            entries.sort_by_key(|entry| entry.file_name());
+
+            for entry in entries {
+                if sample.names.len() >= limit && sample.files.len() >= limit {
+                    return Ok(sample);
+                }
+                if sample.names.len() < limit {
+                    sample
+                        .names
+                        .push(entry.file_name().to_string_lossy().into_owned());
+                }
+
+                let path = entry.path();
+                if fs::metadata(&path)?.is_dir() {
+                    dirs.push_back(path);
+                } else if sample.files.len() < limit {
+                    sample.files.push(read_prefix(&path)?);
+                }
+            }
+        }
+
+        Ok(sample)
+    }
+}
+
+fn read_prefix(path: &Path) -> Result<Vec<u8>> {
+    let mut file =
+        File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut prefix = vec![0u8; FILE_HEADER_SIZE + BLOCK_SIZE];
+    let read = read_full(&mut file, &mut prefix)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    prefix.truncate(read);
+    Ok(prefix)
+}
+
+/// How many samples had the right form, and how many of them decrypted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct KeyStats {
+    pub names: usize,
+    /// Names which have the form of an encrypted name.
+    pub well_formed_names: usize,
+    pub decrypted_names: usize,
+    pub files: usize,
+    /// Files which start with the rclone crypt header and have a valid size.
+    pub well_formed_files: usize,
+    /// Well-formed files with at least one block, as files with only a header can't be checked.
+    pub testable_files: usize,
+    /// Files whose first block authenticated.
+    pub authenticated_files: usize,
+}
+
+impl KeyStats {
+    /// Returns whether the keys match the samples.
+    ///
+    /// Data is authenticated, so a single file is enough. Names are only protected by their
+    /// padding, which a wrong key passes now and then, so at least half of them must decrypt.
+    /// If there are testable files, names are ignored.
+    pub fn matches(&self) -> bool {
+        if self.testable_files > 0 {
+            self.authenticated_files > 0
+        } else {
+            self.decrypted_names > 0 && self.decrypted_names * 2 >= self.well_formed_names
+        }
+    }
+
+    fn well_formed(&self) -> usize {
+        self.well_formed_names + self.well_formed_files
+    }
+
+    fn testable(&self) -> usize {
+        self.well_formed_names + self.testable_files
+    }
+}
+
+/// Tries to decrypt every name and the first block of every file of `sample` with `cipher`.
+pub fn check_keys(cipher: &Cipher, sample: &Sample) -> KeyStats {
+    let mut stats = KeyStats {
+        names: sample.names.len(),
+        files: sample.files.len(),
+        ..KeyStats::default()
+    };
+
+    for name in &sample.names {
+        if is_encrypted_segment(name) {
+            stats.well_formed_names += 1;
+            if cipher.decrypt_segment(name).is_ok() {
+                stats.decrypted_names += 1;
+            }
+        }
+    }
+
+    let file_key = cipher.get_file_key();
+    for file in &sample.files {
+        // Prefixes may be cut off after the first block, so only a short first block is checked
+        let well_formed = file.len() >= FILE_HEADER_SIZE
+            && &file[..FILE_MAGIC.len()] == FILE_MAGIC
+            && (file.len() >= FILE_HEADER_SIZE + BLOCK_SIZE
+                || decrypted_size(file.len() as u64).is_some());
+        if !well_formed {
+            continue;
+        }
+        stats.well_formed_files += 1;
+
+        let block = &file[FILE_HEADER_SIZE..file.len().min(FILE_HEADER_SIZE + BLOCK_SIZE)];
+        if block.is_empty() {
+            continue;
+        }
+        stats.testable_files += 1;
+        if Decrypter::new(&file_key, file)
+            .and_then(|decrypter| decrypter.decrypt_block(0, block))
+            .is_ok()
+        {
+            stats.authenticated_files += 1;
+        }
+    }
+
+    stats
+}
+
+/// The outcome of [`check_password()`](check_password).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    /// The password and salt match the samples.
+    Correct,
+    /// The password matches with a different salt: `Some` salt without surrounding
+    /// whitespace, or `None` for rclone's default salt.
+    WrongSalt { matching_salt: Option<String> },
+    /// Neither the salt nor the alternatives to it match, so the password is most likely wrong.
+    /// A completely different salt can't be ruled out though, as the two are hashed together.
+    WrongPassword,
+    /// The password doesn't match with rclone's default salt. As no salt was given, the remote
+    /// may just as well use a custom one which the password would match.
+    WrongPasswordOrSalt,
+    /// None of the samples look like rclone crypt names or files.
+    NotRcloneCrypt,
+    /// There's nothing to check the keys against, e.g. only empty files.
+    Inconclusive,
+}
+
+/// The verdict and statistics of [`check_password()`](check_password).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PasswordCheck {
+    pub verdict: Verdict,
+    /// Statistics of the given password and salt.
+    pub stats: KeyStats,
+}
+
+/// Checks `password` and `salt` (`password2` in rclone.conf) against `sample`.
+///
+/// If they don't match, the password is tried with common mistakes in the salt: rclone's
+/// default salt instead of the given one, and the given one without surrounding whitespace.
+///
+/// # Errors
+/// Only fails if key derivation fails.
+pub fn check_password(
+    password: &str,
+    salt: Option<&str>,
+    sample: &Sample,
+) -> Result<PasswordCheck> {
+    let stats = check_keys(&Cipher::new(password, salt)?, sample);
+
+    let verdict = if stats.well_formed() == 0 {
+        if stats.names + stats.files == 0 {
+            Verdict::Inconclusive
+        } else {
+            Verdict::NotRcloneCrypt
+        }
+    } else if stats.matches() {
+        Verdict::Correct
+    } else if stats.testable() == 0 {
+        Verdict::Inconclusive
+    } else {
+        let mut alternatives = Vec::new();
+        if let Some(salt) = salt {
+            alternatives.push(None);
+            let trimmed = salt.trim();
+            if trimmed != salt && !trimmed.is_empty() {
+                alternatives.push(Some(trimmed));
+            }
+        }
+
+        let mut verdict = if salt.is_some() {
+            Verdict::WrongPassword
+        } else {
+            Verdict::WrongPasswordOrSalt
+        };
+        for alternative in alternatives {
+            if check_keys(&Cipher::new(password, alternative)?, sample).matches() {
+                verdict = Verdict::WrongSalt {
+                    matching_salt: alternative.map(str::to_string),
+                };
+                break;
+            }
+        }
+        verdict
+    };
+
+    Ok(PasswordCheck { verdict, stats })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_password, Sample, Verdict};
+    use crate::cipher::Cipher;
+    use crate::stream::EncryptedWriter;
+    use crate::tests::encrypt;
+    use std::fs;
+    use std::io::Write;
+    use std::path::Path;
+
+    fn encrypted_sample(cipher: &Cipher) -> Sample {
+        let file = encrypt(cipher, b"hello");
+
+        Sample {
+            names: vec![
+                cipher.encrypt_file_name("a.txt").unwrap(),
+                cipher.encrypt_file_name("b.txt").unwrap(),
+            ],
+            files: vec![file],
+        }
+    }
+
+    #[test]
+    fn check_password_should_give_verdicts() {
+        let cipher = Cipher::new("password", Some("salt")).unwrap();
+        let sample = encrypted_sample(&cipher);
+
+        let check = check_password("password", Some("salt"), &sample).unwrap();
+        assert_eq!(check.verdict, Verdict::Correct);
+        assert_eq!(check.stats.decrypted_names, 2);
+        assert_eq!(check.stats.authenticated_files, 1);
+
+        let check = check_password("wrong", Some("salt"), &sample).unwrap();
+        assert_eq!(check.verdict, Verdict::WrongPassword);
+        assert_eq!(check.stats.well_formed_files, 1);
+        assert_eq!(check.stats.authenticated_files, 0);
+
+        let check = check_password("password", Some(" salt\n"), &sample).unwrap();
+        assert_eq!(
+            check.verdict,
+            Verdict::WrongSalt {
+                matching_salt: Some("salt".to_string())
+            }
+        );
+
+        let check = check_password("password", None, &sample).unwrap();
+        assert_eq!(check.verdict, Verdict::WrongPasswordOrSalt);
+
+        let default = encrypted_sample(&Cipher::new("password", None).unwrap());
+        let check = check_password("password", Some("salt"), &default).unwrap();
+        assert_eq!(
+            check.verdict,
+            Verdict::WrongSalt {
+                matching_salt: None
+            }
+        );
+
+        let plain = Sample {
+            names: vec!["photo.jpg".to_string()],
+            files: vec![b"just some text".to_vec()],
+        };
+        let check = check_password("password", None, &plain).unwrap();
+        assert_eq!(check.verdict, Verdict::NotRcloneCrypt);
+
+        let check = check_password("password", None, &Sample::default()).unwrap();
+        assert_eq!(check.verdict, Verdict::Inconclusive);
+    }
+
+    #[test]
+    fn sample_should_be_collected_from_trees() {
+        let cipher = Cipher::new("password", None).unwrap();
+        let root = tempfile::tempdir().unwrap();
+        let path = |path: &str| {
+            root.path()
+                .join(cipher.encrypt_path(Path::new(path)).unwrap())
+        };
+
+        fs::create_dir(path("dir")).unwrap();
+        for name in ["dir/a.txt", "dir/b.txt", "c.txt"] {
+            let mut writer = EncryptedWriter::new_with_cipher(
+                fs::File::create(path(name)).unwrap(),
+                cipher.clone(),
+            )
+            .unwrap();
+            writer.write_all(&vec![0u8; 100_000]).unwrap();
+            writer.finish().unwrap();
+        }
+
+        let sample = Sample::from_tree(root.path(), 10).unwrap();
+        assert_eq!(sample.names.len(), 4);
+        assert_eq!(sample.files.len(), 3);
+        assert!(sample.files.iter().all(|file| file.len() == 32 + 65552));
+
+        let sample = Sample::from_tree(root.path(), 2).unwrap();
+        assert_eq!(sample.names.len(), 2);
+        assert_eq!(sample.files.len(), 2);
+
+        let check = check_password("password", None, &sample).unwrap();
+        assert_eq!(check.verdict, Verdict::Correct);
+    }
+}
@@ -0,0 +1,625 @@
+/// A persistent on-disk cache of ciphertext ranges, in sparse files like rclone's VFS cache.
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, SystemTime};
+
+use crate::fetch_window;
+
+const MAP_MAGIC: &str = "rclone-crypt range cache 1";
+
+/// Limits of a [`RangeCache`](RangeCache).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CacheOptions {
+    /// Total size of the cached ranges of all objects.
+    pub max_size: u64,
+    /// Objects which haven't been opened for longer than this are evicted.
+    pub max_age: Duration,
+    /// Encrypted blocks fetched from the source at once, see [`HttpRangeReader`](crate::http_reader::HttpRangeReader).
+    pub blocks_per_fetch: u64,
+}
+
+impl Default for CacheOptions {
+    fn default() -> Self {
+        Self {
+            max_size: 1 << 30,
+            max_age: Duration::from_secs(60 * 60),
+            blocks_per_fetch: 16,
+        }
+    }
+}
+
+/// What [`RangeCache::evict()`](RangeCache::evict) removed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct EvictReport {
+    pub objects: usize,
+    /// Size of the ranges evicted.
+    pub bytes: u64,
+}
+
+/// Caches the ranges of ciphertext read from seekable sources in a local directory, so reading
+/// them again, even after a restart, doesn't fetch them again.
+///
+/// Every object is stored in a sparse file of its full size and a map of the ranges which are
+/// present. Ranges are fetched aligned to encrypted blocks, so an
+/// [`EncryptedReader`](crate::stream::EncryptedReader) on top only fetches the blocks it misses.
+///
+/// Objects are evicted as a whole, oldest first, when they exceed the age or together the size
+/// of the [`CacheOptions`](CacheOptions). Eviction runs when an object is opened. Objects with an
+/// open [`CachedReader`](CachedReader) of this cache or a clone of it are never evicted.
+///
+/// # Notes
+/// Keys identify the content: include a version, ETag or modification time in them if objects
+/// can change. A cached object whose size differs from its source is discarded.
+#[derive(Clone, Debug)]
+pub struct RangeCache {
+    dir: PathBuf,
+    options: CacheOptions,
+    /// The number of open readers by object name.
+    open: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+impl RangeCache {
+    /// Opens the cache in `dir`, creating it if needed.
+    pub fn open(dir: impl Into<PathBuf>, options: CacheOptions) -> Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir,
+            options,
+            open: Arc::default(),
+        })
+    }
+
+    /// Returns a reader of `source` which is served from the cache where possible, and adds
+    /// what it fetches from `source` to the cache.
+    pub fn reader<R: Read + Seek>(&self, key: &str, mut source: R) -> Result<CachedReader<R>> {
+        if key.contains(['\n', '\r']) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Cache keys can't contain line breaks",
+            ));
+        }
+        let len = source.seek(SeekFrom::End(0))?;
+
+        let name = format!("{:016x}", fnv1a(key.as_bytes()));
+        let data_path = self.dir.join(format!("{}.data", name));
+        let map_path = self.dir.join(format!("{}.map", name));
+
+        // Held until the object is registered as open, so no other reader can start in between
+        let mut objects = self.open.lock().unwrap_or_else(PoisonError::into_inner);
+        let shared = objects.contains_key(&name);
+
+        let mut ranges = match read_map(&map_path) {
+            Ok(map) if map.key == key && map.len == len => map.ranges,
+            // Missing, changed, colliding or damaged maps start from scratch
+            _ => Vec::new(),
+        };
+        // The map may outlive its data, e.g. if a reader of another process kept writing it
+        // after the object was evicted, so it only counts for data of the expected size
+        let data = match OpenOptions::new().read(true).write(true).open(&data_path) {
+            Ok(data) if data.metadata()?.len() == len => Some(data),
+            // Resizing it would corrupt the data of the other readers
+            Ok(_) if shared => {
+                return Err(Error::other("Cache object is open with a different size"))
+            }
+            Ok(_) => None,
+            Err(e) if e.kind() == ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        let data = match data {
+            // Other readers may have fetched data which isn't in the map yet
+            Some(data) if shared || !ranges.is_empty() => data,
+            _ => {
+                ranges.clear();
+                let data = OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(&data_path)?;
+                data.set_len(len)?;
+                data
+            }
+        };
+        let open = OpenObject::new(&mut objects, &self.open, &name);
+        drop(objects);
+
+        let reader = CachedReader {
+            source,
+            data,
+            map_path,
+            key: key.to_string(),
+            len,
+            ranges,
+            position: 0,
+            blocks_per_fetch: self.options.blocks_per_fetch,
+            _open: open,
+        };
+        // Also marks the object as used
+        reader.write_map()?;
+
+        self.evict()?;
+        Ok(reader)
+    }
+
+    /// Evicts objects which are too old, then the oldest objects until the cache fits its size.
+    /// Objects which are open are kept.
+    pub fn evict(&self) -> Result<EvictReport> {
+        let mut objects = self.objects()?;
+        objects.sort_by_key(|object| object.used);
+
+        let open = self.open.lock().unwrap_or_else(PoisonError::into_inner);
+        let now = SystemTime::now();
+        let mut total: u64 = objects.iter().map(|object| object.size).sum();
+        let mut report = EvictReport::default();
+        for object in &objects {
+            if open.contains_key(&object.name) {
+                continue;
+            }
+            let age = now.duration_since(object.used).unwrap_or_default();
+            if age <= self.options.max_age && total <= self.options.max_size {
+                continue;
+            }
+
+            for extension in ["map", "data"] {
+                match fs::remove_file(self.dir.join(format!("{}.{}", object.name, extension))) {
+                    Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+            total -= object.size;
+            report.objects += 1;
+            report.bytes += object.size;
+        }
+
+        Ok(report)
+    }
+
+    /// Returns the size of the cached ranges of all objects.
+    pub fn size(&self) -> Result<u64> {
+        Ok(self.objects()?.iter().map(|object| object.size).sum())
+    }
+
+    fn objects(&self) -> Result<Vec<CachedObject>> {
+        let mut objects = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|extension| extension != "map") {
+                continue;
+            }
+            let name = match path.file_stem() {
+                Some(name) => name.to_string_lossy().into_owned(),
+                None => continue,
+            };
+
+            // Damaged maps are evicted first, as if they were empty and never used
+            let (size, used) = match (read_map(&path), fs::metadata(&path)?.modified()) {
+                (Ok(map), Ok(used)) => (map.ranges.iter().map(|r| r.end - r.start).sum(), used),
+                _ => (0, SystemTime::UNIX_EPOCH),
+            };
+            objects.push(CachedObject { name, size, used });
+        }
+        Ok(objects)
+    }
+}
+
+/// Registers an object as open in a [`RangeCache`](RangeCache) until dropped.
+#[derive(Debug)]
+struct OpenObject {
+    open: Arc<Mutex<HashMap<String, usize>>>,
+    name: String,
+}
+
+impl OpenObject {
+    /// Registers `name` in `objects`, the locked map of `open`.
+    fn new(
+        objects: &mut HashMap<String, usize>,
+        open: &Arc<Mutex<HashMap<String, usize>>>,
+        name: &str,
+    ) -> Self {
+        *objects.entry(name.to_string()).or_default() += 1;
+        Self {
+            open: Arc::clone(open),
+            name: name.to_string(),
+        }
+    }
+}
+
+impl Drop for OpenObject {
+    fn drop(&mut self) {
+        let mut open = self.open.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(count) = open.get_mut(&self.name) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.name);
+            }
+        }
+    }
+}
+
+struct CachedObject {
+    name: String,
+    size: u64,
+    used: SystemTime,
+}
+
+struct RangeMap {
+    key: String,
+    len: u64,
+    ranges: Vec<Range<u64>>,
+}
+
+/// Reads a map: the magic line, the key, the object size and a `start end` line per range.
+fn read_map(path: &Path) -> Result<RangeMap> {
+    let invalid = || Error::new(ErrorKind::InvalidData, "Invalid range map");
+    let mut lines = BufReader::new(File::open(path)?).lines();
+    let mut line = || lines.next().ok_or_else(invalid)?;
+
+    if line()? != MAP_MAGIC {
+        return Err(invalid());
+    }
+    let key = line()?
+        .strip_prefix("key ")
+        .ok_or_else(invalid)?
+        .to_string();
+    let len = line()?
+        .strip_prefix("len ")
+        .and_then(|len| len.parse().ok())
+        .ok_or_else(invalid)?;
+
+    let mut ranges = Vec::new();
+    for line in lines {
+        let line = line?;
+        let (start, end) = line.split_once(' ').ok_or_else(invalid)?;
+        let range = start.parse().map_err(|_| invalid())?..end.parse().map_err(|_| invalid())?;
+        if range.is_empty() || range.end > len {
+            return Err(invalid());
+        }
+        ranges.push(range);
+    }
+    merge_ranges(&mut ranges);
+
+    Ok(RangeMap { key, len, ranges })
+}
+
+/// Sorts `ranges` and merges the ones which overlap or touch.
+fn merge_ranges(ranges: &mut Vec<Range<u64>>) {
+    ranges.sort_by_key(|range| range.start);
+    let mut merged: Vec<Range<u64>> = Vec::with_capacity(ranges.len());
+    for range in ranges.drain(..) {
+        match merged.last_mut() {
+            Some(last) if last.end >= range.start => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    *ranges = merged;
+}
+
+/// 64-bit FNV-1a, a stable hash for file names. Collisions are caught by the key in the map.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// A [`Read`](Read) + [`Seek`](Seek) view of a source, served from a [`RangeCache`](RangeCache)
+/// where possible.
+pub struct CachedReader<R: Read + Seek> {
+    source: R,
+    data: File,
+    map_path: PathBuf,
+    key: String,
+    len: u64,
+    /// Sorted, non-overlapping and non-adjacent ranges which are cached.
+    ranges: Vec<Range<u64>>,
+    position: u64,
+    blocks_per_fetch: u64,
+    _open: OpenObject,
+}
+
+impl<R: Read + Seek> CachedReader<R> {
+    /// Size of the source.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the cached ranges of the object.
+    pub fn cached_ranges(&self) -> &[Range<u64>] {
+        &self.ranges
+    }
+
+    pub fn into_inner(self) -> R {
+        self.source
+    }
+
+    /// Fetches the missing part of the window holding `position` from the source.
+    fn fetch(&mut self, position: u64) -> Result<()> {
+        let window = fetch_window(position, self.blocks_per_fetch);
+        let start = self
+            .ranges
+            .iter()
+            .map(|range| range.end)
+            .filter(|&end| end <= position)
+            .fold(window.start, u64::max);
+        let end = self
+            .ranges
+            .iter()
+            .map(|range| range.start)
+            .filter(|&start| start > position)
+            .fold(window.end.min(self.len), u64::min);
+
+        let mut buffer = vec![0u8; (end - start) as usize];
+        self.source.seek(SeekFrom::Start(start))?;
+        self.source.read_exact(&mut buffer)?;
+        self.data.seek(SeekFrom::Start(start))?;
+        self.data.write_all(&buffer)?;
+        // The map must never claim data which isn't on disk
+        self.data.sync_data()?;
+
+        self.ranges.push(start..end);
+        merge_ranges(&mut self.ranges);
+
+        self.write_map()
+    }
+
+    /// Writes the map to a temporary file first, so it's never seen half-written.
+    fn write_map(&self) -> Result<()> {
+        let mut map = format!("{}\nkey {}\nlen {}\n", MAP_MAGIC, self.key, self.len);
+        for range in &self.ranges {
+            map.push_str(&format!("{} {}\n", range.start, range.end));
+        }
+
+        let temporary = self.map_path.with_extension("map.tmp");
+        let mut file = File::create(&temporary)?;
+        file.write_all(map.as_bytes())?;
+        file.sync_data()?;
+        fs::rename(&temporary, &self.map_path)
+    }
+}
+
+impl<R: Read + Seek> Read for CachedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.position >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let position = self.position;
+        let cached = |ranges: &[Range<u64>]| {
+            ranges
+                .iter()
+                .find(|range| range.contains(&position))
+                .map(|range| range.end)
+        };
+        let end = match cached(&self.ranges) {
+            Some(end) => end,
+            None => {
+                self.fetch(position)?;
+                cached(&self.ranges).expect("Fetched range must be cached")
+            }
+        };
+
+        let n = buf.len().min((end - position) as usize);
+        self.data.seek(SeekFrom::Start(position))?;
+        self.data.read_exact(&mut buf[..n])?;
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: Read + Seek> Seek for CachedReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::End(n) => self.len.checked_add_signed(n),
+            SeekFrom::Current(n) => self.position.checked_add_signed(n),
+        };
+
+        self.position = position.ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                "Invalid seek to a negative position",
+            )
+        })?;
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CacheOptions, RangeCache};
+    use crate::cipher::Cipher;
+    use crate::stream::EncryptedReader;
+    use crate::tests::encrypt;
+    use crate::{BLOCK_DATA_SIZE, BLOCK_SIZE, FILE_HEADER_SIZE};
+    use std::fs::{self, File};
+    use std::io::{Cursor, Error, Read, Result, Seek, SeekFrom};
+    use std::time::{Duration, SystemTime};
+
+    /// A source which counts the bytes read from it, and fails every read if `offline`.
+    struct Source {
+        inner: Cursor<Vec<u8>>,
+        read: usize,
+        offline: bool,
+    }
+
+    impl Source {
+        fn new(data: &[u8], offline: bool) -> Self {
+            Self {
+                inner: Cursor::new(data.to_vec()),
+                read: 0,
+                offline,
+            }
+        }
+    }
+
+    impl Read for Source {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            if self.offline {
+                return Err(Error::other("Source is offline"));
+            }
+            let n = self.inner.read(buf)?;
+            self.read += n;
+            Ok(n)
+        }
+    }
+
+    impl Seek for Source {
+        fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    fn options() -> CacheOptions {
+        CacheOptions {
+            blocks_per_fetch: 1,
+            ..CacheOptions::default()
+        }
+    }
+
+    #[test]
+    fn range_cache_should_serve_ranges_after_restarts() {
+        let dir = tempfile::tempdir().unwrap();
+        let cipher = Cipher::new("test", Some("salt")).unwrap();
+        let data: Vec<u8> = (0..3 * BLOCK_DATA_SIZE + 10).map(|i| i as u8).collect();
+        let encrypted = encrypt(&cipher, &data);
+
+        let position = 2 * BLOCK_DATA_SIZE as u64 + 5;
+        let read_tail = |source: Source| {
+            let cache = RangeCache::open(dir.path(), options()).unwrap();
+            let cached = cache.reader("object-v1", source).unwrap();
+            let mut reader = EncryptedReader::new_with_cipher(cached, cipher.clone()).unwrap();
+            reader.seek(SeekFrom::Start(position)).unwrap();
+            let mut tail = Vec::new();
+            reader.read_to_end(&mut tail).unwrap();
+            assert_eq!(tail, data[position as usize..]);
+        };
+
+        // The header with the first block, then the last two blocks
+        let mut source = Source::new(&encrypted, false);
+        {
+            let cache = RangeCache::open(dir.path(), options()).unwrap();
+            let mut cached = cache.reader("object-v1", &mut source).unwrap();
+            let mut reader = EncryptedReader::new_with_cipher(&mut cached, cipher.clone()).unwrap();
+            reader.seek(SeekFrom::Start(position)).unwrap();
+            let mut tail = Vec::new();
+            reader.read_to_end(&mut tail).unwrap();
+            assert_eq!(tail, data[position as usize..]);
+            assert_eq!(
+                cached.cached_ranges(),
+                [
+                    0..(FILE_HEADER_SIZE + BLOCK_SIZE) as u64,
+                    (FILE_HEADER_SIZE + 2 * BLOCK_SIZE) as u64..encrypted.len() as u64
+                ]
+            );
+        }
+        assert_eq!(source.read, encrypted.len() - BLOCK_SIZE);
+
+        // A new cache over the same directory doesn't need the source anymore
+        read_tail(Source::new(&encrypted, true));
+        let cache = RangeCache::open(dir.path(), options()).unwrap();
+        assert_eq!(cache.size().unwrap(), (encrypted.len() - BLOCK_SIZE) as u64);
+
+        // A different size means the object changed
+        let mut changed = encrypted.clone();
+        changed.truncate(encrypted.len() - 1);
+        let cached = cache
+            .reader("object-v1", Source::new(&changed, true))
+            .unwrap();
+        assert!(cached.cached_ranges().is_empty());
+    }
+
+    #[test]
+    fn range_cache_should_evict_by_size_and_age() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = RangeCache::open(
+            dir.path(),
+            CacheOptions {
+                max_size: 150,
+                ..options()
+            },
+        )
+        .unwrap();
+
+        let fetch = |key: &str| {
+            let mut cached = cache.reader(key, Source::new(&[1u8; 100], false)).unwrap();
+            cached.read_to_end(&mut Vec::new()).unwrap();
+        };
+        let set_used = |key: &str, used: SystemTime| {
+            let name = format!("{:016x}.map", super::fnv1a(key.as_bytes()));
+            File::options()
+                .write(true)
+                .open(dir.path().join(name))
+                .unwrap()
+                .set_modified(used)
+                .unwrap();
+        };
+
+        fetch("a");
+        set_used("a", SystemTime::now() - Duration::from_secs(10));
+        fetch("b");
+        // Over the size limit, so the older object goes when another one is opened
+        assert_eq!(cache.size().unwrap(), 200);
+        let cached = cache.reader("b", Source::new(&[1u8; 100], true)).unwrap();
+        assert_eq!(cached.cached_ranges().first(), Some(&(0..100)));
+        assert_eq!(cache.size().unwrap(), 100);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+
+        // Open objects are kept however old, even by clones of the cache
+        set_used("b", SystemTime::now() - Duration::from_secs(2 * 60 * 60));
+        let report = cache.clone().evict().unwrap();
+        assert_eq!((report.objects, report.bytes), (0, 0));
+        drop(cached);
+        let report = cache.evict().unwrap();
+        assert_eq!((report.objects, report.bytes), (1, 100));
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+
+        // A map without its data doesn't claim any ranges
+        fetch("c");
+        let name = format!("{:016x}.data", super::fnv1a(b"c"));
+        fs::remove_file(dir.path().join(name)).unwrap();
+        let cached = cache.reader("c", Source::new(&[1u8; 100], true)).unwrap();
+        assert!(cached.cached_ranges().is_empty());
+    }
+
+    #[test]
+    fn range_cache_should_keep_data_of_open_readers() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = RangeCache::open(dir.path(), options()).unwrap();
+        let map_path = dir
+            .path()
+            .join(format!("{:016x}.map", super::fnv1a(b"object")));
+        let map = |ranges: &str| format!("{}\nkey object\nlen 100\n{}", super::MAP_MAGIC, ranges);
+
+        let mut first = cache
+            .reader("object", Source::new(&[1u8; 100], false))
+            .unwrap();
+        first.read_to_end(&mut Vec::new()).unwrap();
+
+        // As if the first reader had fetched its data, but not written the map yet
+        fs::write(&map_path, map("")).unwrap();
+        let second = cache
+            .reader("object", Source::new(&[1u8; 100], true))
+            .unwrap();
+        assert!(second.cached_ranges().is_empty());
+        first.seek(SeekFrom::Start(0)).unwrap();
+        let mut data = Vec::new();
+        first.read_to_end(&mut data).unwrap();
+        assert_eq!(data, [1u8; 100]);
+        drop((first, second));
+
+        // Ranges of maps are sorted and merged
+        fs::write(&map_path, map("50 100\n0 20\n10 30\n30 40\n")).unwrap();
+        let cached = cache
+            .reader("object", Source::new(&[1u8; 100], true))
+            .unwrap();
+        assert_eq!(cached.cached_ranges(), [0..40, 50..100]);
+    }
+}
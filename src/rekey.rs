@@ -0,0 +1,349 @@
+/// Re-encrypting data and names from one set of keys to another.
+///
+/// Plaintext only ever exists in memory, one block at a time.
+use anyhow::{anyhow, Context, Result};
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::cipher::Cipher;
+use crate::decrypter::Decrypter;
+use crate::encrypter::Encrypter;
+use crate::stream::read_full;
+use crate::{BLOCK_SIZE, FILE_HEADER_SIZE};
+
+/// Re-encrypts the encrypted file read from `input` under `new` into `output`, with a fresh nonce.
+/// Returns the size of the plaintext.
+///
+/// # Errors
+/// Fails on I/O errors, or if the header or a block of `input` doesn't authenticate under `old`.
+/// `output` is left incomplete in that case. Both ciphers must encrypt data.
+pub fn rekey_file<R: Read, W: Write>(
+    old: &Cipher,
+    new: &Cipher,
+    mut input: R,
+    mut output: W,
+) -> Result<u64> {
+    old.require_data_encryption()?;
+    new.require_data_encryption()?;
+    let mut header = [0u8; FILE_HEADER_SIZE];
+    if read_full(&mut input, &mut header)? < FILE_HEADER_SIZE {
+        return Err(anyhow!("File is too short to be encrypted"));
+    }
+    let decrypter = Decrypter::new(&old.get_file_key(), &header)?;
+    let encrypter = Encrypter::new(&new.get_file_key())?;
+    output.write_all(&encrypter.get_file_header())?;
+
+    let mut block = vec![0u8; BLOCK_SIZE];
+    let mut size = 0;
+
+    for block_id in 0.. {
+        let read = read_full(&mut input, &mut block)?;
+        if read == 0 {
+            break;
+        }
+
+        let decrypted = decrypter
+            .decrypt_block(block_id, &block[..read])
+            .map_err(|_| anyhow!("Block {} failed authentication", block_id))?;
+        output.write_all(&encrypter.encrypt_block(block_id, &decrypted))?;
+        size += decrypted.len() as u64;
+    }
+
+    Ok(size)
+}
+
+/// Re-encrypts every segment of the encrypted `path` from `old` to `new`.
+pub fn rekey_path(old: &Cipher, new: &Cipher, path: &Path) -> Result<PathBuf> {
+    new.encrypt_path(&old.decrypt_path(path)?)
+}
+
+/// Options for [`rekey_tree()`](rekey_tree).
+#[derive(Clone, Debug, Default)]
+pub struct RekeyOptions {
+    /// File recording every re-encrypted file, one path relative to the source per line.
+    /// Files listed in it are skipped, so an interrupted run can be resumed with the same journal.
+    pub journal: Option<PathBuf>,
+}
+
+/// The outcome of [`rekey_tree()`](rekey_tree).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RekeyReport {
+    /// Number of files which were re-encrypted.
+    pub files: usize,
+    /// Number of directories which were created.
+    pub dirs: usize,
+    /// Number of files which were skipped because the journal lists them.
+    pub resumed: usize,
+}
+
+/// Re-encrypts the encrypted tree at `source` from `old` to `new` into `destination`, name by
+/// name like [`rekey_path()`](rekey_path) and file by file with [`rekey_file()`](rekey_file).
+/// Modification times of files are copied over.
+///
+/// # Errors
+/// Stops at the first name or file which fails to decrypt, or at the first I/O error. The file
+/// being written at that point is removed, and already re-encrypted files are in the journal.
+/// Fails before touching anything if one of the ciphers doesn't encrypt data.
+pub fn rekey_tree(
+    old: &Cipher,
+    new: &Cipher,
+    source: &Path,
+    destination: &Path,
+    options: &RekeyOptions,
+) -> Result<RekeyReport> {
+    old.require_data_encryption()?;
+    new.require_data_encryption()?;
+    let mut done = HashSet::new();
+    let journal = match &options.journal {
+        Some(path) => {
+            match File::open(path) {
+                Ok(file) => {
+                    for line in BufReader::new(file).lines() {
+                        done.insert(PathBuf::from(line?));
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Failed to read {}", path.display()))
+                }
+            }
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("Failed to open {}", path.display()))?;
+            Some(file)
+        }
+        None => None,
+    };
+
+    fs::create_dir_all(destination)
+        .with_context(|| format!("Failed to create {}", destination.display()))?;
+
+    let mut rekey = TreeRekey {
+        old,
+        new,
+        source,
+        destination,
+        done,
+        journal,
+        report: RekeyReport::default(),
+    };
+    rekey.rekey_dir(Path::new(""), Path::new(""))?;
+
+    Ok(rekey.report)
+}
+
+struct TreeRekey<'a> {
+    old: &'a Cipher,
+    new: &'a Cipher,
+    source: &'a Path,
+    destination: &'a Path,
+    done: HashSet<PathBuf>,
+    journal: Option<File>,
+    report: RekeyReport,
+}
+
+impl TreeRekey<'_> {
+    /// Re-encrypts the content of the source directory at `path`, which has already been
+    /// created at `new_path` in the destination.
+    fn rekey_dir(&mut self, path: &Path, new_path: &Path) -> Result<()> {
+        let dir = self.source.join(path);
+        let mut entries = fs::read_dir(&dir)
+            .with_context(|| format!("Failed to read {}", dir.display()))?
+            .collect::<io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+
+        for entry in entries {
+            let name = entry.file_name();
+            let entry_path = path.join(&name);
+
+            let new_name = name
+                .to_str()
+                .ok_or_else(|| anyhow!("Name is not valid UTF-8"))
+                .and_then(|name| self.old.decrypt_plain_segment(name))
+                .and_then(|name| self.new.encrypt_segment(&name))
+                .with_context(|| format!("Failed to decrypt name {}", entry_path.display()))?;
+            let new_entry_path = new_path.join(new_name);
+
+            if fs::metadata(entry.path())?.is_dir() {
+                let target = self.destination.join(&new_entry_path);
+                if !target.is_dir() {
+                    fs::create_dir(&target)
+                        .with_context(|| format!("Failed to create {}", target.display()))?;
+                    self.report.dirs += 1;
+                }
+                self.rekey_dir(&entry_path, &new_entry_path)?;
+            } else if self.done.contains(&entry_path) {
+                self.report.resumed += 1;
+            } else {
+                self.rekey_file(&entry_path, &new_entry_path)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn rekey_file(&mut self, path: &Path, new_path: &Path) -> Result<()> {
+        let source = self.source.join(path);
+        let target = self.destination.join(new_path);
+
+        let input =
+            File::open(&source).with_context(|| format!("Failed to open {}", source.display()))?;
+        let modified = input.metadata()?.modified()?;
+        let output = File::create(&target)
+            .with_context(|| format!("Failed to create {}", target.display()))?;
+
+        let mut output = BufWriter::new(output);
+        let result =
+            rekey_file(self.old, self.new, BufReader::new(input), &mut output).and_then(|_| {
+                let output = output
+                    .into_inner()
+                    .map_err(io::IntoInnerError::into_error)?;
+                output.set_modified(modified)?;
+                Ok(output.sync_all()?)
+            });
+        if let Err(e) = result {
+            let _ = fs::remove_file(&target);
+            return Err(e.context(format!("Failed to re-encrypt {}", path.display())));
+        }
+
+        if let Some(journal) = &mut self.journal {
+            let line = path
+                .to_str()
+                .ok_or_else(|| anyhow!("Path is not valid UTF-8"))?;
+            writeln!(journal, "{}", line)?;
+            journal.sync_data()?;
+        }
+        self.report.files += 1;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{rekey_file, rekey_path, rekey_tree, RekeyOptions};
+    use crate::cipher::Cipher;
+    use crate::stream::EncryptedReader;
+    use crate::tests::encrypt;
+    use std::fs;
+    use std::io::Read;
+    use std::path::Path;
+
+    fn decrypt(cipher: &Cipher, encrypted: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        EncryptedReader::new_with_cipher(encrypted, cipher.clone())
+            .unwrap()
+            .read_to_end(&mut data)
+            .unwrap();
+        data
+    }
+
+    #[test]
+    fn rekey_should_reencrypt_data_and_names() {
+        let old = Cipher::new("old", Some("salt")).unwrap();
+        let new = Cipher::new("new", None).unwrap();
+        let data: Vec<u8> = (0..150_000).map(|i| i as u8).collect();
+        let encrypted = encrypt(&old, &data);
+
+        let mut rekeyed = Vec::new();
+        let size = rekey_file(&old, &new, &encrypted[..], &mut rekeyed).unwrap();
+        assert_eq!(size, data.len() as u64);
+        assert_eq!(rekeyed.len(), encrypted.len());
+        assert_ne!(rekeyed[8..32], encrypted[8..32]);
+        assert_eq!(decrypt(&new, &rekeyed), data);
+
+        assert!(rekey_file(&new, &old, &encrypted[..], Vec::new()).is_err());
+        let plain = new.clone().with_data_encryption(false);
+        let mut output = Vec::new();
+        assert!(rekey_file(&old, &plain, &encrypted[..], &mut output).is_err());
+        assert!(output.is_empty());
+
+        let path = old.encrypt_path(Path::new("a/b.txt")).unwrap();
+        assert_eq!(
+            rekey_path(&old, &new, &path).unwrap(),
+            new.encrypt_path(Path::new("a/b.txt")).unwrap()
+        );
+    }
+
+    #[test]
+    fn rekey_tree_should_resume_from_journal() {
+        let old = Cipher::new("old", Some("salt")).unwrap();
+        let new = Cipher::new("new", None).unwrap();
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        let journal = destination.path().join("journal");
+        let old_path = |path: &str| {
+            source
+                .path()
+                .join(old.encrypt_path(Path::new(path)).unwrap())
+        };
+        let new_path = |path: &str| {
+            destination
+                .path()
+                .join(new.encrypt_path(Path::new(path)).unwrap())
+        };
+        let options = RekeyOptions {
+            journal: Some(journal),
+        };
+
+        fs::create_dir(old_path("dir")).unwrap();
+        fs::write(old_path("dir/a.txt"), encrypt(&old, b"a")).unwrap();
+        fs::write(old_path("b.txt"), encrypt(&old, b"b")).unwrap();
+        let report = rekey_tree(&old, &new, source.path(), destination.path(), &options).unwrap();
+        assert_eq!(report.files, 2);
+        assert_eq!(report.dirs, 1);
+
+        // Encrypted with the wrong keys, so the run stops there
+        fs::write(old_path("c.txt"), encrypt(&new, b"c")).unwrap();
+        assert!(rekey_tree(&old, &new, source.path(), destination.path(), &options).is_err());
+        assert!(!new_path("c.txt").exists());
+
+        fs::write(old_path("c.txt"), encrypt(&old, b"c")).unwrap();
+        let report = rekey_tree(&old, &new, source.path(), destination.path(), &options).unwrap();
+        assert_eq!(report.files, 1);
+        assert_eq!(report.resumed, 2);
+        assert_eq!(report.dirs, 0);
+        assert_eq!(
+            decrypt(&new, &fs::read(new_path("dir/a.txt")).unwrap()),
+            b"a"
+        );
+        assert_eq!(decrypt(&new, &fs::read(new_path("b.txt")).unwrap()), b"b");
+        assert_eq!(decrypt(&new, &fs::read(new_path("c.txt")).unwrap()), b"c");
+    }
+
+    #[test]
+    fn rekey_should_reject_names_which_are_not_plain() {
+        let old = Cipher::new("old", Some("salt")).unwrap();
+        let new = Cipher::new("new", None).unwrap();
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+
+        // Would re-encrypt to an absolute path replacing the destination
+        let absolute = outside.path().join("escaped.txt");
+        let crafted = old.encrypt_segment(absolute.to_str().unwrap()).unwrap();
+        assert!(rekey_path(&old, &new, Path::new(&crafted)).is_err());
+
+        fs::write(source.path().join(&crafted), encrypt(&old, b"x")).unwrap();
+        let error = rekey_tree(
+            &old,
+            &new,
+            source.path(),
+            destination.path(),
+            &RekeyOptions::default(),
+        )
+        .unwrap_err();
+        assert!(format!("{:#}", error).contains("not a plain name"));
+        assert!(!absolute.exists());
+        assert_eq!(fs::read_dir(destination.path()).unwrap().count(), 0);
+
+        for name in ["a/b", ".."] {
+            let crafted = old.encrypt_segment(name).unwrap();
+            assert!(old.decrypt_path(Path::new(&crafted)).is_err());
+        }
+    }
+}
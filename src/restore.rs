@@ -0,0 +1,446 @@
+/// Restoring an encrypted directory tree to plaintext.
+///
+/// This is the reverse of [`sync_tree()`](crate::sync::sync_tree): the encrypted tree can be
+/// a copy of the directory (or bucket) backing an rclone crypt remote.
+use anyhow::{anyhow, Context, Result};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, ErrorKind};
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+
+use crate::cipher::{is_plain_segment, Cipher};
+use crate::stream::{EncryptedReader, SalvageMode};
+
+/// Options for [`restore_tree()`](restore_tree).
+#[derive(Clone, Debug, Default)]
+pub struct RestoreOptions {
+    /// Only restore this plaintext file or directory, relative to the root of the tree.
+    /// It's restored at the same relative path in the destination.
+    pub path: Option<PathBuf>,
+    /// Salvage files with blocks which fail authentication instead of failing them.
+    /// Their damaged ranges are recorded in [`RestoreReport::damaged`](RestoreReport::damaged).
+    pub salvage: Option<SalvageMode>,
+}
+
+/// Why an entry couldn't be restored.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RestoreFailureKind {
+    /// The name couldn't be decrypted. Directories with such a name are skipped entirely.
+    Name,
+    /// The data failed authentication, or isn't in the rclone crypt format.
+    Data,
+    /// Any other I/O error.
+    Io,
+}
+
+/// An entry which couldn't be restored.
+#[derive(Debug)]
+pub struct RestoreFailure {
+    pub kind: RestoreFailureKind,
+    /// Path relative to the encrypted directory.
+    pub encrypted_path: PathBuf,
+    /// Path relative to the plaintext directory, if the name could be decrypted.
+    pub path: Option<PathBuf>,
+    pub error: anyhow::Error,
+}
+
+/// The outcome of [`restore_tree()`](restore_tree).
+#[derive(Debug, Default)]
+pub struct RestoreReport {
+    /// Number of files which were restored.
+    pub files: usize,
+    /// Number of directories which were restored.
+    pub dirs: usize,
+    pub failures: Vec<RestoreFailure>,
+    /// Files which were restored in salvage mode despite blocks failing authentication.
+    pub damaged: Vec<DamagedFile>,
+}
+
+/// A file restored with [`RestoreOptions::salvage`](RestoreOptions::salvage) which had damaged blocks.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DamagedFile {
+    /// Path relative to the encrypted directory.
+    pub encrypted_path: PathBuf,
+    /// Path relative to the plaintext directory.
+    pub path: PathBuf,
+    /// The damaged plaintext byte ranges, see [`EncryptedReader::damaged_ranges()`](EncryptedReader::damaged_ranges).
+    pub ranges: Vec<Range<u64>>,
+}
+
+/// Decrypts the encrypted tree at `source` into `destination`.
+///
+/// Names are decrypted with the [`Cipher`](Cipher), data with an [`EncryptedReader`](EncryptedReader),
+/// and modification times of files are copied over. Entries which fail to restore are recorded
+/// in the report and skipped; partially written files are removed.
+///
+/// # Errors
+/// Only fails if `source` (or the encrypted counterpart of [`RestoreOptions::path`](RestoreOptions::path))
+/// doesn't exist, or if `destination` can't be created.
+pub fn restore_tree(
+    cipher: &Cipher,
+    source: &Path,
+    destination: &Path,
+    options: &RestoreOptions,
+) -> Result<RestoreReport> {
+    let mut restore = TreeRestore {
+        cipher,
+        source,
+        destination,
+        salvage: options.salvage,
+        report: RestoreReport::default(),
+    };
+
+    let path = options.path.clone().unwrap_or_default();
+    if !path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return Err(anyhow!("{} is not a plain relative path", path.display()));
+    }
+    let encrypted_path = cipher.encrypt_path(&path)?;
+
+    let metadata = fs::metadata(source.join(&encrypted_path)).with_context(|| {
+        format!(
+            "Failed to find {} ({}) in {}",
+            path.display(),
+            encrypted_path.display(),
+            source.display()
+        )
+    })?;
+
+    let target = destination.join(&path);
+    let target_dir = if metadata.is_dir() {
+        target.as_path()
+    } else if path.parent().is_some() {
+        target.parent().unwrap()
+    } else {
+        return Err(anyhow!("{} is not a directory", source.display()));
+    };
+    fs::create_dir_all(target_dir)
+        .with_context(|| format!("Failed to create {}", target_dir.display()))?;
+
+    if metadata.is_dir() {
+        restore.restore_dir(&path, &encrypted_path);
+    } else {
+        restore.restore_file(&path, &encrypted_path);
+    }
+
+    Ok(restore.report)
+}
+
+struct TreeRestore<'a> {
+    cipher: &'a Cipher,
+    source: &'a Path,
+    destination: &'a Path,
+    salvage: Option<SalvageMode>,
+    report: RestoreReport,
+}
+
+impl TreeRestore<'_> {
+    /// Restores the content of the encrypted directory at `encrypted_path`, which has already
+    /// been created at `path` in the destination.
+    fn restore_dir(&mut self, path: &Path, encrypted_path: &Path) {
+        let source_dir = self.source.join(encrypted_path);
+
+        let entries =
+            fs::read_dir(&source_dir).and_then(|entries| entries.collect::<io::Result<Vec<_>>>());
+        let mut entries = match entries {
+            Ok(entries) => entries,
+            Err(e) => {
+                return self.fail(RestoreFailureKind::Io, encrypted_path, Some(path), e.into())
+            }
+        };
+        entries.sort_by_key(|entry| entry.file_name());
+
+        for entry in entries {
+            let encrypted_name = entry.file_name();
+            let encrypted_entry_path = encrypted_path.join(&encrypted_name);
+
+            let name = encrypted_name
+                .to_str()
+                .ok_or_else(|| anyhow!("Name is not valid UTF-8"))
+                .and_then(|name| {
+                    self.cipher
+                        .decrypt_file_name(name)
+                        .context("Failed to decrypt name")
+                })
+                .and_then(|name| {
+                    // Crafted names could otherwise escape the destination
+                    if is_plain_segment(&name) {
+                        Ok(name)
+                    } else {
+                        Err(anyhow!(
+                            "Name decrypts to {:?}, which is not a plain name",
+                            name
+                        ))
+                    }
+                });
+            let entry_path = match name {
+                Ok(name) => path.join(name),
+                Err(e) => {
+                    self.fail(RestoreFailureKind::Name, &encrypted_entry_path, None, e);
+                    continue;
+                }
+            };
+
+            // Follows symlinks, like rclone's local backend does for directories and files
+            let metadata = match fs::metadata(entry.path()) {
+                Ok(metadata) => metadata,
+                Err(e) => {
+                    self.fail(
+                        RestoreFailureKind::Io,
+                        &encrypted_entry_path,
+                        Some(&entry_path),
+                        e.into(),
+                    );
+                    continue;
+                }
+            };
+
+            if metadata.is_dir() {
+                let target = self.destination.join(&entry_path);
+                match fs::create_dir_all(&target) {
+                    Ok(()) => {
+                        self.report.dirs += 1;
+                        self.restore_dir(&entry_path, &encrypted_entry_path);
+                    }
+                    Err(e) => self.fail(
+                        RestoreFailureKind::Io,
+                        &encrypted_entry_path,
+                        Some(&entry_path),
+                        e.into(),
+                    ),
+                }
+            } else {
+                self.restore_file(&entry_path, &encrypted_entry_path);
+            }
+        }
+    }
+
+    fn restore_file(&mut self, path: &Path, encrypted_path: &Path) {
+        let source = self.source.join(encrypted_path);
+        let target = self.destination.join(path);
+
+        match self.decrypt_file(&source, &target) {
+            Ok(ranges) => {
+                self.report.files += 1;
+                if !ranges.is_empty() {
+                    self.report.damaged.push(DamagedFile {
+                        encrypted_path: encrypted_path.to_path_buf(),
+                        path: path.to_path_buf(),
+                        ranges,
+                    });
+                }
+            }
+            Err(e) => {
+                let kind = match e.kind() {
+                    ErrorKind::InvalidData => RestoreFailureKind::Data,
+                    _ => RestoreFailureKind::Io,
+                };
+                self.fail(kind, encrypted_path, Some(path), e.into());
+            }
+        }
+    }
+
+    /// Returns the damaged ranges of the file in salvage mode.
+    fn decrypt_file(&self, source: &Path, target: &Path) -> io::Result<Vec<Range<u64>>> {
+        let input = File::open(source)?;
+        let modified = input.metadata()?.modified();
+
+        let input = BufReader::new(input);
+        let reader = match self.salvage {
+            Some(mode) => EncryptedReader::new_with_salvage(input, self.cipher.clone(), mode),
+            None => EncryptedReader::new_with_cipher(input, self.cipher.clone()),
+        };
+        let mut reader = reader.map_err(|e| match e.kind() {
+            // Anything but a failed block means the header is missing or invalid
+            ErrorKind::InvalidData => e,
+            _ => io::Error::new(ErrorKind::InvalidData, e),
+        })?;
+
+        let mut output = BufWriter::new(File::create(target)?);
+        let result = io::copy(&mut reader, &mut output)
+            .and_then(|_| output.into_inner().map_err(|e| e.into_error()))
+            .and_then(|file| match modified {
+                Ok(modified) => file.set_modified(modified),
+                Err(_) => Ok(()),
+            });
+
+        if result.is_err() {
+            let _ = fs::remove_file(target);
+        }
+        result.map(|_| reader.damaged_ranges().to_vec())
+    }
+
+    fn fail(
+        &mut self,
+        kind: RestoreFailureKind,
+        encrypted_path: &Path,
+        path: Option<&Path>,
+        error: anyhow::Error,
+    ) {
+        self.report.failures.push(RestoreFailure {
+            kind,
+            encrypted_path: encrypted_path.to_path_buf(),
+            path: path.map(Path::to_path_buf),
+            error,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{restore_tree, RestoreFailureKind, RestoreOptions};
+    use crate::cipher::Cipher;
+    use crate::stream::SalvageMode;
+    use crate::sync::{sync_tree, SyncOptions};
+    use crate::BLOCK_DATA_SIZE;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    fn encrypted_tree(cipher: &Cipher) -> (tempfile::TempDir, tempfile::TempDir) {
+        let plaintext = tempfile::tempdir().unwrap();
+        let encrypted = tempfile::tempdir().unwrap();
+
+        fs::create_dir_all(plaintext.path().join("docs/old")).unwrap();
+        fs::write(plaintext.path().join("docs/a.txt"), b"a").unwrap();
+        fs::write(plaintext.path().join("docs/old/b.txt"), vec![1u8; 70_000]).unwrap();
+        fs::write(plaintext.path().join("c.txt"), b"c").unwrap();
+
+        sync_tree(
+            cipher,
+            plaintext.path(),
+            encrypted.path(),
+            &SyncOptions::default(),
+        )
+        .unwrap();
+        (plaintext, encrypted)
+    }
+
+    #[test]
+    fn restore_should_report_failures_and_continue() {
+        let cipher = Cipher::new("test", Some("test")).unwrap();
+        let (plaintext, encrypted) = encrypted_tree(&cipher);
+        let restored = tempfile::tempdir().unwrap();
+
+        // Flip a bit in the data of c.txt, and add an entry which isn't encrypted
+        let c_txt = encrypted
+            .path()
+            .join(cipher.encrypt_path(Path::new("c.txt")).unwrap());
+        let mut data = fs::read(&c_txt).unwrap();
+        *data.last_mut().unwrap() ^= 1;
+        fs::write(&c_txt, data).unwrap();
+        fs::write(encrypted.path().join("plain.txt"), b"").unwrap();
+        // Names crafted to escape the destination
+        for name in ["..", "../escaped.txt"] {
+            let crafted = encrypted.path().join(cipher.encrypt_segment(name).unwrap());
+            fs::write(crafted, cipher.encrypt_segment("x").unwrap()).unwrap();
+        }
+
+        let report = restore_tree(
+            &cipher,
+            encrypted.path(),
+            restored.path(),
+            &RestoreOptions::default(),
+        )
+        .unwrap();
+
+        assert_eq!(report.files, 2);
+        assert_eq!(report.dirs, 2);
+
+        let mut failures: Vec<_> = report
+            .failures
+            .iter()
+            .map(|failure| (failure.kind, failure.path.clone()))
+            .collect();
+        failures.sort_by_key(|(kind, _)| *kind as u8);
+        assert_eq!(
+            failures,
+            [
+                (RestoreFailureKind::Name, None),
+                (RestoreFailureKind::Name, None),
+                (RestoreFailureKind::Name, None),
+                (RestoreFailureKind::Data, Some(PathBuf::from("c.txt"))),
+            ]
+        );
+        assert!(!restored.path().join("../escaped.txt").exists());
+
+        assert!(!restored.path().join("c.txt").exists());
+        for path in ["docs/a.txt", "docs/old/b.txt"].iter() {
+            let original = plaintext.path().join(path);
+            let restored = restored.path().join(path);
+
+            assert_eq!(fs::read(&restored).unwrap(), fs::read(&original).unwrap());
+            assert_eq!(
+                fs::metadata(&restored).unwrap().modified().unwrap(),
+                fs::metadata(&original).unwrap().modified().unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn restore_should_restore_sub_paths() {
+        let cipher = Cipher::new("test", Some("test")).unwrap();
+        let (_plaintext, encrypted) = encrypted_tree(&cipher);
+        let restored = tempfile::tempdir().unwrap();
+
+        let options = RestoreOptions {
+            path: Some(PathBuf::from("docs/old")),
+            ..RestoreOptions::default()
+        };
+        let report = restore_tree(&cipher, encrypted.path(), restored.path(), &options).unwrap();
+        assert_eq!(report.files, 1);
+        assert!(report.failures.is_empty());
+        assert!(restored.path().join("docs/old/b.txt").is_file());
+        assert!(!restored.path().join("docs/a.txt").exists());
+
+        let options = RestoreOptions {
+            path: Some(PathBuf::from("docs/a.txt")),
+            ..RestoreOptions::default()
+        };
+        let report = restore_tree(&cipher, encrypted.path(), restored.path(), &options).unwrap();
+        assert_eq!(report.files, 1);
+        assert_eq!(fs::read(restored.path().join("docs/a.txt")).unwrap(), b"a");
+
+        let options = RestoreOptions {
+            path: Some(PathBuf::from("missing")),
+            ..RestoreOptions::default()
+        };
+        assert!(restore_tree(&cipher, encrypted.path(), restored.path(), &options).is_err());
+    }
+
+    #[test]
+    fn restore_should_salvage_damaged_files() {
+        let cipher = Cipher::new("test", Some("test")).unwrap();
+        let (plaintext, encrypted) = encrypted_tree(&cipher);
+        let restored = tempfile::tempdir().unwrap();
+
+        // Flip a bit in the second block of docs/old/b.txt
+        let b_txt = encrypted
+            .path()
+            .join(cipher.encrypt_path(Path::new("docs/old/b.txt")).unwrap());
+        let mut data = fs::read(&b_txt).unwrap();
+        *data.last_mut().unwrap() ^= 1;
+        fs::write(&b_txt, data).unwrap();
+
+        let options = RestoreOptions {
+            salvage: Some(SalvageMode::Zero),
+            ..RestoreOptions::default()
+        };
+        let report = restore_tree(&cipher, encrypted.path(), restored.path(), &options).unwrap();
+
+        assert!(report.failures.is_empty());
+        assert_eq!(report.files, 3);
+        assert_eq!(report.damaged.len(), 1);
+        assert_eq!(report.damaged[0].path, Path::new("docs/old/b.txt"));
+        assert_eq!(
+            report.damaged[0].ranges,
+            vec![BLOCK_DATA_SIZE as u64..70_000]
+        );
+
+        let original = fs::read(plaintext.path().join("docs/old/b.txt")).unwrap();
+        let salvaged = fs::read(restored.path().join("docs/old/b.txt")).unwrap();
+        assert_eq!(salvaged.len(), original.len());
+        assert_eq!(salvaged[..BLOCK_DATA_SIZE], original[..BLOCK_DATA_SIZE]);
+    }
+}
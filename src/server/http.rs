@@ -0,0 +1,230 @@
+/// A read-only HTTP server for an encrypted directory, like `rclone serve http`, e.g. to stream media.
+use std::io::{Error, Result};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::Path;
+use std::thread;
+use std::time::UNIX_EPOCH;
+use tiny_http::{Method, Request, Response, ResponseBox, Server};
+
+use super::{
+    error_response, escape_json, escape_xml, file_response, header, header_value, href, http_date,
+    status, url_path,
+};
+use crate::cryptfs::{CryptFs, DirEntry};
+
+/// Serves a [`CryptFs`](CryptFs) read-only over HTTP, decrypting names and data on the fly.
+///
+/// Files are served with `Content-Length`, `Accept-Ranges`, `ETag` and `Last-Modified`, and
+/// `Range` requests only decrypt the blocks holding the range, so media can be streamed and
+/// seeked in browsers. Directories are listed as HTML, or as JSON for `?format=json` and
+/// clients which accept `application/json` but not `text/html`.
+pub struct HttpServer {
+    server: Server,
+    fs: CryptFs,
+}
+
+impl HttpServer {
+    /// Listens on `addr`, serving `fs`. Use port 0 to pick a free port.
+    pub fn bind(addr: impl ToSocketAddrs, fs: CryptFs) -> Result<Self> {
+        let server = Server::http(addr).map_err(Error::other)?;
+        Ok(Self { server, fs })
+    }
+
+    /// Returns the address the server listens on.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.server.server_addr().to_ip()
+    }
+
+    /// Serves requests, each on its own thread, until [`stop()`](HttpServer::stop) is called.
+    pub fn serve(&self) {
+        thread::scope(|scope| {
+            for request in self.server.incoming_requests() {
+                scope.spawn(move || self.handle(request));
+            }
+        });
+    }
+
+    /// Makes [`serve()`](HttpServer::serve) return once the requests being served are done.
+    pub fn stop(&self) {
+        self.server.unblock();
+    }
+
+    /// Answers a single request.
+    pub fn handle(&self, request: Request) {
+        let response = self.respond(&request).unwrap_or_else(error_response);
+        // Failing to send the response means the client is gone, so there's nobody to tell
+        let _ = request.respond(response);
+    }
+
+    fn respond(&self, request: &Request) -> Result<ResponseBox> {
+        if !matches!(request.method(), Method::Get | Method::Head) {
+            return Ok(status(405).with_header(header("Allow", "GET, HEAD")));
+        }
+
+        let path = url_path(request.url())?;
+        let metadata = self.fs.metadata(&path)?;
+        if !metadata.is_dir {
+            return file_response(&self.fs, &path, &metadata, request);
+        }
+
+        // Relative links in listings need the trailing `/`
+        let (url, query) = request.url().split_once('?').unwrap_or((request.url(), ""));
+        if !url.ends_with('/') {
+            let mut location = href(&path, true);
+            if !query.is_empty() {
+                location.push('?');
+                location.push_str(query);
+            }
+            return Ok(status(301).with_header(header("Location", &location)));
+        }
+
+        let entries = self.fs.read_dir(&path)?;
+        let json = query.split('&').any(|param| param == "format=json")
+            || header_value(request, "Accept").is_some_and(|accept| {
+                accept.contains("application/json") && !accept.contains("text/html")
+            });
+        let (content_type, body) = if json {
+            ("application/json", json_listing(&entries))
+        } else {
+            ("text/html; charset=utf-8", html_listing(&path, &entries))
+        };
+
+        Ok(Response::from_string(body)
+            .with_header(header("Content-Type", content_type))
+            .boxed())
+    }
+}
+
+fn json_listing(entries: &[DirEntry]) -> String {
+    let entries: Vec<_> = entries
+        .iter()
+        .map(|entry| {
+            let modified = entry
+                .metadata
+                .modified
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map_or("null".to_string(), |modified| {
+                    modified.as_secs().to_string()
+                });
+            format!(
+                "{{\"name\":\"{}\",\"href\":\"{}\",\"is_dir\":{},\"size\":{},\"modified\":{}}}",
+                escape_json(&entry.name),
+                href(&entry.path, entry.metadata.is_dir),
+                entry.metadata.is_dir,
+                entry.metadata.len,
+                modified
+            )
+        })
+        .collect();
+    format!("[{}]\n", entries.join(","))
+}
+
+fn html_listing(path: &Path, entries: &[DirEntry]) -> String {
+    let title = escape_xml(&format!("Index of {}", href(path, true)));
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>{}</title></head>\n<body>\n<h1>{}</h1>\n<table>\n",
+        title, title
+    );
+    if path.parent().is_some() {
+        html.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+    }
+
+    for entry in entries {
+        let name = if entry.metadata.is_dir {
+            format!("{}/", entry.name)
+        } else {
+            entry.name.clone()
+        };
+        let size = if entry.metadata.is_dir {
+            String::new()
+        } else {
+            entry.metadata.len.to_string()
+        };
+        let modified = entry.metadata.modified.map(http_date).unwrap_or_default();
+        html.push_str(&format!(
+            "<tr><td><a href=\"{}\">{}</a></td><td>{}</td><td>{}</td></tr>\n",
+            escape_xml(&href(&entry.path, entry.metadata.is_dir)),
+            escape_xml(&name),
+            size,
+            modified
+        ));
+    }
+
+    html.push_str("</table>\n</body>\n</html>\n");
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HttpServer;
+    use crate::cipher::Cipher;
+    use crate::cryptfs::CryptFs;
+    use crate::server::tests::request;
+    use std::io::Write;
+    use std::path::Path;
+    use std::thread;
+
+    #[test]
+    fn http_server_should_serve_ranges_and_listings() {
+        let root = tempfile::tempdir().unwrap();
+        let fs = CryptFs::new(root.path(), Cipher::new("test", Some("salt")).unwrap()).unwrap();
+        let data: Vec<u8> = (0..200_000).map(|i| i as u8).collect();
+        fs.create_dir(Path::new("videos")).unwrap();
+        let mut file = fs.create(Path::new("videos/a <1>.mp4")).unwrap();
+        file.write_all(&data).unwrap();
+        file.finish().unwrap();
+        drop(file);
+
+        let server = HttpServer::bind("127.0.0.1:0", fs).unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::scope(|scope| {
+            scope.spawn(|| server.serve());
+
+            let response = request(addr, "GET", "/videos/a%20%3C1%3E.mp4", &[], b"");
+            assert_eq!(response.status, 200);
+            assert_eq!(response.header("Content-Type"), Some("video/mp4"));
+            assert_eq!(response.header("Content-Length"), Some("200000"));
+            assert_eq!(response.header("Accept-Ranges"), Some("bytes"));
+            assert!(response.header("Last-Modified").is_some());
+            assert_eq!(response.body, data);
+            let etag = response.header("ETag").unwrap().to_string();
+
+            let ranged = [("Range", "bytes=131070-131080")];
+            let response = request(addr, "GET", "/videos/a%20%3C1%3E.mp4", &ranged, b"");
+            assert_eq!(response.status, 206);
+            assert_eq!(response.header("Content-Length"), Some("11"));
+            assert_eq!(
+                response.header("Content-Range"),
+                Some("bytes 131070-131080/200000")
+            );
+            assert_eq!(response.body, data[131_070..131_081]);
+
+            let stale = [("Range", "bytes=0-0"), ("If-Range", "\"stale\"")];
+            let response = request(addr, "GET", "/videos/a%20%3C1%3E.mp4", &stale, b"");
+            assert_eq!(response.status, 200);
+            let cached = [("If-None-Match", etag.as_str())];
+            let response = request(addr, "GET", "/videos/a%20%3C1%3E.mp4", &cached, b"");
+            assert_eq!(response.status, 304);
+            assert!(response.body.is_empty());
+
+            let response = request(addr, "GET", "/videos", &[], b"");
+            assert_eq!(response.status, 301);
+            assert_eq!(response.header("Location"), Some("/videos/"));
+            let response = request(addr, "GET", "/videos/", &[], b"");
+            let html = String::from_utf8(response.body).unwrap();
+            assert!(html.contains("<a href=\"/videos/a%20%3C1%3E.mp4\">a &lt;1&gt;.mp4</a>"));
+            let response = request(addr, "GET", "/?format=json", &[], b"");
+            assert_eq!(response.header("Content-Type"), Some("application/json"));
+            let json = String::from_utf8(response.body).unwrap();
+            assert!(json.starts_with(
+                "[{\"name\":\"videos\",\"href\":\"/videos/\",\"is_dir\":true,\"size\":0,\"modified\":1"
+            ));
+            assert!(json.ends_with("}]\n"));
+
+            assert_eq!(request(addr, "PUT", "/b.txt", &[], b"x").status, 405);
+            assert_eq!(request(addr, "GET", "/missing", &[], b"").status, 404);
+
+            server.stop();
+        });
+    }
+}
@@ -0,0 +1,335 @@
+mod http;
+mod webdav;
+
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tiny_http::{Header, Request, Response, ResponseBox, StatusCode};
+
+use crate::cryptfs::{CryptFs, Metadata};
+
+pub use http::HttpServer;
+pub use webdav::WebDavServer;
+
+/// Characters left alone when encoding path segments of URLs.
+const SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+pub(crate) fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).expect("Header must be ASCII")
+}
+
+pub(crate) fn header_value<'a>(request: &'a Request, name: &'static str) -> Option<&'a str> {
+    request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv(name))
+        .map(|header| header.value.as_str())
+}
+
+pub(crate) fn http_date(time: SystemTime) -> String {
+    httpdate::fmt_http_date(time)
+}
+
+pub(crate) fn status(code: u16) -> ResponseBox {
+    Response::empty(code).boxed()
+}
+
+/// Maps errors of [`CryptFs`](crate::cryptfs::CryptFs) to responses.
+pub(crate) fn error_response(e: Error) -> ResponseBox {
+    let code = match e.kind() {
+        ErrorKind::NotFound => 404,
+        ErrorKind::InvalidInput => 400,
+        ErrorKind::PermissionDenied => 403,
+        _ => 500,
+    };
+    Response::from_string(format!("{}\n", e))
+        .with_status_code(code)
+        .boxed()
+}
+
+/// Returns a strong entity tag of a file, from its plaintext size and modification time.
+pub(crate) fn etag(metadata: &Metadata) -> String {
+    let modified = metadata
+        .modified
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .unwrap_or_default();
+    format!("\"{:x}-{:x}\"", metadata.len, modified.as_nanos())
+}
+
+/// Guesses the media type of a file from its extension, so browsers can play or show it.
+pub(crate) fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_default();
+
+    match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "txt" | "log" => "text/plain; charset=utf-8",
+        "css" => "text/css",
+        "js" => "text/javascript",
+        "json" => "application/json",
+        "pdf" => "application/pdf",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "svg" => "image/svg+xml",
+        "mp4" | "m4v" => "video/mp4",
+        "webm" => "video/webm",
+        "mkv" => "video/x-matroska",
+        "mov" => "video/quicktime",
+        "ogv" => "video/ogg",
+        "mp3" => "audio/mpeg",
+        "m4a" => "audio/mp4",
+        "ogg" | "oga" | "opus" => "audio/ogg",
+        "wav" => "audio/wav",
+        "flac" => "audio/flac",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Answers a `GET` or `HEAD` of the file at `path`, with a single byte range if requested.
+///
+/// Only the blocks holding the range are read and decrypted.
+pub(crate) fn file_response(
+    fs: &CryptFs,
+    path: &Path,
+    metadata: &Metadata,
+    request: &Request,
+) -> Result<ResponseBox> {
+    let len = metadata.len;
+    let etag = etag(metadata);
+    let mut headers = vec![
+        header("Content-Type", content_type(path)),
+        header("Accept-Ranges", "bytes"),
+        header("ETag", &etag),
+    ];
+    if let Some(modified) = metadata.modified {
+        headers.push(header("Last-Modified", &http_date(modified)));
+    }
+
+    let matches = |tags: &str| {
+        tags.split(',')
+            .map(|tag| tag.trim().trim_start_matches("W/"))
+            .any(|tag| tag == "*" || tag == etag)
+    };
+    if header_value(request, "If-None-Match").is_some_and(matches) {
+        let mut response = status(304);
+        for header in headers.into_iter().skip(1) {
+            response.add_header(header);
+        }
+        return Ok(response);
+    }
+
+    // A stale `If-Range` asks for the whole new content instead of a part of it
+    let range = header_value(request, "Range")
+        .filter(|_| header_value(request, "If-Range").is_none_or(|tag| tag.trim() == etag))
+        .and_then(|value| parse_range(value, len));
+    let (code, range) = match range {
+        Some(range) if range.is_empty() => {
+            return Ok(
+                status(416).with_header(header("Content-Range", &format!("bytes */{}", len)))
+            );
+        }
+        Some(range) => {
+            headers.push(header(
+                "Content-Range",
+                &format!("bytes {}-{}/{}", range.start, range.end - 1, len),
+            ));
+            (206, range)
+        }
+        None => (200, 0..len),
+    };
+
+    let mut file = fs.open(path)?;
+    file.seek(SeekFrom::Start(range.start))?;
+    let size = range.end - range.start;
+    Ok(Response::new(
+        StatusCode(code),
+        headers,
+        file.take(size),
+        Some(size as usize),
+        None,
+    )
+    // Clients need the length to show progress and seek, so never switch to chunked encoding
+    .with_chunked_threshold(usize::MAX)
+    .boxed())
+}
+
+/// Returns the percent-decoded path of a request URL, without the query.
+pub(crate) fn url_path(url: &str) -> Result<PathBuf> {
+    let path = url.split(['?', '#']).next().unwrap_or_default();
+    percent_decode_str(path)
+        .decode_utf8()
+        .map(|path| PathBuf::from(path.as_ref()))
+        .map_err(|_| Error::new(ErrorKind::InvalidInput, "Path is not UTF-8"))
+}
+
+/// Returns the absolute URL path of a plaintext path, with a trailing `/` for directories.
+pub(crate) fn href(path: &Path, is_dir: bool) -> String {
+    let mut href = String::new();
+    for component in path.components() {
+        if let Component::Normal(segment) = component {
+            href.push('/');
+            href.extend(utf8_percent_encode(&segment.to_string_lossy(), SEGMENT));
+        }
+    }
+    if is_dir || href.is_empty() {
+        href.push('/');
+    }
+    href
+}
+
+/// Returns whether `path` is the root of the served directory.
+pub(crate) fn is_root(path: &Path) -> bool {
+    path.components()
+        .all(|component| matches!(component, Component::RootDir | Component::CurDir))
+}
+
+/// Parses a `Range` header of a resource of `len` bytes.
+///
+/// Returns `None` for headers which should be ignored, like multiple ranges, and an empty
+/// range for unsatisfiable ones.
+pub(crate) fn parse_range(value: &str, len: u64) -> Option<Range<u64>> {
+    let (start, end) = value.trim().strip_prefix("bytes=")?.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+
+    if start.is_empty() {
+        let suffix: u64 = end.parse().ok()?;
+        return Some(len.saturating_sub(suffix)..len);
+    }
+
+    let start: u64 = start.parse().ok()?;
+    let end = if end.is_empty() {
+        len
+    } else {
+        let last: u64 = end.parse().ok()?;
+        if last < start {
+            return None;
+        }
+        last.saturating_add(1).min(len)
+    };
+    Some(start.min(end)..end)
+}
+
+pub(crate) fn escape_json(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+pub(crate) fn escape_xml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{href, parse_range, url_path};
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use std::path::Path;
+
+    /// A response read by [`request()`](request).
+    pub(crate) struct TestResponse {
+        pub status: u16,
+        pub headers: Vec<(String, String)>,
+        pub body: Vec<u8>,
+    }
+
+    impl TestResponse {
+        pub fn header(&self, name: &str) -> Option<&str> {
+            self.headers
+                .iter()
+                .find(|(field, _)| field.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.as_str())
+        }
+    }
+
+    /// Sends a single request over a new connection and reads the whole response.
+    pub(crate) fn request(
+        addr: SocketAddr,
+        method: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> TestResponse {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut head = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n",
+            method,
+            path,
+            addr,
+            body.len()
+        );
+        for (name, value) in headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("\r\n");
+        stream.write_all(head.as_bytes()).unwrap();
+        stream.write_all(body).unwrap();
+
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        let end = response
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .unwrap();
+        let head = String::from_utf8(response[..end].to_vec()).unwrap();
+        let mut lines = head.split("\r\n");
+        let status = lines.next().unwrap().split(' ').nth(1).unwrap();
+
+        TestResponse {
+            status: status.parse().unwrap(),
+            headers: lines
+                .filter_map(|line| line.split_once(':'))
+                .map(|(name, value)| (name.to_string(), value.trim().to_string()))
+                .collect(),
+            body: response[end + 4..].to_vec(),
+        }
+    }
+
+    #[test]
+    fn urls_and_ranges_should_be_parsed() {
+        assert_eq!(
+            url_path("/a%20b/c.txt?x=1").unwrap(),
+            Path::new("/a b/c.txt")
+        );
+        assert_eq!(href(Path::new("a b/c#.txt"), false), "/a%20b/c%23.txt");
+        assert_eq!(href(Path::new("dir"), true), "/dir/");
+        assert_eq!(href(Path::new("/"), true), "/");
+
+        assert_eq!(parse_range("bytes=0-9", 100), Some(0..10));
+        assert_eq!(parse_range("bytes=90-", 100), Some(90..100));
+        assert_eq!(parse_range("bytes=90-200", 100), Some(90..100));
+        assert_eq!(parse_range("bytes=-10", 100), Some(90..100));
+        assert!(parse_range("bytes=100-", 100).unwrap().is_empty());
+        assert_eq!(parse_range("bytes=0-1,5-6", 100), None);
+        assert_eq!(parse_range("bytes=5-1", 100), None);
+        assert_eq!(parse_range("items=0-1", 100), None);
+    }
+}
@@ -0,0 +1,449 @@
+/// A WebDAV server giving access to an encrypted directory by plaintext paths, like `rclone serve webdav`.
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use std::io::{self, Error, ErrorKind, Result};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::Path;
+use std::thread;
+use subtle::ConstantTimeEq;
+use tiny_http::{Method, Request, Response, ResponseBox, Server};
+
+use super::{
+    error_response, escape_xml, etag, file_response, header, header_value, href, http_date,
+    is_root, status, url_path,
+};
+use crate::cryptfs::{CryptFs, Metadata};
+
+const ALLOW: &str = "OPTIONS, GET, HEAD, PUT, DELETE, MKCOL, MOVE, PROPFIND";
+
+/// Serves a [`CryptFs`](CryptFs) over WebDAV (class 1, without locking), decrypting names and
+/// data on the fly.
+///
+/// Supports `PROPFIND` with plaintext sizes, `GET` and `HEAD` with single byte ranges, `PUT`,
+/// `DELETE`, `MKCOL` and `MOVE`. Directories can't be read with `GET`.
+pub struct WebDavServer {
+    server: Server,
+    fs: CryptFs,
+    /// Expected `user:password` of basic authentication.
+    credentials: Option<String>,
+}
+
+impl WebDavServer {
+    /// Listens on `addr`, serving `fs`. Use port 0 to pick a free port.
+    pub fn bind(addr: impl ToSocketAddrs, fs: CryptFs) -> Result<Self> {
+        let server = Server::http(addr).map_err(Error::other)?;
+        Ok(Self {
+            server,
+            fs,
+            credentials: None,
+        })
+    }
+
+    /// Requires clients to log in with HTTP basic authentication.
+    pub fn with_basic_auth(mut self, user: &str, password: &str) -> Self {
+        self.credentials = Some(format!("{}:{}", user, password));
+        self
+    }
+
+    /// Returns the address the server listens on.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.server.server_addr().to_ip()
+    }
+
+    /// Serves requests, each on its own thread, until [`stop()`](WebDavServer::stop) is called.
+    pub fn serve(&self) {
+        thread::scope(|scope| {
+            for request in self.server.incoming_requests() {
+                scope.spawn(move || self.handle(request));
+            }
+        });
+    }
+
+    /// Makes [`serve()`](WebDavServer::serve) return once the requests being served are done.
+    pub fn stop(&self) {
+        self.server.unblock();
+    }
+
+    /// Answers a single request.
+    pub fn handle(&self, mut request: Request) {
+        let response = if self.authorized(&request) {
+            self.respond(&mut request).unwrap_or_else(error_response)
+        } else {
+            status(401).with_header(header(
+                "WWW-Authenticate",
+                "Basic realm=\"rclone-crypt\", charset=\"UTF-8\"",
+            ))
+        };
+
+        // Failing to send the response means the client is gone, so there's nobody to tell
+        let _ = request.respond(response);
+    }
+
+    fn authorized(&self, request: &Request) -> bool {
+        let expected = match &self.credentials {
+            Some(credentials) => credentials,
+            None => return true,
+        };
+
+        header_value(request, "Authorization")
+            .and_then(|value| value.strip_prefix("Basic "))
+            .and_then(|encoded| STANDARD.decode(encoded.trim()).ok())
+            .is_some_and(|given| given.ct_eq(expected.as_bytes()).into())
+    }
+
+    fn respond(&self, request: &mut Request) -> Result<ResponseBox> {
+        let path = url_path(request.url())?;
+
+        match request.method().clone() {
+            Method::Options => Ok(status(200)
+                .with_header(header("DAV", "1"))
+                .with_header(header("Allow", ALLOW))),
+            Method::Get | Method::Head => self.get(&path, request),
+            Method::Put => self.put(&path, request),
+            Method::Delete => self.delete(&path),
+            Method::NonStandard(method) if method == "PROPFIND" => self.propfind(&path, request),
+            Method::NonStandard(method) if method == "MKCOL" => self.mkcol(&path, request),
+            Method::NonStandard(method) if method == "MOVE" => self.rename(&path, request),
+            _ => Ok(status(405).with_header(header("Allow", ALLOW))),
+        }
+    }
+
+    fn get(&self, path: &Path, request: &Request) -> Result<ResponseBox> {
+        let metadata = self.fs.metadata(path)?;
+        if metadata.is_dir {
+            return Ok(status(405).with_header(header("Allow", ALLOW)));
+        }
+        file_response(&self.fs, path, &metadata, request)
+    }
+
+    fn put(&self, path: &Path, request: &mut Request) -> Result<ResponseBox> {
+        let existed = match self.fs.metadata(path) {
+            Ok(metadata) if metadata.is_dir => return Ok(status(405)),
+            Ok(_) => true,
+            Err(e) if e.kind() == ErrorKind::NotFound => false,
+            Err(e) => return Err(e),
+        };
+        if !self.parent_exists(path)? {
+            return Ok(status(409));
+        }
+
+        // The existing file is only replaced once the whole upload is written
+        let mut file = self.fs.create(path)?;
+        let copied = io::copy(request.as_reader(), &mut file)?;
+        // The body ends early if the client goes away
+        if request
+            .body_length()
+            .is_some_and(|len| copied != len as u64)
+        {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "The upload ended before its Content-Length",
+            ));
+        }
+        file.finish()?;
+
+        Ok(status(if existed { 204 } else { 201 }))
+    }
+
+    fn delete(&self, path: &Path) -> Result<ResponseBox> {
+        if is_root(path) {
+            return Ok(status(403));
+        }
+
+        if self.fs.metadata(path)?.is_dir {
+            self.fs.remove_dir_all(path)?;
+        } else {
+            self.fs.remove_file(path)?;
+        }
+        Ok(status(204))
+    }
+
+    fn mkcol(&self, path: &Path, request: &Request) -> Result<ResponseBox> {
+        if request.body_length().unwrap_or(0) > 0 {
+            return Ok(status(415));
+        }
+
+        match self.fs.metadata(path) {
+            Ok(_) => return Ok(status(405)),
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        if !self.parent_exists(path)? {
+            return Ok(status(409));
+        }
+
+        self.fs.create_dir(path)?;
+        Ok(status(201))
+    }
+
+    fn rename(&self, path: &Path, request: &Request) -> Result<ResponseBox> {
+        let destination = match header_value(request, "Destination") {
+            // Destinations are absolute URLs, but some clients send only the path
+            Some(destination) => match destination.split_once("://") {
+                Some((_, rest)) => url_path(rest.find('/').map_or("/", |start| &rest[start..]))?,
+                None => url_path(destination)?,
+            },
+            None => return Ok(status(400)),
+        };
+        let overwrite = header_value(request, "Overwrite") != Some("F");
+
+        if is_root(path) || is_root(&destination) {
+            return Ok(status(403));
+        }
+        let source = self.fs.metadata(path)?;
+        // Replacing an ancestor would delete the source, and a directory can't move into itself
+        if destination.starts_with(path) || path.starts_with(&destination) {
+            return Ok(status(403));
+        }
+
+        let existed = match self.fs.metadata(&destination) {
+            Ok(_) if !overwrite => return Ok(status(412)),
+            Ok(metadata) => {
+                if metadata.is_dir {
+                    self.fs.remove_dir_all(&destination)?;
+                } else if source.is_dir {
+                    self.fs.remove_file(&destination)?;
+                }
+                true
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => false,
+            Err(e) => return Err(e),
+        };
+        if !self.parent_exists(&destination)? {
+            return Ok(status(409));
+        }
+
+        self.fs.rename(path, &destination)?;
+        Ok(status(if existed { 204 } else { 201 }))
+    }
+
+    fn propfind(&self, path: &Path, request: &Request) -> Result<ResponseBox> {
+        let metadata = self.fs.metadata(path)?;
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+        xml.push_str("<D:multistatus xmlns:D=\"DAV:\">\n");
+        push_response(&mut xml, path, &name, &metadata);
+        // Depth: infinity is served as 1, like many servers do to keep responses bounded
+        if metadata.is_dir && header_value(request, "Depth").map(str::trim) != Some("0") {
+            for entry in self.fs.read_dir(path)? {
+                push_response(&mut xml, &entry.path, &entry.name, &entry.metadata);
+            }
+        }
+        xml.push_str("</D:multistatus>\n");
+
+        Ok(Response::from_string(xml)
+            .with_status_code(207)
+            .with_header(header("Content-Type", "application/xml; charset=utf-8"))
+            .boxed())
+    }
+
+    fn parent_exists(&self, path: &Path) -> Result<bool> {
+        let parent = match path.parent() {
+            Some(parent) => parent,
+            None => return Ok(false),
+        };
+
+        match self.fs.metadata(parent) {
+            Ok(metadata) => Ok(metadata.is_dir),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Appends the `PROPFIND` response of a single file or directory.
+fn push_response(xml: &mut String, path: &Path, name: &str, metadata: &Metadata) {
+    xml.push_str("<D:response><D:href>");
+    xml.push_str(&escape_xml(&href(path, metadata.is_dir)));
+    xml.push_str("</D:href><D:propstat><D:prop>");
+    xml.push_str(&format!(
+        "<D:displayname>{}</D:displayname>",
+        escape_xml(name)
+    ));
+    if metadata.is_dir {
+        xml.push_str("<D:resourcetype><D:collection/></D:resourcetype>");
+    } else {
+        xml.push_str(&format!(
+            "<D:resourcetype/><D:getcontentlength>{}</D:getcontentlength>",
+            metadata.len
+        ));
+    }
+    if let Some(modified) = metadata.modified {
+        xml.push_str(&format!(
+            "<D:getlastmodified>{}</D:getlastmodified>",
+            http_date(modified)
+        ));
+    }
+    if !metadata.is_dir {
+        xml.push_str(&format!(
+            "<D:getetag>{}</D:getetag>",
+            escape_xml(&etag(metadata))
+        ));
+    }
+    xml.push_str("</D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>\n");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WebDavServer;
+    use crate::cipher::Cipher;
+    use crate::cryptfs::CryptFs;
+    use crate::server::tests::request;
+    use std::io::{Read, Write};
+    use std::net::{Shutdown, TcpStream};
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn webdav_server_should_serve_plaintext() {
+        let root = tempfile::tempdir().unwrap();
+        let cipher = Cipher::new("test", Some("salt")).unwrap();
+        let fs = CryptFs::new(root.path(), cipher.clone()).unwrap();
+        let server = Arc::new(WebDavServer::bind("127.0.0.1:0", fs).unwrap());
+        let addr = server.local_addr().unwrap();
+        let serving = thread::spawn({
+            let server = server.clone();
+            move || server.serve()
+        });
+        let data: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+
+        assert_eq!(request(addr, "MKCOL", "/my%20dir", &[], b"").status, 201);
+        assert_eq!(request(addr, "MKCOL", "/my%20dir", &[], b"").status, 405);
+        assert_eq!(request(addr, "PUT", "/x/a.bin", &[], &data).status, 409);
+        assert_eq!(
+            request(addr, "PUT", "/my%20dir/a.bin", &[], &data).status,
+            201
+        );
+        assert!(root
+            .path()
+            .join(cipher.encrypt_path(Path::new("my dir/a.bin")).unwrap())
+            .is_file());
+
+        let response = request(addr, "GET", "/my%20dir/a.bin", &[], b"");
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("Content-Length"), Some("100000"));
+        assert_eq!(response.body, data);
+        let response = request(
+            addr,
+            "GET",
+            "/my%20dir/a.bin",
+            &[("Range", "bytes=65530-65545")],
+            b"",
+        );
+        assert_eq!(response.status, 206);
+        assert_eq!(
+            response.header("Content-Range"),
+            Some("bytes 65530-65545/100000")
+        );
+        assert_eq!(response.body, data[65530..65546]);
+        let response = request(
+            addr,
+            "GET",
+            "/my%20dir/a.bin",
+            &[("Range", "bytes=100000-")],
+            b"",
+        );
+        assert_eq!(response.status, 416);
+
+        let response = request(addr, "PROPFIND", "/", &[("Depth", "1")], b"");
+        assert_eq!(response.status, 207);
+        let xml = String::from_utf8(response.body).unwrap();
+        assert!(xml.contains("<D:href>/my%20dir/</D:href>"));
+        assert!(xml.contains("<D:displayname>my dir</D:displayname>"));
+        let response = request(addr, "PROPFIND", "/my%20dir/a.bin", &[("Depth", "0")], b"");
+        let xml = String::from_utf8(response.body).unwrap();
+        assert!(xml.contains("<D:getcontentlength>100000</D:getcontentlength>"));
+
+        for (from, to) in [
+            ("/my%20dir/a.bin", "/my%20dir"),
+            ("/my%20dir", "/my%20dir/sub"),
+        ] {
+            let destination = format!("http://{}{}", addr, to);
+            let moved = request(addr, "MOVE", from, &[("Destination", &destination)], b"");
+            assert_eq!(moved.status, 403);
+        }
+        assert_eq!(request(addr, "GET", "/my%20dir/a.bin", &[], b"").body, data);
+
+        let destination = format!("http://{}/b.bin", addr);
+        let moved = request(
+            addr,
+            "MOVE",
+            "/my%20dir/a.bin",
+            &[("Destination", &destination)],
+            b"",
+        );
+        assert_eq!(moved.status, 201);
+        assert_eq!(
+            request(addr, "GET", "/my%20dir/a.bin", &[], b"").status,
+            404
+        );
+        assert_eq!(request(addr, "GET", "/b.bin", &[], b"").body, data);
+        assert_eq!(request(addr, "DELETE", "/b.bin", &[], b"").status, 204);
+        assert_eq!(request(addr, "DELETE", "/my%20dir", &[], b"").status, 204);
+        assert_eq!(request(addr, "GET", "/%2E%2E/x", &[], b"").status, 400);
+        assert_eq!(std::fs::read_dir(root.path()).unwrap().count(), 0);
+
+        server.stop();
+        serving.join().unwrap();
+    }
+
+    #[test]
+    fn webdav_server_should_keep_files_on_failed_uploads() {
+        let root = tempfile::tempdir().unwrap();
+        let fs = CryptFs::new(root.path(), Cipher::new("test", None).unwrap()).unwrap();
+        let server = WebDavServer::bind("127.0.0.1:0", fs).unwrap();
+        let addr = server.local_addr().unwrap();
+
+        thread::scope(|scope| {
+            scope.spawn(|| server.serve());
+            assert_eq!(request(addr, "PUT", "/a.txt", &[], b"old").status, 201);
+
+            // The client goes away in the middle of the body
+            let mut stream = TcpStream::connect(addr).unwrap();
+            let head = "PUT /a.txt HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\
+                Content-Length: 100000\r\n\r\n";
+            stream.write_all(head.as_bytes()).unwrap();
+            stream.write_all(&[1u8; 70_000]).unwrap();
+            stream.shutdown(Shutdown::Write).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            assert!(response.starts_with("HTTP/1.1 500"), "{}", response);
+
+            // The old file is still there, and the server still serves
+            assert_eq!(request(addr, "GET", "/a.txt", &[], b"").body, b"old");
+            assert_eq!(std::fs::read_dir(root.path()).unwrap().count(), 1);
+
+            server.stop();
+        });
+    }
+
+    #[test]
+    fn webdav_server_should_require_basic_auth() {
+        let root = tempfile::tempdir().unwrap();
+        let fs = CryptFs::new(root.path(), Cipher::new("test", None).unwrap()).unwrap();
+        let server = WebDavServer::bind("127.0.0.1:0", fs)
+            .unwrap()
+            .with_basic_auth("user", "secret");
+        let addr = server.local_addr().unwrap();
+
+        thread::scope(|scope| {
+            scope.spawn(|| server.serve());
+
+            let response = request(addr, "PROPFIND", "/", &[], b"");
+            assert_eq!(response.status, 401);
+            assert!(response.header("WWW-Authenticate").is_some());
+            // "user:wrong" and "user:secret"
+            let wrong = [("Authorization", "Basic dXNlcjp3cm9uZw==")];
+            assert_eq!(request(addr, "PROPFIND", "/", &wrong, b"").status, 401);
+            let right = [("Authorization", "Basic dXNlcjpzZWNyZXQ=")];
+            assert_eq!(request(addr, "PROPFIND", "/", &right, b"").status, 207);
+
+            server.stop();
+        });
+    }
+}
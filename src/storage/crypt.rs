@@ -0,0 +1,234 @@
+use std::io::{Error, ErrorKind, Result};
+use std::ops::Range;
+
+use super::{ObjectMeta, Storage};
+use crate::cipher::Cipher;
+use crate::decrypter::Decrypter;
+use crate::encrypter::Encrypter;
+use crate::{decrypted_size, encrypted_range, FILE_HEADER_SIZE};
+
+/// A [`Storage`](Storage) encrypting the keys and data of another one, like an rclone crypt
+/// remote over it.
+///
+/// Ranged reads only fetch and decrypt the blocks holding the requested range. If the cipher
+/// doesn't encrypt data, only keys are encrypted.
+/// Objects whose key doesn't decrypt, or whose size isn't a valid encrypted size, are left
+/// out of listings.
+#[derive(Debug)]
+pub struct CryptStorage<S: Storage> {
+    inner: S,
+    cipher: Cipher,
+}
+
+impl<S: Storage> CryptStorage<S> {
+    pub fn new(inner: S, cipher: Cipher) -> Self {
+        Self { inner, cipher }
+    }
+
+    /// Returns the wrapped storage.
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// Returns the key of the encrypted object backing `key`.
+    pub fn encrypt_key(&self, key: &str) -> Result<String> {
+        super::check_key(key)?;
+        self.cipher
+            .encrypt_file_name(key)
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e.to_string()))
+    }
+
+    fn decrypter(&self, header: &[u8]) -> Result<Decrypter> {
+        Decrypter::new(&self.cipher.get_file_key(), header)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))
+    }
+
+    /// Returns the plaintext size of an object of `size` bytes in the inner storage.
+    fn plaintext_size(&self, size: u64) -> Option<u64> {
+        if self.cipher.encrypts_data() {
+            decrypted_size(size)
+        } else {
+            Some(size)
+        }
+    }
+
+    fn decrypt_meta(&self, object: ObjectMeta) -> Option<ObjectMeta> {
+        Some(ObjectMeta {
+            key: self.cipher.decrypt_file_name(&object.key).ok()?,
+            size: self.plaintext_size(object.size)?,
+            modified: object.modified,
+        })
+    }
+}
+
+impl<S: Storage> Storage for CryptStorage<S> {
+    fn list(&self, prefix: &str) -> Result<Vec<ObjectMeta>> {
+        // Only whole segments can be encrypted, so list the deepest directory the prefix
+        // names completely, and filter by the rest of it
+        let encrypted_prefix = match prefix.rfind('/') {
+            Some(end) => self.encrypt_key(&prefix[..end])? + "/",
+            None => String::new(),
+        };
+
+        let mut objects: Vec<_> = self
+            .inner
+            .list(&encrypted_prefix)?
+            .into_iter()
+            .filter_map(|object| self.decrypt_meta(object))
+            .filter(|object| object.key.starts_with(prefix))
+            .collect();
+        objects.sort_by(|a, b| a.key.cmp(&b.key));
+        Ok(objects)
+    }
+
+    fn stat(&self, key: &str) -> Result<ObjectMeta> {
+        let object = self.inner.stat(&self.encrypt_key(key)?)?;
+        let size = self.plaintext_size(object.size).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("{} bytes is not a valid encrypted size", object.size),
+            )
+        })?;
+
+        Ok(ObjectMeta {
+            key: key.to_string(),
+            size,
+            modified: object.modified,
+        })
+    }
+
+    fn get(&self, key: &str, range: Option<Range<u64>>) -> Result<Vec<u8>> {
+        let encrypted_key = self.encrypt_key(key)?;
+        if !self.cipher.encrypts_data() {
+            return self.inner.get(&encrypted_key, range);
+        }
+        let range = match range {
+            Some(range) if range.is_empty() => {
+                self.inner.stat(&encrypted_key)?;
+                return Ok(Vec::new());
+            }
+            Some(range) => range,
+            None => 0..u64::MAX,
+        };
+
+        let (first_block, blocks) = encrypted_range(range.clone());
+        let (header, data) = if first_block == 0 {
+            let mut data = self.inner.get(&encrypted_key, Some(0..blocks.end))?;
+            let blocks = data.split_off(FILE_HEADER_SIZE.min(data.len()));
+            (data, blocks)
+        } else {
+            let header = self
+                .inner
+                .get(&encrypted_key, Some(0..FILE_HEADER_SIZE as u64))?;
+            (header, self.inner.get(&encrypted_key, Some(blocks))?)
+        };
+
+        self.decrypter(&header)?
+            .decrypt_range(first_block, &data, range)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))
+    }
+
+    fn put(&self, key: &str, data: &[u8]) -> Result<()> {
+        if !self.cipher.encrypts_data() {
+            return self.inner.put(&self.encrypt_key(key)?, data);
+        }
+        let encrypter = Encrypter::new(&self.cipher.get_file_key()).map_err(Error::other)?;
+        self.inner
+            .put(&self.encrypt_key(key)?, &encrypter.encrypt_all(data))
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        self.inner.delete(&self.encrypt_key(key)?)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        self.inner
+            .rename(&self.encrypt_key(from)?, &self.encrypt_key(to)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CryptStorage;
+    use crate::cipher::Cipher;
+    use crate::storage::tests::check_storage;
+    use crate::storage::{MemoryStorage, Storage};
+    use crate::{encrypted_size, BLOCK_DATA_SIZE};
+    use std::io::ErrorKind;
+
+    fn storage() -> CryptStorage<MemoryStorage> {
+        let cipher = Cipher::new("test", Some("salt")).unwrap();
+        CryptStorage::new(MemoryStorage::new(), cipher)
+    }
+
+    #[test]
+    fn crypt_storage_should_store_objects() {
+        check_storage(&storage());
+    }
+
+    #[test]
+    fn crypt_storage_should_store_objects_without_data_encryption() {
+        let cipher = Cipher::new("test", Some("salt"))
+            .unwrap()
+            .with_data_encryption(false);
+        let storage = CryptStorage::new(MemoryStorage::new(), cipher);
+        check_storage(&storage);
+
+        storage.put("a.txt", b"hello").unwrap();
+        let encrypted_key = storage.encrypt_key("a.txt").unwrap();
+        assert_eq!(storage.inner().get(&encrypted_key, None).unwrap(), b"hello");
+        assert_eq!(storage.stat("a.txt").unwrap().size, 5);
+        assert_eq!(storage.get("a.txt", Some(1..3)).unwrap(), b"el");
+    }
+
+    #[test]
+    fn crypt_storage_should_read_ranges() {
+        let storage = storage();
+        let data: Vec<u8> = (0..3 * BLOCK_DATA_SIZE + 100).map(|i| i as u8).collect();
+        storage.put("dir/data.bin", &data).unwrap();
+
+        let encrypted_key = storage.encrypt_key("dir/data.bin").unwrap();
+        let encrypted = storage.inner().stat(&encrypted_key).unwrap();
+        assert_eq!(encrypted.size, encrypted_size(data.len() as u64));
+        assert_eq!(
+            storage.stat("dir/data.bin").unwrap().size,
+            data.len() as u64
+        );
+
+        let len = data.len() as u64;
+        let block = BLOCK_DATA_SIZE as u64;
+        for range in [
+            0..10,
+            block - 5..block + 5,
+            2 * block..len,
+            block + 1..len + 100,
+            10..10,
+        ] {
+            let expected = &data[range.start as usize..range.end.min(len) as usize];
+            assert_eq!(
+                storage.get("dir/data.bin", Some(range.clone())).unwrap(),
+                expected,
+                "{:?}",
+                range
+            );
+        }
+        assert_eq!(storage.get("dir/data.bin", None).unwrap(), data);
+        assert!(storage
+            .get("dir/data.bin", Some(len + 10..len + 20))
+            .unwrap()
+            .is_empty());
+
+        // Corrupt the second block
+        let mut encrypted = storage.inner().get(&encrypted_key, None).unwrap();
+        encrypted[70_000] ^= 1;
+        storage.inner().put(&encrypted_key, &encrypted).unwrap();
+        assert!(storage.get("dir/data.bin", Some(0..10)).is_ok());
+        assert_eq!(
+            storage
+                .get("dir/data.bin", Some(block..block + 1))
+                .unwrap_err()
+                .kind(),
+            ErrorKind::InvalidData
+        );
+    }
+}
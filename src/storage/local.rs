@@ -0,0 +1,150 @@
+use std::fs::{self, File};
+use std::io::{ErrorKind, Read, Result, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use super::{check_key, ObjectMeta, Storage};
+
+/// A [`Storage`](Storage) keeping objects as files below a local directory.
+///
+/// Directories are created as needed, and removed again once they are empty.
+#[derive(Clone, Debug)]
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    fn path(&self, key: &str) -> Result<PathBuf> {
+        check_key(key)?;
+        Ok(key
+            .split('/')
+            .fold(self.root.clone(), |path, segment| path.join(segment)))
+    }
+
+    /// Adds every file below `dir`, which holds the keys starting with `dir_key`, to `objects`.
+    fn list_dir(
+        &self,
+        dir: &Path,
+        dir_key: &str,
+        prefix: &str,
+        objects: &mut Vec<ObjectMeta>,
+    ) -> Result<()> {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        for entry in entries {
+            let entry = entry?;
+            let name = match entry.file_name().into_string() {
+                Ok(name) => name,
+                // Can't be addressed by a key
+                Err(_) => continue,
+            };
+            let key = format!("{}{}", dir_key, name);
+
+            let metadata = fs::metadata(entry.path())?;
+            if metadata.is_dir() {
+                let dir_key = key + "/";
+                if dir_key.starts_with(prefix) || prefix.starts_with(&dir_key) {
+                    self.list_dir(&entry.path(), &dir_key, prefix, objects)?;
+                }
+            } else if key.starts_with(prefix) {
+                objects.push(ObjectMeta {
+                    key,
+                    size: metadata.len(),
+                    modified: metadata.modified().ok(),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Removes the empty parent directories of `path` below the root.
+    fn remove_empty_parents(&self, path: &Path) {
+        let mut dir = path.parent();
+        while let Some(path) = dir.filter(|dir| *dir != self.root) {
+            if fs::remove_dir(path).is_err() {
+                break;
+            }
+            dir = path.parent();
+        }
+    }
+}
+
+impl Storage for LocalStorage {
+    fn list(&self, prefix: &str) -> Result<Vec<ObjectMeta>> {
+        // Start at the deepest directory the prefix names completely
+        let (dir_key, dir) = match prefix.rfind('/') {
+            Some(end) => (&prefix[..=end], self.path(&prefix[..end])?),
+            None => ("", self.root.clone()),
+        };
+
+        let mut objects = Vec::new();
+        self.list_dir(&dir, dir_key, prefix, &mut objects)?;
+        objects.sort_by(|a, b| a.key.cmp(&b.key));
+        Ok(objects)
+    }
+
+    fn stat(&self, key: &str) -> Result<ObjectMeta> {
+        let metadata = fs::metadata(self.path(key)?)?;
+        if metadata.is_dir() {
+            return Err(super::not_found(key));
+        }
+        Ok(ObjectMeta {
+            key: key.to_string(),
+            size: metadata.len(),
+            modified: metadata.modified().ok(),
+        })
+    }
+
+    fn get(&self, key: &str, range: Option<Range<u64>>) -> Result<Vec<u8>> {
+        let mut file = File::open(self.path(key)?)?;
+        let mut data = Vec::new();
+
+        match range {
+            Some(range) => {
+                file.seek(SeekFrom::Start(range.start))?;
+                file.take(range.end.saturating_sub(range.start))
+                    .read_to_end(&mut data)?;
+            }
+            None => {
+                file.read_to_end(&mut data)?;
+            }
+        }
+        Ok(data)
+    }
+
+    fn put(&self, key: &str, data: &[u8]) -> Result<()> {
+        let path = self.path(key)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, data)
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        let path = self.path(key)?;
+        fs::remove_file(&path)?;
+        self.remove_empty_parents(&path);
+        Ok(())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        let from = self.path(from)?;
+        let to = self.path(to)?;
+        fs::metadata(&from)?;
+        if let Some(parent) = to.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(&from, to)?;
+        self.remove_empty_parents(&from);
+        Ok(())
+    }
+}
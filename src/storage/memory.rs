@@ -0,0 +1,76 @@
+use std::collections::BTreeMap;
+use std::io::Result;
+use std::ops::Range;
+use std::sync::RwLock;
+use std::time::SystemTime;
+
+use super::{check_key, not_found, slice_range, ObjectMeta, Storage};
+
+/// A [`Storage`](Storage) keeping objects in memory, e.g. for tests.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    objects: RwLock<BTreeMap<String, (Vec<u8>, SystemTime)>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+fn meta(key: &str, data: &[u8], modified: SystemTime) -> ObjectMeta {
+    ObjectMeta {
+        key: key.to_string(),
+        size: data.len() as u64,
+        modified: Some(modified),
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn list(&self, prefix: &str) -> Result<Vec<ObjectMeta>> {
+        let objects = self.objects.read().unwrap();
+        Ok(objects
+            .range(prefix.to_string()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, (data, modified))| meta(key, data, *modified))
+            .collect())
+    }
+
+    fn stat(&self, key: &str) -> Result<ObjectMeta> {
+        let objects = self.objects.read().unwrap();
+        let (data, modified) = objects.get(key).ok_or_else(|| not_found(key))?;
+        Ok(meta(key, data, *modified))
+    }
+
+    fn get(&self, key: &str, range: Option<Range<u64>>) -> Result<Vec<u8>> {
+        let objects = self.objects.read().unwrap();
+        let (data, _) = objects.get(key).ok_or_else(|| not_found(key))?;
+        Ok(slice_range(data, range).to_vec())
+    }
+
+    fn put(&self, key: &str, data: &[u8]) -> Result<()> {
+        check_key(key)?;
+        self.objects
+            .write()
+            .unwrap()
+            .insert(key.to_string(), (data.to_vec(), SystemTime::now()));
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        self.objects
+            .write()
+            .unwrap()
+            .remove(key)
+            .map(|_| ())
+            .ok_or_else(|| not_found(key))
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        check_key(to)?;
+        let mut objects = self.objects.write().unwrap();
+        let object = objects.remove(from).ok_or_else(|| not_found(from))?;
+        objects.insert(to.to_string(), object);
+        Ok(())
+    }
+}
@@ -0,0 +1,135 @@
+mod crypt;
+mod local;
+mod memory;
+
+use std::io::{Error, ErrorKind, Result};
+use std::ops::Range;
+use std::time::SystemTime;
+
+pub use crypt::CryptStorage;
+pub use local::LocalStorage;
+pub use memory::MemoryStorage;
+
+/// Metadata of an object of a [`Storage`](Storage).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ObjectMeta {
+    pub key: String,
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+}
+
+/// A flat store of objects addressed by `/`-separated keys, like an object store bucket.
+///
+/// Keys are relative, and have neither empty segments nor `.` or `..` segments.
+/// Missing objects are reported as [`ErrorKind::NotFound`](ErrorKind::NotFound) errors.
+pub trait Storage: Send + Sync {
+    /// Lists every object whose key starts with `prefix`, sorted by key.
+    fn list(&self, prefix: &str) -> Result<Vec<ObjectMeta>>;
+
+    fn stat(&self, key: &str) -> Result<ObjectMeta>;
+
+    /// Returns the content of an object, or the bytes of `range` which are within it.
+    fn get(&self, key: &str, range: Option<Range<u64>>) -> Result<Vec<u8>>;
+
+    /// Creates or replaces an object.
+    fn put(&self, key: &str, data: &[u8]) -> Result<()>;
+
+    fn delete(&self, key: &str) -> Result<()>;
+
+    /// Moves an object, replacing `to` if it exists.
+    fn rename(&self, from: &str, to: &str) -> Result<()>;
+}
+
+/// Returns an error if `key` isn't a valid key, see [`Storage`](Storage).
+pub(crate) fn check_key(key: &str) -> Result<()> {
+    if key
+        .split('/')
+        .any(|segment| segment.is_empty() || segment == "." || segment == "..")
+    {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("Invalid key {:?}", key),
+        ));
+    }
+    Ok(())
+}
+
+/// Returns the part of `data` within `range`.
+pub(crate) fn slice_range(data: &[u8], range: Option<Range<u64>>) -> &[u8] {
+    match range {
+        Some(range) => {
+            let len = data.len() as u64;
+            let start = range.start.min(len);
+            let end = range.end.min(len).max(start);
+            &data[start as usize..end as usize]
+        }
+        None => data,
+    }
+}
+
+pub(crate) fn not_found(key: &str) -> Error {
+    Error::new(ErrorKind::NotFound, format!("{} not found", key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LocalStorage, MemoryStorage, Storage};
+    use std::io::ErrorKind;
+
+    /// Checks the behavior every [`Storage`](Storage) must have.
+    pub(crate) fn check_storage(storage: &dyn Storage) {
+        storage.put("a/b/c.txt", b"hello world").unwrap();
+        storage.put("a/d.txt", b"").unwrap();
+        storage.put("ab.txt", b"x").unwrap();
+
+        let keys = |prefix| {
+            storage
+                .list(prefix)
+                .unwrap()
+                .into_iter()
+                .map(|object| object.key)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(keys(""), ["a/b/c.txt", "a/d.txt", "ab.txt"]);
+        assert_eq!(keys("a/"), ["a/b/c.txt", "a/d.txt"]);
+        assert_eq!(keys("a"), ["a/b/c.txt", "a/d.txt", "ab.txt"]);
+        assert!(keys("x/").is_empty());
+
+        assert_eq!(storage.stat("a/b/c.txt").unwrap().size, 11);
+        assert_eq!(storage.get("a/b/c.txt", None).unwrap(), b"hello world");
+        assert_eq!(storage.get("a/b/c.txt", Some(6..100)).unwrap(), b"world");
+        assert!(storage.get("a/b/c.txt", Some(20..30)).unwrap().is_empty());
+
+        storage.rename("a/b/c.txt", "e/f.txt").unwrap();
+        assert_eq!(storage.get("e/f.txt", Some(0..5)).unwrap(), b"hello");
+        assert_eq!(
+            storage.stat("a/b/c.txt").unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+
+        storage.delete("e/f.txt").unwrap();
+        assert_eq!(
+            storage.get("e/f.txt", None).unwrap_err().kind(),
+            ErrorKind::NotFound
+        );
+        assert_eq!(keys(""), ["a/d.txt", "ab.txt"]);
+
+        assert_eq!(
+            storage.put("../x", b"").unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+    }
+
+    #[test]
+    fn memory_storage_should_store_objects() {
+        check_storage(&MemoryStorage::new());
+    }
+
+    #[test]
+    fn local_storage_should_store_objects() {
+        let root = tempfile::tempdir().unwrap();
+        check_storage(&LocalStorage::new(root.path()));
+        // Directories left empty by deletes and renames are removed
+        assert!(!root.path().join("e").exists());
+    }
+}
@@ -0,0 +1,372 @@
+use super::macros::into_io_error;
+use crate::{cipher::Cipher, decrypter::Decrypter, encrypter::Encrypter};
+use crate::{BLOCK_DATA_SIZE, BLOCK_SIZE, FILE_HEADER_SIZE, FILE_MAGIC};
+use bytes::{BufMut, Bytes, BytesMut};
+use std::io::{Error, ErrorKind, Result};
+use tokio_util::codec::{Decoder, Encoder};
+
+/// Marker item which tells an [`EncryptCodec`](EncryptCodec) that the plaintext has ended.
+///
+/// Sending it flushes the final (possibly short) block. A `FramedWrite` using an
+/// [`EncryptCodec`](EncryptCodec) accepts both [`Bytes`](Bytes) and [`Finish`](Finish) items.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Finish;
+
+/// A [`tokio_util::codec::Encoder`](Encoder) which turns plaintext chunks into rclone-crypt frames.
+///
+/// # How it works
+/// Plaintext chunks of any size are buffered internally. Every time the buffer holds
+/// [`BLOCK_DATA_SIZE`](BLOCK_DATA_SIZE) bytes, a [`BLOCK_SIZE`](BLOCK_SIZE) ciphertext block is written
+/// into the destination buffer. The file header is written in front of the first block.
+///
+/// Once all the plaintext has been encoded, a [`Finish`](Finish) item must be encoded to flush
+/// the final block. Encoding [`Finish`](Finish) on an empty stream produces just the file header,
+/// like rclone does for empty files. Encoding more [`Bytes`](Bytes) after that is an error.
+///
+/// If the cipher doesn't encrypt data (see [`Cipher::with_data_encryption()`](Cipher::with_data_encryption)),
+/// chunks are passed through as-is, without a header.
+pub struct EncryptCodec {
+    /// `None` if data isn't encrypted.
+    encrypter: Option<Encrypter>,
+    block_id: u64,
+    header_written: bool,
+    finished: bool,
+    inner_buf: Vec<u8>,
+}
+
+impl EncryptCodec {
+    /// Creates a new instance which will encrypt data using the given `password` and `salt`.
+    /// Internally a [`Cipher`](Cipher) and [`Encrypter`](Encrypter) are automatically created.
+    ///
+    /// # Errors
+    /// The returned [`Result`](Result) could only contain an [`Error`](Error) if an instance
+    /// of [`Cipher`](Cipher) or [`Encrypter`](Encrypter) could not be created.
+    pub fn new(password: &str, salt: Option<&str>) -> Result<Self> {
+        let cipher = into_io_error!(Cipher::new(password, salt), "Failed to create Cipher")?;
+        Self::new_with_cipher(cipher)
+    }
+
+    /// Same as [`new()`](Self::new) but takes a cipher instead of a password and a salt.
+    pub fn new_with_cipher(cipher: Cipher) -> Result<Self> {
+        let encrypter = if cipher.encrypts_data() {
+            Some(into_io_error!(
+                Encrypter::new(&cipher.get_file_key()),
+                "Failed to create Encrypter"
+            )?)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            encrypter,
+            block_id: 0,
+            header_written: false,
+            finished: false,
+            inner_buf: Vec::with_capacity(BLOCK_DATA_SIZE),
+        })
+    }
+
+    fn encrypter(&self) -> &Encrypter {
+        self.encrypter
+            .as_ref()
+            .expect("encrypter is only missing without data encryption")
+    }
+
+    fn write_header(&mut self, dst: &mut BytesMut) {
+        if !self.header_written {
+            dst.put_slice(&self.encrypter().get_file_header());
+            self.header_written = true;
+        }
+    }
+
+    fn write_block(&mut self, dst: &mut BytesMut) {
+        let encrypted = self
+            .encrypter()
+            .encrypt_block(self.block_id, &self.inner_buf);
+        dst.put_slice(&encrypted);
+        self.block_id += 1;
+        self.inner_buf.clear();
+    }
+}
+
+impl Encoder<Bytes> for EncryptCodec {
+    type Error = Error;
+
+    fn encode(&mut self, item: Bytes, dst: &mut BytesMut) -> Result<()> {
+        if self.finished {
+            return Err(Error::other("Encoder is already finished"));
+        }
+        if self.encrypter.is_none() {
+            dst.put_slice(&item);
+            return Ok(());
+        }
+        self.write_header(dst);
+
+        let mut item = &item[..];
+        while !item.is_empty() {
+            let take = (BLOCK_DATA_SIZE - self.inner_buf.len()).min(item.len());
+            self.inner_buf.extend_from_slice(&item[..take]);
+            item = &item[take..];
+
+            if self.inner_buf.len() == BLOCK_DATA_SIZE {
+                self.write_block(dst);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Encoder<Finish> for EncryptCodec {
+    type Error = Error;
+
+    fn encode(&mut self, _item: Finish, dst: &mut BytesMut) -> Result<()> {
+        self.finished = true;
+        if self.encrypter.is_none() {
+            return Ok(());
+        }
+        self.write_header(dst);
+
+        if !self.inner_buf.is_empty() {
+            self.write_block(dst);
+        }
+        Ok(())
+    }
+}
+
+/// A [`tokio_util::codec::Decoder`](Decoder) which turns rclone-crypt frames into plaintext chunks.
+///
+/// # How it works
+/// The first [`FILE_HEADER_SIZE`](FILE_HEADER_SIZE) bytes are consumed as the file header and used to
+/// create a [`Decrypter`](Decrypter). After that, every [`BLOCK_SIZE`](BLOCK_SIZE) bytes of ciphertext
+/// are decrypted and emitted as one plaintext chunk. The final, shorter block is decrypted once the
+/// underlying stream reaches its end.
+///
+/// If the cipher doesn't encrypt data (see [`Cipher::with_data_encryption()`](Cipher::with_data_encryption)),
+/// the data is passed through as-is, without a header.
+///
+/// # Notes
+/// A block which fails authentication is reported as an [`ErrorKind::InvalidData`](ErrorKind::InvalidData) error,
+/// and a stream which ends before the end of the header as an [`ErrorKind::UnexpectedEof`](ErrorKind::UnexpectedEof) one.
+pub struct DecryptCodec {
+    cipher: Cipher,
+    decrypter: Option<Decrypter>,
+    block_id: u64,
+}
+
+impl DecryptCodec {
+    /// Creates a new instance which will decrypt data using the given `password` and `salt`.
+    ///
+    /// # Errors
+    /// The returned [`Result`](Result) could only contain an [`Error`](Error) if an instance
+    /// of [`Cipher`](Cipher) could not be created.
+    pub fn new(password: &str, salt: Option<&str>) -> Result<Self> {
+        let cipher = into_io_error!(Cipher::new(password, salt), "Failed to create Cipher")?;
+        Ok(Self::new_with_cipher(cipher))
+    }
+
+    /// Same as [`new()`](Self::new) but takes a cipher instead of a password and a salt.
+    pub fn new_with_cipher(cipher: Cipher) -> Self {
+        Self {
+            cipher,
+            decrypter: None,
+            block_id: 0,
+        }
+    }
+
+    fn decrypt_block(&mut self, block: &[u8]) -> Result<Bytes> {
+        let decrypter = self
+            .decrypter
+            .as_ref()
+            .expect("decrypter is created before any block");
+
+        let decrypted = decrypter
+            .decrypt_block(self.block_id, block)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "Failed to decrypt block"))?;
+        self.block_id += 1;
+
+        Ok(Bytes::from(decrypted))
+    }
+}
+
+impl Decoder for DecryptCodec {
+    type Item = Bytes;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Bytes>> {
+        if !self.cipher.encrypts_data() {
+            if src.is_empty() {
+                return Ok(None);
+            }
+            return Ok(Some(src.split().freeze()));
+        }
+
+        if self.decrypter.is_none() {
+            if src.len() < FILE_HEADER_SIZE {
+                src.reserve(FILE_HEADER_SIZE - src.len());
+                return Ok(None);
+            }
+
+            let header = src.split_to(FILE_HEADER_SIZE);
+            if &header[..FILE_MAGIC.len()] != FILE_MAGIC {
+                return Err(Error::new(ErrorKind::InvalidData, "Bad Rclone header"));
+            }
+
+            let decrypter = into_io_error!(
+                Decrypter::new(&self.cipher.get_file_key(), &header),
+                "Failed to create decrypter"
+            )?;
+            self.decrypter = Some(decrypter);
+        }
+
+        if src.len() < BLOCK_SIZE {
+            src.reserve(BLOCK_SIZE - src.len());
+            return Ok(None);
+        }
+
+        let block = src.split_to(BLOCK_SIZE);
+        self.decrypt_block(&block).map(Some)
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Bytes>> {
+        if let Some(frame) = self.decode(src)? {
+            return Ok(Some(frame));
+        }
+
+        // Even an empty file has a header
+        if self.decrypter.is_none() && self.cipher.encrypts_data() {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "Unable to read header",
+            ));
+        }
+
+        if src.is_empty() {
+            return Ok(None);
+        }
+
+        let block = src.split();
+        self.decrypt_block(&block).map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DecryptCodec, EncryptCodec, Finish};
+    use crate::{cipher::Cipher, stream::EncryptedWriter, BLOCK_DATA_SIZE, FILE_HEADER_SIZE};
+    use bytes::{Bytes, BytesMut};
+    use futures::{executor::block_on, SinkExt, StreamExt, TryStreamExt};
+    use std::io::{ErrorKind, Write};
+    use tokio_util::codec::{Decoder, Encoder, FramedRead, FramedWrite};
+
+    fn test_data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn codec_roundtrip_through_framed_pipelines() {
+        let cipher = Cipher::new("test", Some("test")).unwrap();
+        let data = test_data(BLOCK_DATA_SIZE * 2 + 1234);
+
+        let mut encrypted = Vec::new();
+        block_on(async {
+            let mut sink = FramedWrite::new(
+                &mut encrypted,
+                EncryptCodec::new_with_cipher(cipher.clone()).unwrap(),
+            );
+            for chunk in data.chunks(10_000) {
+                sink.send(Bytes::copy_from_slice(chunk)).await.unwrap();
+            }
+            sink.send(Finish).await.unwrap();
+        });
+
+        let chunks: Vec<Bytes> = block_on(
+            FramedRead::new(&encrypted[..], DecryptCodec::new_with_cipher(cipher)).try_collect(),
+        )
+        .unwrap();
+
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks.concat(), data);
+    }
+
+    #[test]
+    fn codec_decodes_writer_output() {
+        let cipher = Cipher::new("test", Some("test")).unwrap();
+        let data = test_data(BLOCK_DATA_SIZE + 1);
+
+        let mut encrypted = Vec::new();
+        {
+            let mut writer =
+                EncryptedWriter::new_with_cipher(&mut encrypted, cipher.clone()).unwrap();
+            writer.write_all(&data).unwrap();
+        }
+
+        let mut decoder = DecryptCodec::new_with_cipher(cipher);
+        let mut src = BytesMut::from(&encrypted[..]);
+        let mut decrypted = Vec::new();
+        while let Some(chunk) = decoder.decode_eof(&mut src).unwrap() {
+            decrypted.extend_from_slice(&chunk);
+        }
+
+        assert_eq!(decrypted, data);
+    }
+
+    #[test]
+    fn codec_encodes_empty_stream_as_header() {
+        let cipher = Cipher::new("test", Some("test")).unwrap();
+        let mut encoder = EncryptCodec::new_with_cipher(cipher.clone()).unwrap();
+
+        let mut dst = BytesMut::new();
+        encoder.encode(Finish, &mut dst).unwrap();
+
+        assert_eq!(dst.len(), FILE_HEADER_SIZE);
+        let error = encoder
+            .encode(Bytes::from_static(b"late"), &mut dst)
+            .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::Other);
+        assert_eq!(dst.len(), FILE_HEADER_SIZE);
+
+        // The header alone decodes to nothing, but no header at all is a truncated file
+        let mut decoder = DecryptCodec::new_with_cipher(cipher.clone());
+        assert_eq!(decoder.decode_eof(&mut dst).unwrap(), None);
+        let mut decoder = DecryptCodec::new_with_cipher(cipher);
+        let error = decoder.decode_eof(&mut BytesMut::new()).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn codec_passes_data_through_without_data_encryption() {
+        let cipher = Cipher::new("test", Some("test"))
+            .unwrap()
+            .with_data_encryption(false);
+        let mut encoder = EncryptCodec::new_with_cipher(cipher.clone()).unwrap();
+        let mut decoder = DecryptCodec::new_with_cipher(cipher);
+
+        let mut dst = BytesMut::new();
+        encoder
+            .encode(Bytes::from_static(b"hello"), &mut dst)
+            .unwrap();
+        encoder.encode(Finish, &mut dst).unwrap();
+        assert_eq!(&dst[..], b"hello");
+
+        assert_eq!(decoder.decode_eof(&mut dst).unwrap().unwrap(), "hello");
+        assert_eq!(decoder.decode_eof(&mut dst).unwrap(), None);
+    }
+
+    #[test]
+    fn codec_rejects_tampered_block() {
+        let cipher = Cipher::new("test", Some("test")).unwrap();
+        let mut encoder = EncryptCodec::new_with_cipher(cipher.clone()).unwrap();
+
+        let mut dst = BytesMut::new();
+        encoder
+            .encode(Bytes::from_static(b"abc123"), &mut dst)
+            .unwrap();
+        encoder.encode(Finish, &mut dst).unwrap();
+        let last = dst.len() - 1;
+        dst[last] ^= 1;
+
+        let result =
+            block_on(FramedRead::new(&dst[..], DecryptCodec::new_with_cipher(cipher)).next());
+        assert!(matches!(result, Some(Err(_))));
+    }
+}
@@ -1,3 +1,5 @@
+#[cfg(feature = "codec")]
+mod codec;
 mod read;
 mod seekable_write;
 mod write;
@@ -13,6 +15,26 @@ mod macros {
     pub(super) use into_io_error;
 }
 
-pub use read::EncryptedReader;
+use std::io::{ErrorKind, Read, Result};
+
+/// Reads from `inner` until `buf` is full or `inner` is exhausted.
+/// Returns the number of bytes read, which is only less than `buf.len()` at the end of `inner`.
+pub(crate) fn read_full<R: Read>(inner: &mut R, buf: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+
+    while filled < buf.len() {
+        match inner.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(feature = "codec")]
+pub use codec::{DecryptCodec, EncryptCodec, Finish};
+pub use read::{EncryptedReader, SalvageMode};
 pub use seekable_write::SeekableEncryptedWriter;
 pub use write::EncryptedWriter;
@@ -1,31 +1,53 @@
 use super::macros::into_io_error;
-use crate::BLOCK_SIZE;
+use super::read_full;
 use crate::{cipher::Cipher, decrypter::Decrypter};
-use crate::{FILE_HEADER_SIZE, FILE_MAGIC};
-use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom};
+use crate::{decrypted_size, FileNonce, FILE_HEADER_SIZE, FILE_MAGIC};
+use crate::{BLOCK_DATA_SIZE, BLOCK_HEADER_SIZE, BLOCK_SIZE};
+use std::io::{self, Error, ErrorKind, Read, Result, Seek, SeekFrom};
+use std::ops::Range;
 
 /// A reader which automatically decrypts encrypted data from an inner reader.
 ///
-/// Seeking is not supported yet.
+/// If the inner reader is seekable, seeking to a position only reads and decrypts the block
+/// holding it, so ranges of large or remote files can be read without reading what's before them.
 ///
 /// # How it works
 /// Since [`Decrypter`](Decrypter) wants to decrypt at most [`BLOCK_SIZE`](BLOCK_SIZE), internally this reader
 /// will read and decrypt an entire block and store the decrypted data inside an internal buffer. When `read()`
 /// is called, data is returned (and popped) from this buffer instead. If the buffer becomes empty, the next
-/// block is read. If no more blocks can be read (for example in case the inner reader is exhausted), `read()`
+/// block is read. A single `read()` never returns data from more than one block. If no more blocks can be read (for example in case the inner reader is exhausted), `read()`
 /// will return `Ok(0)`. Calling `read()` again will just return `Ok(0)` again.  
 /// I'd advise not calling it after it already returned `Ok(0)`, especially in performance-oriented
 /// applications, as it will always try to read another block.
 ///
+/// If the cipher doesn't encrypt data (see [`Cipher::with_data_encryption()`](Cipher::with_data_encryption)),
+/// the data of the inner reader is returned as-is, without a header.
+///
 /// # Notes
 /// All I/O errors which may occur in the inner reader are passed through to this one.
+/// A block which fails authentication (wrong key, corrupted or truncated data) is reported
+/// as an [`ErrorKind::InvalidData`](ErrorKind::InvalidData) error, unless the reader was created
+/// with [`new_with_salvage()`](Self::new_with_salvage).
 pub struct EncryptedReader<R: Read> {
     inner: R,
-    decrypter: Decrypter,
+    /// `None` if data isn't encrypted.
+    decrypter: Option<Decrypter>,
     current_block_id: u64,
     block_content: Vec<u8>,
     seek_pos: u64,
     real_seek_pos: u64,
+    salvage: Option<SalvageMode>,
+    damaged_ranges: Vec<Range<u64>>,
+}
+
+/// What [`EncryptedReader`](EncryptedReader) does with blocks which fail authentication
+/// in salvage mode, like rclone's `--crypt-pass-bad-blocks`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SalvageMode {
+    /// Replace the plaintext of the block with zeros, so the output keeps its size and offsets.
+    Zero,
+    /// Leave the block out of the output.
+    Skip,
 }
 
 impl<R: Read> EncryptedReader<R> {
@@ -48,76 +70,193 @@ impl<R: Read> EncryptedReader<R> {
     }
 
     /// Same as [`new()`](Self::new), but takes a cipher instead of a password and a salt.
-    pub fn new_with_cipher(mut inner: R, cipher: Cipher) -> Result<Self> {
-        let mut header_buf = [0u8; FILE_HEADER_SIZE];
-        inner
-            .read_exact(&mut header_buf)
-            .map_err(|_| Error::new(ErrorKind::Other, "Unable to read header"))?;
-
-        if &header_buf[0..FILE_MAGIC.len()] != FILE_MAGIC {
-            return Err(Error::new(ErrorKind::Other, "Bad Rclone header"));
-        }
+    pub fn new_with_cipher(inner: R, cipher: Cipher) -> Result<Self> {
+        Self::new_with_options(inner, cipher, None)
+    }
 
-        let decrypter = into_io_error!(
-            Decrypter::new(&cipher.get_file_key(), &header_buf),
-            "Failed to create decrypter"
-        )?;
+    /// Same as [`new_with_cipher()`](Self::new_with_cipher), but doesn't fail on blocks which fail
+    /// authentication. They are handled according to `mode` instead, and their plaintext ranges
+    /// are recorded in [`damaged_ranges()`](Self::damaged_ranges).
+    ///
+    /// Only use this to rescue data from a damaged file: salvaged output can't be trusted.
+    pub fn new_with_salvage(inner: R, cipher: Cipher, mode: SalvageMode) -> Result<Self> {
+        Self::new_with_options(inner, cipher, Some(mode))
+    }
 
-        let mut first_block = vec![0u8; BLOCK_SIZE];
-        let read = inner.read(&mut first_block)?;
-        first_block.truncate(read);
+    fn new_with_options(
+        mut inner: R,
+        cipher: Cipher,
+        salvage: Option<SalvageMode>,
+    ) -> Result<Self> {
+        let decrypter = if cipher.encrypts_data() {
+            let mut header_buf = [0u8; FILE_HEADER_SIZE];
+            inner
+                .read_exact(&mut header_buf)
+                .map_err(|_| Error::other("Unable to read header"))?;
 
-        let decrypted = into_io_error!(
-            decrypter.decrypt_block(0, &first_block),
-            "Failed to decrypt first block"
-        )?;
+            if &header_buf[0..FILE_MAGIC.len()] != FILE_MAGIC {
+                return Err(Error::other("Bad Rclone header"));
+            }
+
+            Some(into_io_error!(
+                Decrypter::new(&cipher.get_file_key(), &header_buf),
+                "Failed to create decrypter"
+            )?)
+        } else {
+            None
+        };
 
-        Ok(Self {
+        let mut reader = Self {
             inner,
             decrypter,
             current_block_id: 0,
-            block_content: decrypted,
+            block_content: Vec::new(),
             seek_pos: 0,
             real_seek_pos: 0,
-        })
+            salvage,
+            damaged_ranges: Vec::new(),
+        };
+
+        // An empty file only consists of the header
+        reader.load_block(0)?;
+        Ok(reader)
     }
 
-    fn next_chunk(&mut self) -> Result<()> {
-        let mut block = vec![0; BLOCK_SIZE];
-        let read = self.inner.read(&mut block)?;
-        block.truncate(read);
+    /// Returns the plaintext byte ranges of the blocks which failed authentication so far, in salvage mode.
+    ///
+    /// The ranges are offsets in the original plaintext. With [`SalvageMode::Skip`](SalvageMode::Skip)
+    /// they are missing from the output, so everything after them is shifted.
+    pub fn damaged_ranges(&self) -> &[Range<u64>] {
+        &self.damaged_ranges
+    }
 
+    /// Returns the nonce of the file, as read from its header, or zeros if data isn't encrypted.
+    pub fn get_nonce(&self) -> FileNonce {
+        self.decrypter
+            .as_ref()
+            .map_or(FileNonce::default(), Decrypter::get_nonce)
+    }
+
+    /// Returns the sizes of the file header and of a block in `inner`. Data which isn't
+    /// encrypted is read in blocks of the same plaintext size, so seeking works the same way.
+    fn layout(&self) -> (u64, u64) {
+        match self.decrypter {
+            Some(_) => (FILE_HEADER_SIZE as u64, BLOCK_SIZE as u64),
+            None => (0, BLOCK_DATA_SIZE as u64),
+        }
+    }
+
+    /// Reads and decrypts the block with the given id from the current position of `inner`
+    /// into the internal buffer. Returns `false` if `inner` has no more data.
+    fn load_block(&mut self, block_id: u64) -> Result<bool> {
+        let mut block = vec![0u8; self.layout().1 as usize];
+        let read = read_full(&mut self.inner, &mut block)?;
         if read == 0 {
-            return Err(Error::new(ErrorKind::Other, "No more data to read"));
+            return Ok(false);
         }
-        self.current_block_id += 1;
+        block.truncate(read);
 
-        let decrypted = into_io_error!(
-            self.decrypter.decrypt_block(self.current_block_id, &block),
-            "Failed to decrypt block"
-        )?;
+        self.current_block_id = block_id;
+        let decrypter = match &self.decrypter {
+            Some(decrypter) => decrypter,
+            None => {
+                self.block_content = block;
+                return Ok(true);
+            }
+        };
+        self.block_content = match decrypter.decrypt_block(block_id, &block) {
+            Ok(decrypted) => decrypted,
+            Err(_) => match self.salvage {
+                None => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "Failed to decrypt block",
+                    ));
+                }
+                Some(mode) => {
+                    let start = block_id * BLOCK_DATA_SIZE as u64;
+                    let size = read.saturating_sub(BLOCK_HEADER_SIZE);
+                    let range = start..start + size as u64;
 
-        self.block_content = decrypted;
-        Ok(())
+                    // Blocks are read in order, but seeking reads them again from the start
+                    let new = self
+                        .damaged_ranges
+                        .last()
+                        .is_none_or(|last| range.start >= last.end);
+                    if !range.is_empty() && new {
+                        self.damaged_ranges.push(range);
+                    }
+
+                    match mode {
+                        SalvageMode::Zero => vec![0u8; size],
+                        SalvageMode::Skip => Vec::new(),
+                    }
+                }
+            },
+        };
+        Ok(true)
+    }
+
+    /// Loads the next block into the internal buffer.
+    /// Returns `false` if there are no more blocks.
+    fn next_chunk(&mut self) -> Result<bool> {
+        self.load_block(self.current_block_id + 1)
     }
 }
 
 impl<R: Read + Seek> EncryptedReader<R> {
+    /// Moves to the plaintext position `n` by loading the block holding it.
+    fn seek_to_block(&mut self, n: u64) -> Result<u64> {
+        let block_data_size = BLOCK_DATA_SIZE as u64;
+        // The end of a full block is kept in it, so seeking to the end of a file works
+        let (block_id, offset) = if n > 0 && n.is_multiple_of(block_data_size) {
+            (n / block_data_size - 1, block_data_size)
+        } else {
+            (n / block_data_size, n % block_data_size)
+        };
+
+        let (header_size, block_size) = self.layout();
+        let position = block_id
+            .checked_mul(block_size)
+            .and_then(|position| position.checked_add(header_size))
+            .ok_or_else(|| Error::new(ErrorKind::UnexpectedEof, "Invalid seek position"))?;
+        self.inner.seek(SeekFrom::Start(position))?;
+        self.current_block_id = block_id;
+        self.block_content.clear();
+
+        let loaded = self.load_block(block_id)?;
+        if (!loaded && n > 0) || offset > self.block_content.len() as u64 {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "Invalid seek position",
+            ));
+        }
+
+        self.seek_pos = offset;
+        self.real_seek_pos = n;
+        Ok(n)
+    }
+
     fn reset(&mut self) -> Result<()> {
-        self.inner.seek(SeekFrom::Start(FILE_HEADER_SIZE as u64))?;
+        self.inner.seek(SeekFrom::Start(self.layout().0))?;
         self.current_block_id = 0;
         self.seek_pos = 0;
         self.real_seek_pos = 0;
+        self.block_content.clear();
 
-        let mut first_block = vec![0u8; BLOCK_SIZE];
-        let read = self.inner.read(&mut first_block)?;
-        first_block.truncate(read);
+        self.load_block(0)?;
+        Ok(())
+    }
 
-        let decrypted = into_io_error!(
-            self.decrypter.decrypt_block(0, &first_block),
-            "Failed to decrypt first block while resetting"
-        )?;
-        self.block_content = decrypted;
+    /// Reads and discards the next `n` bytes of output.
+    fn skip(&mut self, n: u64) -> Result<()> {
+        let skipped = io::copy(&mut (&mut *self).take(n), &mut io::sink())?;
+        if skipped < n {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "Invalid seek position",
+            ));
+        }
         Ok(())
     }
 }
@@ -127,56 +266,69 @@ impl<R: Read + Seek> EncryptedReader<R> {
 /// All other methods will use their default implementation.
 impl<R: Read> Read for EncryptedReader<R> {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
-        let mut index = 0;
-
-        loop {
-            let Some(ptr) = buf.get_mut(index) else {
-                break;
-            };
-            let Some(value) = self.block_content.get(self.seek_pos as usize) else {
-                if self.next_chunk().is_err() {
-                    break;
-                }
-                self.seek_pos = 0;
-                continue;
-            };
-
-            *ptr = *value;
-            index += 1;
-            self.seek_pos += 1;
-            self.real_seek_pos += 1;
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        while self.seek_pos as usize >= self.block_content.len() {
+            if !self.next_chunk()? {
+                return Ok(0);
+            }
+            self.seek_pos = 0;
         }
-        Ok(index)
+
+        let available = &self.block_content[self.seek_pos as usize..];
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+
+        self.seek_pos += n as u64;
+        self.real_seek_pos += n as u64;
+        Ok(n)
     }
 }
 
+/// Returns the position `n` bytes from the end of a stream of `len` bytes.
+fn offset_from(len: u64, n: i64) -> Result<u64> {
+    len.checked_add_signed(n)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Invalid seek position"))
+}
+
 /// # Note
 /// Using `SeekFrom::Current(n)` with `n` < 0 is **NOT** supported.
+/// In [`SalvageMode::Skip`](SalvageMode::Skip), positions don't map to blocks, so seeking reads
+/// everything before the new position.
 impl<R: Read + Seek> Seek for EncryptedReader<R> {
     fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let skipping = self.salvage == Some(SalvageMode::Skip);
         match pos {
+            SeekFrom::Start(n) if !skipping => self.seek_to_block(n),
+            SeekFrom::Current(n) if !skipping && n >= 0 => {
+                self.seek_to_block(self.real_seek_pos + n as u64)
+            }
             SeekFrom::Start(n) => {
                 self.reset()?;
-
-                let mut tmpbuf = vec![0; n as usize];
-                self.read_exact(&mut tmpbuf)
-                    .map_err(|_| Error::new(ErrorKind::UnexpectedEof, "Invalid seek position"))?;
+                self.skip(n)?;
                 Ok(n)
             }
+            SeekFrom::End(n) if !skipping => {
+                let len = self.inner.seek(SeekFrom::End(0))?;
+                let len = match self.decrypter {
+                    Some(_) => decrypted_size(len).ok_or_else(|| {
+                        Error::new(
+                            ErrorKind::InvalidData,
+                            format!("{} bytes is not a valid encrypted size", len),
+                        )
+                    })?,
+                    None => len,
+                };
+                self.seek_to_block(offset_from(len, n)?)
+            }
             SeekFrom::End(n) => {
-                if n < 0 {
-                    return Err(Error::new(ErrorKind::Unsupported, "Negative seek position"));
-                }
-                let n = n as usize;
-
                 self.reset()?;
-                let mut tmpbuf = Vec::new();
-                self.read_to_end(&mut tmpbuf).unwrap();
-                let file_size = tmpbuf.len();
+                let len = io::copy(self, &mut io::sink())?;
+                let seek_pos = offset_from(len, n)?;
 
-                self.reset()?;
-                let seek_pos = file_size - n;
-                self.seek(SeekFrom::Start(seek_pos as u64))
+                self.seek(SeekFrom::Start(seek_pos))
             }
             SeekFrom::Current(n) => {
                 if n < 0 {
@@ -186,11 +338,255 @@ impl<R: Read + Seek> Seek for EncryptedReader<R> {
                     ));
                 }
                 let current_seek = self.real_seek_pos;
-                let mut tmpbuf = vec![0; n as usize];
-                self.read_exact(&mut tmpbuf)
-                    .map_err(|_| Error::new(ErrorKind::UnexpectedEof, "Invalid seek position"))?;
+                self.skip(n as u64)?;
                 Ok(current_seek + n as u64)
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::cipher::Cipher;
+    use crate::stream::{EncryptedReader, SalvageMode, SeekableEncryptedWriter};
+    use crate::tests::encrypt;
+    use crate::{BLOCK_DATA_SIZE, BLOCK_SIZE, FILE_HEADER_SIZE};
+    use std::io::{Cursor, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+
+    /// Returns at most 1000 bytes per `read()`, like a pipe or a socket would
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            let n = buf.len().min(self.0.len()).min(1000);
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn reader_should_roundtrip_short_reads() {
+        let cipher = Cipher::new("test", Some("test")).unwrap();
+        let data: Vec<u8> = (0..BLOCK_DATA_SIZE * 2 + 17).map(|i| i as u8).collect();
+        let encrypted = encrypt(&cipher, &data);
+
+        let mut reader = EncryptedReader::new_with_cipher(Trickle(&encrypted), cipher).unwrap();
+        let mut decrypted = Vec::new();
+        reader.read_to_end(&mut decrypted).unwrap();
+
+        assert_eq!(decrypted, data);
+    }
+
+    #[test]
+    fn empty_file_should_be_header_only() {
+        let cipher = Cipher::new("test", Some("test")).unwrap();
+        let encrypted = encrypt(&cipher, b"");
+        assert_eq!(encrypted.len(), FILE_HEADER_SIZE);
+
+        let mut reader = EncryptedReader::new_with_cipher(&encrypted[..], cipher).unwrap();
+        let mut decrypted = Vec::new();
+        reader.read_to_end(&mut decrypted).unwrap();
+
+        assert!(decrypted.is_empty());
+    }
+
+    #[test]
+    fn reader_should_fail_on_tampered_block() {
+        let cipher = Cipher::new("test", Some("test")).unwrap();
+        let data = vec![7u8; BLOCK_DATA_SIZE + 100];
+        let mut encrypted = encrypt(&cipher, &data);
+
+        // Corrupt the second block
+        let last = encrypted.len() - 1;
+        encrypted[last] ^= 1;
+
+        let mut reader = EncryptedReader::new_with_cipher(&encrypted[..], cipher).unwrap();
+        let mut decrypted = Vec::new();
+        let error = reader.read_to_end(&mut decrypted).unwrap_err();
+
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn salvage_should_replace_bad_blocks() {
+        let cipher = Cipher::new("test", Some("test")).unwrap();
+        let data: Vec<u8> = (0..BLOCK_DATA_SIZE * 2 + 100)
+            .map(|i| i as u8 | 1)
+            .collect();
+        let mut encrypted = encrypt(&cipher, &data);
+
+        // Corrupt the second block
+        encrypted[FILE_HEADER_SIZE + BLOCK_SIZE + 50] ^= 1;
+        let damaged = BLOCK_DATA_SIZE as u64..2 * BLOCK_DATA_SIZE as u64;
+
+        let mut reader =
+            EncryptedReader::new_with_salvage(&encrypted[..], cipher.clone(), SalvageMode::Zero)
+                .unwrap();
+        let mut decrypted = Vec::new();
+        reader.read_to_end(&mut decrypted).unwrap();
+
+        assert_eq!(reader.damaged_ranges().to_vec(), vec![damaged.clone()]);
+        assert_eq!(decrypted.len(), data.len());
+        assert_eq!(decrypted[..BLOCK_DATA_SIZE], data[..BLOCK_DATA_SIZE]);
+        assert!(decrypted[damaged.start as usize..damaged.end as usize]
+            .iter()
+            .all(|b| *b == 0));
+        assert_eq!(
+            decrypted[damaged.end as usize..],
+            data[damaged.end as usize..]
+        );
+
+        let mut reader =
+            EncryptedReader::new_with_salvage(&encrypted[..], cipher.clone(), SalvageMode::Skip)
+                .unwrap();
+        let mut decrypted = Vec::new();
+        reader.read_to_end(&mut decrypted).unwrap();
+
+        assert_eq!(reader.damaged_ranges().to_vec(), vec![damaged.clone()]);
+        assert_eq!(decrypted[..BLOCK_DATA_SIZE], data[..BLOCK_DATA_SIZE]);
+        assert_eq!(decrypted[BLOCK_DATA_SIZE..], data[damaged.end as usize..]);
+
+        // Seeking reads the output again, without recording the damage twice
+        let mut reader =
+            EncryptedReader::new_with_salvage(Cursor::new(&encrypted), cipher, SalvageMode::Skip)
+                .unwrap();
+        let len = decrypted.len() as u64;
+        let mut buf = [0u8; 5];
+        assert_eq!(reader.seek(SeekFrom::End(-5)).unwrap(), len - 5);
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, decrypted[decrypted.len() - 5..]);
+        assert_eq!(reader.seek(SeekFrom::Start(10)).unwrap(), 10);
+        assert_eq!(reader.seek(SeekFrom::Current(60_000)).unwrap(), 60_010);
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, decrypted[60_010..60_015]);
+        assert_eq!(
+            reader.seek(SeekFrom::Start(len + 1)).unwrap_err().kind(),
+            ErrorKind::UnexpectedEof
+        );
+        assert_eq!(reader.damaged_ranges().to_vec(), vec![damaged]);
+    }
+
+    #[test]
+    fn seek_should_only_read_the_target_block() {
+        /// Counts the bytes read from the inner reader
+        struct Counting {
+            inner: Cursor<Vec<u8>>,
+            read: usize,
+        }
+
+        impl Read for Counting {
+            fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+                let n = self.inner.read(buf)?;
+                self.read += n;
+                Ok(n)
+            }
+        }
+
+        impl Seek for Counting {
+            fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+                self.inner.seek(pos)
+            }
+        }
+
+        let cipher = Cipher::new("test", Some("test")).unwrap();
+        let data: Vec<u8> = (0..BLOCK_DATA_SIZE * 3).map(|i| i as u8).collect();
+        let encrypted = encrypt(&cipher, &data);
+        let inner = Counting {
+            inner: Cursor::new(encrypted),
+            read: 0,
+        };
+
+        let mut reader = EncryptedReader::new_with_cipher(inner, cipher).unwrap();
+        let position = 2 * BLOCK_DATA_SIZE as u64 + 10;
+        assert_eq!(reader.seek(SeekFrom::Start(position)).unwrap(), position);
+        let mut buf = [0u8; 5];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, data[position as usize..position as usize + 5]);
+        // The header and first block when created, then only the third block
+        assert_eq!(reader.inner.read, FILE_HEADER_SIZE + 2 * BLOCK_SIZE);
+
+        reader.seek(SeekFrom::Current(100)).unwrap();
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, data[position as usize + 105..position as usize + 110]);
+
+        assert_eq!(
+            reader.seek(SeekFrom::Start(data.len() as u64)).unwrap(),
+            data.len() as u64
+        );
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+        reader.seek(SeekFrom::Start(0)).unwrap();
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, data[..5]);
+        assert_eq!(
+            reader
+                .seek(SeekFrom::Start(data.len() as u64 + 1))
+                .unwrap_err()
+                .kind(),
+            ErrorKind::UnexpectedEof
+        );
+
+        // Seeking from the end only reads the last block
+        reader.inner.read = 0;
+        let position = reader.seek(SeekFrom::End(-5)).unwrap();
+        assert_eq!(position, data.len() as u64 - 5);
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, data[data.len() - 5..]);
+        assert_eq!(reader.inner.read, BLOCK_SIZE);
+        assert_eq!(reader.seek(SeekFrom::End(0)).unwrap(), data.len() as u64);
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+        assert_eq!(
+            reader
+                .seek(SeekFrom::End(-(data.len() as i64) - 1))
+                .unwrap_err()
+                .kind(),
+            ErrorKind::InvalidInput
+        );
+    }
+
+    #[test]
+    fn salvage_should_handle_a_bad_first_block() {
+        let cipher = Cipher::new("test", Some("test")).unwrap();
+        let mut encrypted = encrypt(&cipher, b"hello");
+        encrypted[FILE_HEADER_SIZE] ^= 1;
+
+        assert!(EncryptedReader::new_with_cipher(&encrypted[..], cipher.clone()).is_err());
+
+        let mut reader =
+            EncryptedReader::new_with_salvage(&encrypted[..], cipher, SalvageMode::Zero).unwrap();
+        let mut decrypted = Vec::new();
+        reader.read_to_end(&mut decrypted).unwrap();
+
+        assert_eq!(decrypted, [0u8; 5]);
+        assert_eq!(reader.damaged_ranges().to_vec(), vec![0..5]);
+    }
+
+    #[test]
+    fn streams_should_pass_data_through_without_data_encryption() {
+        let cipher = Cipher::new("test", Some("test"))
+            .unwrap()
+            .with_data_encryption(false);
+        let data: Vec<u8> = (0..BLOCK_DATA_SIZE * 2 + 17).map(|i| i as u8).collect();
+
+        assert_eq!(encrypt(&cipher, &data), data);
+        assert!(encrypt(&cipher, b"").is_empty());
+        let mut written = Vec::new();
+        let mut writer =
+            SeekableEncryptedWriter::new_with_cipher(&mut written, cipher.clone()).unwrap();
+        writer.write_all(b"hello").unwrap();
+        drop(writer);
+        assert_eq!(written, b"hello");
+
+        let mut reader = EncryptedReader::new_with_cipher(Cursor::new(&data), cipher).unwrap();
+        let position = BLOCK_DATA_SIZE as u64 + 3;
+        reader.seek(SeekFrom::Start(position)).unwrap();
+        let mut tail = Vec::new();
+        reader.read_to_end(&mut tail).unwrap();
+        assert_eq!(tail, data[position as usize..]);
+        reader.seek(SeekFrom::End(-7)).unwrap();
+        let mut tail = Vec::new();
+        reader.read_to_end(&mut tail).unwrap();
+        assert_eq!(tail, data[data.len() - 7..]);
+        assert_eq!(reader.get_nonce(), [0u8; 24]);
+    }
+}
@@ -18,10 +18,14 @@ use std::io::{Cursor, Error, ErrorKind, Result, Seek, SeekFrom, Write};
 /// significantly less secure than [`EncryptedWriter`](crate::stream::EncryptedWriter), but on the other
 /// hand, allows seeking. If the inner writer is slow, dropping may be slow as well.
 ///
+/// If the cipher doesn't encrypt data (see [`Cipher::with_data_encryption()`](Cipher::with_data_encryption)),
+/// the data is written as-is, without a header.
+///
 /// # Panics
 /// The `drop()`method uses a bunch of `unwrap()`s so, any failed write operation will cause a panic.
 pub struct SeekableEncryptedWriter<W: Write> {
-    encrypter: Encrypter,
+    /// `None` if data isn't encrypted.
+    encrypter: Option<Encrypter>,
     inner: W,
     inner_buf: Cursor<Vec<u8>>,
 }
@@ -41,10 +45,14 @@ impl<W: Write> SeekableEncryptedWriter<W> {
 
     /// Same as [`new()`](Self::new) but takes a cipher instead of a password and a salt.
     pub fn new_with_cipher(inner: W, cipher: Cipher) -> Result<Self> {
-        let encrypter = into_io_error!(
-            Encrypter::new(&cipher.get_file_key()),
-            "Failed to create Encrypter"
-        )?;
+        let encrypter = if cipher.encrypts_data() {
+            Some(into_io_error!(
+                Encrypter::new(&cipher.get_file_key()),
+                "Failed to create Encrypter"
+            )?)
+        } else {
+            None
+        };
 
         Ok(Self {
             encrypter,
@@ -73,14 +81,20 @@ impl<W: Write> Seek for SeekableEncryptedWriter<W> {
 impl<W: Write> Drop for SeekableEncryptedWriter<W> {
     fn drop(&mut self) {
         let inner_buf = self.inner_buf.get_mut();
+        let encrypter = match &self.encrypter {
+            Some(encrypter) => encrypter,
+            None => {
+                self.inner.write_all(inner_buf).unwrap();
+                self.inner.flush().unwrap();
+                return;
+            }
+        };
         let blocks = inner_buf.chunks(BLOCK_DATA_SIZE);
 
-        self.inner
-            .write_all(&self.encrypter.get_file_header())
-            .unwrap();
+        self.inner.write_all(&encrypter.get_file_header()).unwrap();
 
         for (block_id, block) in blocks.enumerate() {
-            let encrypted = self.encrypter.encrypt_block(block_id as u64, block);
+            let encrypted = encrypter.encrypt_block(block_id as u64, block);
             self.inner.write_all(&encrypted).unwrap();
         }
         self.inner.flush().unwrap();
@@ -1,6 +1,6 @@
 use super::macros::into_io_error;
-use crate::BLOCK_DATA_SIZE;
 use crate::{cipher::Cipher, encrypter::Encrypter};
+use crate::{FileNonce, BLOCK_DATA_SIZE};
 use std::io::{Error, ErrorKind, Result, Write};
 
 /// A writer which automatically encrypts data from an inner reader.
@@ -14,15 +14,22 @@ use std::io::{Error, ErrorKind, Result, Write};
 ///
 /// If we've passed less than [`BLOCK_DATA_SIZE`](BLOCK_DATA_SIZE) bytes into this writer, and it's
 /// being dropped, the leftover data inside the internal buffer is encrypted and passed into the inner writer.
-/// Note that if this process fails, a panic could occur while dropping.
+/// Note that if this process fails, a panic could occur while dropping. Call [`finish()`](Self::finish)
+/// before dropping to handle such errors instead, and [`discard()`](Self::discard) to give up on the
+/// output after an error.
+///
+/// If the cipher doesn't encrypt data (see [`Cipher::with_data_encryption()`](Cipher::with_data_encryption)),
+/// data is passed into the inner writer as-is, without a header.
 ///
 /// # Notes
 /// All I/O errors which may occur in the inner reader are passed through to this one.
 pub struct EncryptedWriter<W: Write> {
-    encrypter: Encrypter,
+    /// `None` if data isn't encrypted.
+    encrypter: Option<Encrypter>,
     block_id: u64,
     inner: W,
     inner_buf: Vec<u8>,
+    finished: bool,
 }
 
 impl<W: Write> EncryptedWriter<W> {
@@ -40,32 +47,72 @@ impl<W: Write> EncryptedWriter<W> {
 
     /// Same as [`new()`](Self::new) but takes a cipher instead of a password and a salt.
     pub fn new_with_cipher(inner: W, cipher: Cipher) -> Result<Self> {
+        if !cipher.encrypts_data() {
+            return Ok(Self::new_with_encrypter(inner, None));
+        }
+
         let encrypter = into_io_error!(
             Encrypter::new(&cipher.get_file_key()),
             "Failed to create Encrypter"
         )?;
+        Ok(Self::new_with_encrypter(inner, Some(encrypter)))
+    }
+
+    /// Same as [`new_with_cipher()`](Self::new_with_cipher) but uses the given nonce instead of
+    /// a random one. See [`Encrypter::new_with_nonce()`](Encrypter::new_with_nonce).
+    /// The nonce is unused if the cipher doesn't encrypt data.
+    pub fn new_with_nonce(inner: W, cipher: Cipher, nonce: &FileNonce) -> Result<Self> {
+        if !cipher.encrypts_data() {
+            return Ok(Self::new_with_encrypter(inner, None));
+        }
+
+        let encrypter = into_io_error!(
+            Encrypter::new_with_nonce(&cipher.get_file_key(), nonce),
+            "Failed to create Encrypter"
+        )?;
+        Ok(Self::new_with_encrypter(inner, Some(encrypter)))
+    }
 
-        Ok(Self {
+    fn new_with_encrypter(inner: W, encrypter: Option<Encrypter>) -> Self {
+        Self {
             encrypter,
             block_id: 0,
             inner,
             inner_buf: vec![],
-        })
+            finished: false,
+        }
     }
 
-    /// Flush the rest of the inner buffer.
-    fn finish(&mut self) -> Result<()> {
-        if self.inner_buf.is_empty() {
+    /// Drops the data which isn't written yet and marks the writer as finished, so dropping it
+    /// doesn't write anything or panic. The output is left incomplete, so this is only meant for
+    /// abandoning it, e.g. after an error of the inner writer.
+    pub fn discard(&mut self) {
+        self.inner_buf.clear();
+        self.finished = true;
+    }
+
+    /// Encrypts and writes the rest of the inner buffer, then flushes the inner writer.
+    ///
+    /// If no data was written at all, only the file header is written, like rclone does for empty files.
+    /// Writing after calling this returns an error, and calling it again does nothing.
+    pub fn finish(&mut self) -> Result<()> {
+        if self.finished {
             return Ok(());
         }
 
-        let encrypted_block = self.encrypter.encrypt_block(self.block_id, &self.inner_buf);
-        if self.block_id == 0 {
-            self.inner.write_all(&self.encrypter.get_file_header())?;
+        if let Some(encrypter) = &self.encrypter {
+            if self.block_id == 0 {
+                self.inner.write_all(&encrypter.get_file_header())?;
+            }
+            if !self.inner_buf.is_empty() {
+                let encrypted_block = encrypter.encrypt_block(self.block_id, &self.inner_buf);
+                self.inner.write_all(&encrypted_block)?;
+                self.inner_buf.clear();
+            }
         }
-        self.inner.write_all(&encrypted_block)?;
-        self.inner_buf.clear();
-        Ok(())
+
+        self.finished = true;
+        self.inner.flush()
     }
 }
 
@@ -74,14 +121,22 @@ impl<W: Write> EncryptedWriter<W> {
 /// All other methods will use their default implementation.
 impl<W: Write> Write for EncryptedWriter<W> {
     fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if self.finished {
+            return Err(Error::other("Writer is already finished"));
+        }
+        let encrypter = match &self.encrypter {
+            Some(encrypter) => encrypter,
+            None => return self.inner.write(buf),
+        };
+
         for byte in buf {
             if self.inner_buf.len() == BLOCK_DATA_SIZE {
                 let mut encrypted = Vec::new();
 
                 if self.block_id == 0 {
-                    encrypted.extend(self.encrypter.get_file_header());
+                    encrypted.extend(encrypter.get_file_header());
                 }
-                encrypted.extend(self.encrypter.encrypt_block(self.block_id, &self.inner_buf));
+                encrypted.extend(encrypter.encrypt_block(self.block_id, &self.inner_buf));
 
                 self.inner.write_all(&encrypted)?;
                 self.block_id += 1;
@@ -0,0 +1,463 @@
+/// One-way sync of a plaintext directory tree into an encrypted tree.
+///
+/// The encrypted tree has the same layout as an rclone crypt remote over the destination
+/// directory would produce, so it can be uploaded as-is.
+use anyhow::{anyhow, Context, Result};
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::cipher::Cipher;
+use crate::cryptfs::create_partial;
+use crate::encrypted_size;
+use crate::stream::EncryptedWriter;
+
+/// Options for [`sync_tree()`](sync_tree).
+#[derive(Clone, Debug, Default)]
+pub struct SyncOptions {
+    /// Delete encrypted entries which don't have a plaintext counterpart.
+    pub delete: bool,
+    /// Only report what would be done, without touching the destination.
+    pub dry_run: bool,
+    /// Modification times closer than this are considered equal.
+    pub modify_window: Duration,
+}
+
+/// What was (or would be, in a dry run) done to an entry of the encrypted tree.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncAction {
+    /// A directory was created.
+    CreateDir,
+    /// A new file was encrypted.
+    Create,
+    /// An existing file was encrypted again, because its size or mtime changed.
+    Update,
+    /// An extraneous file or directory was deleted.
+    Delete,
+}
+
+/// A single change made by [`sync_tree()`](sync_tree).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SyncChange {
+    pub action: SyncAction,
+    /// Path relative to the source directory.
+    /// For deleted entries, this is `None` if their name can't be decrypted.
+    pub path: Option<PathBuf>,
+    /// Path relative to the destination directory.
+    pub encrypted_path: PathBuf,
+}
+
+/// The outcome of [`sync_tree()`](sync_tree).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SyncReport {
+    pub changes: Vec<SyncChange>,
+    /// Number of files which were already up to date.
+    pub unchanged: usize,
+    /// Source entries which were skipped because they're neither files nor directories (e.g. symlinks).
+    pub skipped: Vec<PathBuf>,
+}
+
+/// Encrypts the plaintext tree at `source` into `destination`.
+///
+/// Names are encrypted with [`Cipher::encrypt_path()`](Cipher::encrypt_path) and data with an
+/// [`EncryptedWriter`](EncryptedWriter). A file is only encrypted again if its size or modification
+/// time differs from the encrypted copy, whose modification time is set to the one of the plaintext.
+///
+/// # Errors
+/// Stops at the first I/O error, or at the first name which isn't valid UTF-8.
+pub fn sync_tree(
+    cipher: &Cipher,
+    source: &Path,
+    destination: &Path,
+    options: &SyncOptions,
+) -> Result<SyncReport> {
+    let mut sync = TreeSync {
+        cipher,
+        source,
+        destination,
+        options,
+        report: SyncReport::default(),
+    };
+
+    if !fs::metadata(source)
+        .with_context(|| format!("Failed to read {}", source.display()))?
+        .is_dir()
+    {
+        return Err(anyhow!("{} is not a directory", source.display()));
+    }
+
+    sync.sync_dir(Path::new(""), Path::new(""), destination.is_dir())?;
+    Ok(sync.report)
+}
+
+struct TreeSync<'a> {
+    cipher: &'a Cipher,
+    source: &'a Path,
+    destination: &'a Path,
+    options: &'a SyncOptions,
+    report: SyncReport,
+}
+
+impl TreeSync<'_> {
+    /// Syncs the directory at `path` (relative to the source), whose encrypted counterpart
+    /// is `encrypted_path`. `exists` tells whether the encrypted directory exists.
+    fn sync_dir(&mut self, path: &Path, encrypted_path: &Path, exists: bool) -> Result<()> {
+        let source_dir = self.source.join(path);
+        let destination_dir = self.destination.join(encrypted_path);
+
+        if !exists {
+            // The destination itself isn't reported
+            if path.parent().is_some() {
+                self.change(SyncAction::CreateDir, Some(path), encrypted_path);
+            }
+            if !self.options.dry_run {
+                fs::create_dir_all(&destination_dir)
+                    .with_context(|| format!("Failed to create {}", destination_dir.display()))?;
+            }
+        }
+
+        let mut entries = fs::read_dir(&source_dir)
+            .with_context(|| format!("Failed to read {}", source_dir.display()))?
+            .collect::<std::io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+
+        let mut expected = HashSet::new();
+
+        for entry in entries {
+            let name = entry.file_name();
+            let name = name
+                .to_str()
+                .ok_or_else(|| anyhow!("{} is not valid UTF-8", entry.path().display()))?;
+            let encrypted_name = self.cipher.encrypt_file_name(name)?;
+
+            let entry_path = path.join(name);
+            let encrypted_entry_path = encrypted_path.join(&encrypted_name);
+            let target = self.destination.join(&encrypted_entry_path);
+            expected.insert(encrypted_name);
+
+            // Symlinks are neither files nor directories here, so they are skipped
+            let file_type = entry.file_type()?;
+            let target_metadata = if exists {
+                fs::symlink_metadata(&target).ok()
+            } else {
+                None
+            };
+
+            if file_type.is_dir() {
+                let target_is_dir = match target_metadata {
+                    Some(metadata) if !metadata.is_dir() => {
+                        self.delete(Some(&entry_path), &encrypted_entry_path)?;
+                        false
+                    }
+                    Some(_) => true,
+                    None => false,
+                };
+                self.sync_dir(&entry_path, &encrypted_entry_path, target_is_dir)?;
+            } else if file_type.is_file() {
+                let metadata = entry.metadata()?;
+                let action = match target_metadata {
+                    Some(target) if target.is_dir() => {
+                        self.delete(Some(&entry_path), &encrypted_entry_path)?;
+                        SyncAction::Create
+                    }
+                    Some(target) if self.is_unchanged(&metadata, &target) => {
+                        self.report.unchanged += 1;
+                        continue;
+                    }
+                    Some(_) => SyncAction::Update,
+                    None => SyncAction::Create,
+                };
+
+                self.change(action, Some(&entry_path), &encrypted_entry_path);
+                if !self.options.dry_run {
+                    self.encrypt_file(&self.source.join(&entry_path), &target, &metadata)?;
+                }
+            } else {
+                self.report.skipped.push(entry_path);
+            }
+        }
+
+        if exists && self.options.delete {
+            self.delete_extraneous(path, encrypted_path, &expected)?;
+        }
+
+        Ok(())
+    }
+
+    /// Deletes the entries of the encrypted directory which aren't in `expected`.
+    fn delete_extraneous(
+        &mut self,
+        path: &Path,
+        encrypted_path: &Path,
+        expected: &HashSet<String>,
+    ) -> Result<()> {
+        let destination_dir = self.destination.join(encrypted_path);
+
+        let mut extraneous = Vec::new();
+        for entry in fs::read_dir(&destination_dir)
+            .with_context(|| format!("Failed to read {}", destination_dir.display()))?
+        {
+            let name = entry?.file_name();
+            if !name.to_str().is_some_and(|name| expected.contains(name)) {
+                extraneous.push(name);
+            }
+        }
+        extraneous.sort();
+
+        for name in extraneous {
+            let plaintext_name = name
+                .to_str()
+                .and_then(|name| self.cipher.decrypt_file_name(name).ok());
+            let plaintext_path = plaintext_name.map(|name| path.join(name));
+
+            self.delete(plaintext_path.as_deref(), &encrypted_path.join(name))?;
+        }
+
+        Ok(())
+    }
+
+    fn is_unchanged(&self, source: &fs::Metadata, target: &fs::Metadata) -> bool {
+        let expected_len = if self.cipher.encrypts_data() {
+            encrypted_size(source.len())
+        } else {
+            source.len()
+        };
+        if !target.is_file() || target.len() != expected_len {
+            return false;
+        }
+
+        match (source.modified(), target.modified()) {
+            (Ok(source), Ok(target)) => {
+                let difference = source
+                    .duration_since(target)
+                    .or_else(|_| target.duration_since(source))
+                    .unwrap_or_default();
+                difference <= self.options.modify_window
+            }
+            _ => false,
+        }
+    }
+
+    /// Encrypts `source` into a temporary file which then replaces `target`, so that a failure
+    /// doesn't leave a truncated copy behind.
+    fn encrypt_file(&self, source: &Path, target: &Path, metadata: &fs::Metadata) -> Result<()> {
+        let (temporary, file) = create_partial(target)
+            .with_context(|| format!("Failed to create a file next to {}", target.display()))?;
+        let result = self
+            .encrypt_into(source, file, metadata)
+            .and_then(|()| fs::rename(&temporary, target).map_err(Into::into));
+        if result.is_err() {
+            // The error of the encryption is more useful
+            let _ = fs::remove_file(&temporary);
+        }
+        result
+    }
+
+    fn encrypt_into(&self, source: &Path, output: File, metadata: &fs::Metadata) -> Result<()> {
+        let mut input = BufReader::new(
+            File::open(source).with_context(|| format!("Failed to open {}", source.display()))?,
+        );
+        let mut output = BufWriter::new(output);
+
+        let mut writer = EncryptedWriter::new_with_cipher(&mut output, self.cipher.clone())?;
+        std::io::copy(&mut input, &mut writer)
+            .with_context(|| format!("Failed to encrypt {}", source.display()))?;
+        writer.finish()?;
+        drop(writer);
+
+        let file = output.into_inner().map_err(|e| e.into_error())?;
+        file.set_modified(metadata.modified().unwrap_or_else(|_| SystemTime::now()))?;
+        Ok(())
+    }
+
+    /// Deletes a file or directory of the encrypted tree.
+    fn delete(&mut self, path: Option<&Path>, encrypted_path: &Path) -> Result<()> {
+        self.change(SyncAction::Delete, path, encrypted_path);
+        if self.options.dry_run {
+            return Ok(());
+        }
+
+        let target = self.destination.join(encrypted_path);
+        let result = if fs::symlink_metadata(&target)?.is_dir() {
+            fs::remove_dir_all(&target)
+        } else {
+            fs::remove_file(&target)
+        };
+        result.with_context(|| format!("Failed to delete {}", target.display()))
+    }
+
+    fn change(&mut self, action: SyncAction, path: Option<&Path>, encrypted_path: &Path) {
+        self.report.changes.push(SyncChange {
+            action,
+            path: path.map(Path::to_path_buf),
+            encrypted_path: encrypted_path.to_path_buf(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{sync_tree, SyncAction, SyncOptions, SyncReport, TreeSync};
+    use crate::cipher::Cipher;
+    use crate::stream::EncryptedReader;
+    use std::fs::{self, File};
+    use std::io::Read;
+    use std::path::Path;
+    use std::time::{Duration, SystemTime};
+
+    fn actions(report: &super::SyncReport) -> Vec<(SyncAction, String)> {
+        report
+            .changes
+            .iter()
+            .map(|change| {
+                let path = change.path.as_deref().unwrap_or(Path::new("?"));
+                (change.action, path.to_str().unwrap().to_string())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sync_should_only_touch_changed_files() {
+        let cipher = Cipher::new("test", Some("test")).unwrap();
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        let options = SyncOptions::default();
+
+        fs::create_dir(source.path().join("dir")).unwrap();
+        fs::write(source.path().join("dir/a.txt"), b"hello").unwrap();
+        fs::write(source.path().join("b.txt"), vec![7u8; 100_000]).unwrap();
+
+        let report = sync_tree(&cipher, source.path(), destination.path(), &options).unwrap();
+        assert_eq!(
+            actions(&report),
+            [
+                (SyncAction::Create, "b.txt".to_string()),
+                (SyncAction::CreateDir, "dir".to_string()),
+                (SyncAction::Create, "dir/a.txt".to_string()),
+            ]
+        );
+
+        let encrypted = destination
+            .path()
+            .join(cipher.encrypt_path(Path::new("dir/a.txt")).unwrap());
+        let mut reader =
+            EncryptedReader::new_with_cipher(File::open(&encrypted).unwrap(), cipher.clone())
+                .unwrap();
+        let mut decrypted = Vec::new();
+        reader.read_to_end(&mut decrypted).unwrap();
+        assert_eq!(decrypted, b"hello");
+        assert_eq!(
+            fs::metadata(&encrypted).unwrap().modified().unwrap(),
+            fs::metadata(source.path().join("dir/a.txt"))
+                .unwrap()
+                .modified()
+                .unwrap()
+        );
+
+        let report = sync_tree(&cipher, source.path(), destination.path(), &options).unwrap();
+        assert!(report.changes.is_empty());
+        assert_eq!(report.unchanged, 2);
+
+        let past = SystemTime::now() - Duration::from_secs(3600);
+        File::options()
+            .write(true)
+            .open(source.path().join("b.txt"))
+            .unwrap()
+            .set_modified(past)
+            .unwrap();
+
+        let report = sync_tree(&cipher, source.path(), destination.path(), &options).unwrap();
+        assert_eq!(
+            actions(&report),
+            [(SyncAction::Update, "b.txt".to_string())]
+        );
+    }
+
+    #[test]
+    fn sync_should_compare_raw_sizes_without_data_encryption() {
+        let cipher = Cipher::new("test", Some("test"))
+            .unwrap()
+            .with_data_encryption(false);
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        let options = SyncOptions::default();
+
+        fs::write(source.path().join("a.txt"), b"hello").unwrap();
+        sync_tree(&cipher, source.path(), destination.path(), &options).unwrap();
+        let encrypted = destination
+            .path()
+            .join(cipher.encrypt_file_name("a.txt").unwrap());
+        assert_eq!(fs::read(encrypted).unwrap(), b"hello");
+
+        let report = sync_tree(&cipher, source.path(), destination.path(), &options).unwrap();
+        assert!(report.changes.is_empty());
+        assert_eq!(report.unchanged, 1);
+    }
+
+    #[test]
+    fn sync_should_keep_the_old_copy_if_encryption_fails() {
+        let cipher = Cipher::new("test", Some("test")).unwrap();
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+        let options = SyncOptions::default();
+        let sync = TreeSync {
+            cipher: &cipher,
+            source: source.path(),
+            destination: destination.path(),
+            options: &options,
+            report: SyncReport::default(),
+        };
+
+        let target = destination.path().join("target");
+        fs::write(&target, b"old").unwrap();
+        // A directory opens fine, but reading it fails
+        let metadata = fs::metadata(source.path()).unwrap();
+        assert!(sync
+            .encrypt_file(source.path(), &target, &metadata)
+            .is_err());
+
+        assert_eq!(fs::read(&target).unwrap(), b"old");
+        assert_eq!(fs::read_dir(destination.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn sync_should_delete_extraneous_entries() {
+        let cipher = Cipher::new("test", Some("test")).unwrap();
+        let source = tempfile::tempdir().unwrap();
+        let destination = tempfile::tempdir().unwrap();
+
+        fs::write(source.path().join("a.txt"), b"a").unwrap();
+        fs::write(source.path().join("b.txt"), b"b").unwrap();
+        sync_tree(
+            &cipher,
+            source.path(),
+            destination.path(),
+            &SyncOptions::default(),
+        )
+        .unwrap();
+
+        fs::remove_file(source.path().join("b.txt")).unwrap();
+        fs::write(destination.path().join("not-encrypted"), b"").unwrap();
+
+        let mut options = SyncOptions {
+            delete: true,
+            dry_run: true,
+            ..SyncOptions::default()
+        };
+        let report = sync_tree(&cipher, source.path(), destination.path(), &options).unwrap();
+        assert_eq!(
+            actions(&report),
+            [
+                (SyncAction::Delete, "b.txt".to_string()),
+                (SyncAction::Delete, "?".to_string()),
+            ]
+        );
+        assert_eq!(fs::read_dir(destination.path()).unwrap().count(), 3);
+
+        options.dry_run = false;
+        sync_tree(&cipher, source.path(), destination.path(), &options).unwrap();
+        assert_eq!(fs::read_dir(destination.path()).unwrap().count(), 1);
+    }
+}
@@ -1,17 +1,36 @@
+#[cfg(feature = "std")]
+use crate::fetch_window;
+#[cfg(feature = "std")]
+use crate::stream::EncryptedWriter;
 use crate::{
+    calculate_nonce,
     cipher::Cipher,
+    decrypted_size, encrypted_range, encrypted_size,
     obscure::{obscure, reveal},
 };
-use std::path::Path;
+#[cfg(feature = "std")]
+use std::io::Write;
+
+/// Encrypts `data` in memory, for the tests of every module.
+#[cfg(feature = "std")]
+pub(crate) fn encrypt(cipher: &Cipher, data: &[u8]) -> Vec<u8> {
+    let mut encrypted = Vec::new();
+    let mut writer = EncryptedWriter::new_with_cipher(&mut encrypted, cipher.clone()).unwrap();
+    writer.write_all(data).unwrap();
+    writer.finish().unwrap();
+    drop(writer);
+    encrypted
+}
 
 #[test]
+#[cfg(feature = "std")]
 fn test_path_encryption() {
     let password = "test";
     let salt = "test";
 
     let cipher = Cipher::new(password, Some(salt)).unwrap();
 
-    let path = Path::new("test.txt");
+    let path = std::path::Path::new("test.txt");
     let encrypted = cipher.encrypt_path(path).unwrap().display().to_string();
 
     /*
@@ -22,6 +41,16 @@ fn test_path_encryption() {
     assert_eq!(encrypted, "g83ktjf47lcm0bprvp034uulhg");
 }
 
+#[test]
+fn test_file_name_encryption() {
+    let cipher = Cipher::new("test", Some("test")).unwrap();
+
+    // Same rclone v1.62.2 reference as above, without going through `Path`
+    let encrypted = cipher.encrypt_file_name("test.txt").unwrap();
+    assert_eq!(encrypted, "g83ktjf47lcm0bprvp034uulhg");
+    assert_eq!(cipher.decrypt_file_name(&encrypted).unwrap(), "test.txt");
+}
+
 #[test]
 fn password_obscure() {
     let string = String::from("hello_world");
@@ -52,3 +81,83 @@ fn password_reveal_rclone() {
         assert_eq!(string, revealed);
     }
 }
+
+#[test]
+fn nonce_increment() {
+    let nonce = [0u8; 24];
+    assert_eq!(calculate_nonce(nonce, 0), nonce);
+    assert_eq!(calculate_nonce(nonce, 0x0102)[..3], [0x02, 0x01, 0x00]);
+
+    // Overflowing the low 64 bits carries into byte 8 onwards
+    let mut nonce = [0u8; 24];
+    nonce[..8].copy_from_slice(&[0xff; 8]);
+    nonce[8] = 0xff;
+    let next = calculate_nonce(nonce, 1);
+
+    assert_eq!(next[..8], [0u8; 8]);
+    assert_eq!(next[8..11], [0x00, 0x01, 0x00]);
+}
+
+#[test]
+fn encrypted_sizes() {
+    let sizes = [
+        (0, 32),
+        (1, 49),
+        (65536, 65584),
+        (65537, 65601),
+        (131072, 131136),
+    ];
+
+    for (plaintext, encrypted) in sizes.iter() {
+        assert_eq!(encrypted_size(*plaintext), *encrypted);
+        assert_eq!(decrypted_size(*encrypted), Some(*plaintext));
+    }
+
+    assert_eq!(decrypted_size(31), None);
+    assert_eq!(decrypted_size(48), None);
+    assert_eq!(decrypted_size(65584 + 16), None);
+}
+
+#[test]
+fn encrypted_ranges() {
+    assert_eq!(encrypted_range(0..1), (0, 32..65584));
+    assert_eq!(encrypted_range(65535..65537), (0, 32..131136));
+    assert_eq!(encrypted_range(65536..131072), (1, 65584..131136));
+    assert_eq!(encrypted_range(70000..70000), (1, 65584..65584));
+    assert_eq!(encrypted_range(0..u64::MAX), (0, 32..u64::MAX));
+}
+
+#[test]
+#[cfg(feature = "std")]
+fn fetch_windows() {
+    assert_eq!(fetch_window(0, 1), 0..65584);
+    assert_eq!(fetch_window(65583, 1), 0..65584);
+    assert_eq!(fetch_window(65584, 1), 65584..131136);
+    assert_eq!(fetch_window(65584, 2), 0..131136);
+    assert_eq!(fetch_window(131136, 2), 131136..262240);
+}
+
+#[test]
+#[cfg(feature = "std")]
+fn discarded_writers_should_drop_without_writing() {
+    /// Fails every write, like a full disk
+    struct Full;
+
+    impl Write for Full {
+        fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::Error::other("No space left on device"))
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let cipher = Cipher::new("test", None).unwrap();
+    let mut writer = EncryptedWriter::new_with_cipher(Full, cipher).unwrap();
+    writer.write_all(b"hello").unwrap();
+    assert!(writer.finish().is_err());
+    // Dropping it as is would retry and panic
+    writer.discard();
+    drop(writer);
+}
@@ -0,0 +1,377 @@
+/// Verifying that encrypted files and trees authenticate, without writing any plaintext.
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use crate::cipher::Cipher;
+use crate::decrypter::Decrypter;
+use crate::stream::read_full;
+use crate::{FileNonce, BLOCK_HEADER_SIZE, BLOCK_SIZE, FILE_HEADER_SIZE, FILE_MAGIC};
+
+/// A problem with the structure of an encrypted file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileProblem {
+    /// The file is shorter than [`FILE_HEADER_SIZE`](FILE_HEADER_SIZE).
+    ShortHeader {
+        /// Size of the whole file.
+        size: usize,
+    },
+    /// The file doesn't start with [`FILE_MAGIC`](FILE_MAGIC).
+    BadMagic,
+    /// The file ends with a fragment which is too short to be a block, as a block is
+    /// at least [`BLOCK_HEADER_SIZE`](BLOCK_HEADER_SIZE) + 1 bytes long.
+    TrailingFragment {
+        /// Offset of the fragment in the encrypted file.
+        offset: u64,
+        /// Size of the fragment.
+        size: usize,
+    },
+}
+
+/// The result of authenticating a single block.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlockResult {
+    /// Id of the block, counting from 0 after the header.
+    pub block_id: u64,
+    /// Offset of the block in the encrypted file.
+    pub offset: u64,
+    /// Size of the block in the encrypted file.
+    pub size: usize,
+    /// Whether the block authenticated.
+    pub ok: bool,
+}
+
+/// The outcome of [`verify_file()`](verify_file).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FileReport {
+    /// What's wrong with the layout of the file, if anything.
+    pub problem: Option<FileProblem>,
+    /// The nonce from the file header, if it could be read.
+    pub nonce: Option<FileNonce>,
+    /// Every block of the file, in order.
+    pub blocks: Vec<BlockResult>,
+    /// Id of the first block which failed to authenticate.
+    pub first_failed_block: Option<u64>,
+    /// Number of blocks which failed to authenticate.
+    pub failed_blocks: u64,
+}
+
+impl FileReport {
+    /// Returns whether the whole file authenticated.
+    pub fn is_ok(&self) -> bool {
+        self.problem.is_none() && self.failed_blocks == 0
+    }
+}
+
+/// Authenticates every block of the encrypted file read from `inner`, discarding the plaintext.
+///
+/// # Errors
+/// Only I/O errors of `inner` are returned, any problem with the data itself is part of the report.
+/// Fails with [`ErrorKind::Unsupported`](io::ErrorKind::Unsupported) if `cipher` doesn't
+/// encrypt data.
+pub fn verify_file<R: Read>(cipher: &Cipher, mut inner: R) -> io::Result<FileReport> {
+    cipher
+        .require_data_encryption()
+        .map_err(|e| io::Error::new(io::ErrorKind::Unsupported, e.to_string()))?;
+    let mut report = FileReport::default();
+
+    let mut header = [0u8; FILE_HEADER_SIZE];
+    let read = read_full(&mut inner, &mut header)?;
+    if read < FILE_HEADER_SIZE {
+        report.problem = Some(FileProblem::ShortHeader { size: read });
+        return Ok(report);
+    }
+    if &header[..FILE_MAGIC.len()] != FILE_MAGIC {
+        report.problem = Some(FileProblem::BadMagic);
+        return Ok(report);
+    }
+
+    let decrypter = Decrypter::new(&cipher.get_file_key(), &header)
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    report.nonce = Some(decrypter.get_nonce());
+
+    let mut block = vec![0u8; BLOCK_SIZE];
+    let mut offset = FILE_HEADER_SIZE as u64;
+
+    for block_id in 0.. {
+        let size = read_full(&mut inner, &mut block)?;
+        if size == 0 {
+            break;
+        }
+        if size <= BLOCK_HEADER_SIZE {
+            report.problem = Some(FileProblem::TrailingFragment { offset, size });
+            break;
+        }
+
+        let ok = decrypter.decrypt_block(block_id, &block[..size]).is_ok();
+        if !ok {
+            report.failed_blocks += 1;
+            report.first_failed_block.get_or_insert(block_id);
+        }
+        report.blocks.push(BlockResult {
+            block_id,
+            offset,
+            size,
+            ok,
+        });
+
+        offset += size as u64;
+    }
+
+    Ok(report)
+}
+
+/// A verified file of a tree.
+#[derive(Debug)]
+pub struct VerifiedFile {
+    /// Path relative to the root of the tree.
+    pub encrypted_path: PathBuf,
+    /// The decrypted path, or `None` if any of its names failed to decrypt.
+    pub path: Option<PathBuf>,
+    /// The report of the file, or the error reading it.
+    pub report: io::Result<FileReport>,
+}
+
+/// The outcome of [`verify_tree()`](verify_tree).
+#[derive(Debug, Default)]
+pub struct TreeReport {
+    /// Every file of the tree, sorted by encrypted path.
+    pub files: Vec<VerifiedFile>,
+    /// Files and directories whose name failed to decrypt.
+    pub bad_names: Vec<PathBuf>,
+    /// Groups of files sharing the same nonce, which must never happen.
+    pub duplicate_nonces: Vec<Vec<PathBuf>>,
+}
+
+impl TreeReport {
+    /// Returns whether every name decrypted, every file authenticated and no nonce was reused.
+    pub fn is_ok(&self) -> bool {
+        self.bad_names.is_empty()
+            && self.duplicate_nonces.is_empty()
+            && self
+                .files
+                .iter()
+                .all(|file| file.report.as_ref().is_ok_and(FileReport::is_ok))
+    }
+}
+
+/// Verifies every file of the encrypted tree at `root` with [`verify_file()`](verify_file), checks
+/// that every name decrypts, and that no two files share a nonce.
+///
+/// # Errors
+/// Only fails if a directory can't be listed or `cipher` doesn't encrypt data. I/O errors of
+/// single files are part of the report.
+pub fn verify_tree(cipher: &Cipher, root: &Path) -> Result<TreeReport> {
+    cipher.require_data_encryption()?;
+    let mut report = TreeReport::default();
+    verify_dir(
+        cipher,
+        root,
+        Path::new(""),
+        Some(Path::new("")),
+        &mut report,
+    )?;
+
+    let mut nonces: BTreeMap<FileNonce, Vec<PathBuf>> = BTreeMap::new();
+    for file in &report.files {
+        if let Some(nonce) = file.report.as_ref().ok().and_then(|report| report.nonce) {
+            nonces
+                .entry(nonce)
+                .or_default()
+                .push(file.encrypted_path.clone());
+        }
+    }
+    report.duplicate_nonces = nonces
+        .into_values()
+        .filter(|paths| paths.len() > 1)
+        .collect();
+
+    Ok(report)
+}
+
+fn verify_dir(
+    cipher: &Cipher,
+    root: &Path,
+    encrypted_path: &Path,
+    path: Option<&Path>,
+    report: &mut TreeReport,
+) -> Result<()> {
+    let dir = root.join(encrypted_path);
+    let mut entries = fs::read_dir(&dir)
+        .with_context(|| format!("Failed to read {}", dir.display()))?
+        .collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let encrypted_name = entry.file_name();
+        let encrypted_entry_path = encrypted_path.join(&encrypted_name);
+
+        let name = encrypted_name
+            .to_str()
+            .and_then(|name| cipher.decrypt_file_name(name).ok());
+        if name.is_none() {
+            report.bad_names.push(encrypted_entry_path.clone());
+        }
+        let entry_path = path.zip(name).map(|(path, name)| path.join(name));
+
+        // Follows symlinks; dangling ones and unreadable entries are failed files
+        let metadata = fs::metadata(entry.path());
+        if metadata.as_ref().is_ok_and(fs::Metadata::is_dir) {
+            // Keep going, the files inside may still be fine
+            verify_dir(
+                cipher,
+                root,
+                &encrypted_entry_path,
+                entry_path.as_deref(),
+                report,
+            )?;
+        } else {
+            let file_report = metadata
+                .and_then(|_| File::open(entry.path()))
+                .and_then(|file| verify_file(cipher, BufReader::new(file)));
+
+            report.files.push(VerifiedFile {
+                encrypted_path: encrypted_entry_path,
+                path: entry_path,
+                report: file_report,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{verify_file, verify_tree, FileProblem};
+    use crate::cipher::Cipher;
+    use crate::tests::encrypt;
+    use crate::{BLOCK_SIZE, FILE_HEADER_SIZE};
+    use std::fs;
+    use std::io::ErrorKind;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn verify_should_report_every_block() {
+        let cipher = Cipher::new("test", Some("test")).unwrap();
+        let mut encrypted = encrypt(&cipher, &vec![0u8; 200_000]);
+
+        let report = verify_file(&cipher, &encrypted[..]).unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.blocks.len(), 4);
+        assert_eq!(
+            report.blocks[3].offset,
+            (FILE_HEADER_SIZE + 3 * BLOCK_SIZE) as u64
+        );
+
+        // Corrupt blocks 1 and 3
+        encrypted[FILE_HEADER_SIZE + BLOCK_SIZE + 100] ^= 1;
+        encrypted[FILE_HEADER_SIZE + 3 * BLOCK_SIZE + 100] ^= 1;
+
+        let report = verify_file(&cipher, &encrypted[..]).unwrap();
+        assert!(!report.is_ok());
+        assert_eq!(report.failed_blocks, 2);
+        assert_eq!(report.first_failed_block, Some(1));
+        assert_eq!(
+            report
+                .blocks
+                .iter()
+                .map(|block| block.ok)
+                .collect::<Vec<_>>(),
+            [true, false, true, false]
+        );
+
+        let wrong = Cipher::new("wrong", Some("test")).unwrap();
+        assert_eq!(
+            verify_file(&wrong, &encrypted[..]).unwrap().failed_blocks,
+            4
+        );
+    }
+
+    #[test]
+    fn verify_should_report_structural_problems() {
+        let cipher = Cipher::new("test", Some("test")).unwrap();
+        let encrypted = encrypt(&cipher, b"hello");
+
+        let report = verify_file(&cipher, &encrypted[..10]).unwrap();
+        assert_eq!(report.problem, Some(FileProblem::ShortHeader { size: 10 }));
+
+        let mut bad_magic = encrypted.clone();
+        bad_magic[0] = b'X';
+        let report = verify_file(&cipher, &bad_magic[..]).unwrap();
+        assert_eq!(report.problem, Some(FileProblem::BadMagic));
+
+        let report = verify_file(&cipher, &encrypted[..FILE_HEADER_SIZE + 5]).unwrap();
+        assert_eq!(
+            report.problem,
+            Some(FileProblem::TrailingFragment {
+                offset: FILE_HEADER_SIZE as u64,
+                size: 5
+            })
+        );
+        assert!(report.blocks.is_empty());
+
+        // A header-only file is an empty file
+        let report = verify_file(&cipher, &encrypted[..FILE_HEADER_SIZE]).unwrap();
+        assert!(report.is_ok());
+
+        // Without data encryption there's nothing to authenticate
+        let cipher = cipher.with_data_encryption(false);
+        let error = verify_file(&cipher, &b"hello"[..]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn verify_should_check_names_and_nonces() {
+        let cipher = Cipher::new("test", Some("test")).unwrap();
+        let root = tempfile::tempdir().unwrap();
+        let path = |path: &str| {
+            root.path()
+                .join(cipher.encrypt_path(Path::new(path)).unwrap())
+        };
+
+        fs::create_dir(path("dir")).unwrap();
+        let encrypted = encrypt(&cipher, b"a");
+        fs::write(path("dir/a.txt"), &encrypted).unwrap();
+        fs::write(path("copy.txt"), &encrypted).unwrap();
+        fs::write(path("b.txt"), encrypt(&cipher, b"b")).unwrap();
+
+        let report = verify_tree(&cipher, root.path()).unwrap();
+        assert!(!report.is_ok());
+        assert!(report.bad_names.is_empty());
+        assert_eq!(report.files.len(), 3);
+        assert_eq!(report.duplicate_nonces.len(), 1);
+        assert_eq!(report.duplicate_nonces[0].len(), 2);
+
+        fs::remove_file(path("copy.txt")).unwrap();
+        fs::write(root.path().join("plain.txt"), encrypt(&cipher, b"c")).unwrap();
+
+        let report = verify_tree(&cipher, root.path()).unwrap();
+        assert_eq!(report.bad_names, [PathBuf::from("plain.txt")]);
+        assert!(report.duplicate_nonces.is_empty());
+        assert!(report
+            .files
+            .iter()
+            .all(|file| file.report.as_ref().unwrap().is_ok()));
+        assert!(report
+            .files
+            .iter()
+            .any(|file| file.path.as_deref() == Some(Path::new("dir/a.txt"))));
+
+        // A dangling symlink is a failed file, not a failed verification
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(root.path().join("missing"), path("link.txt")).unwrap();
+            let report = verify_tree(&cipher, root.path()).unwrap();
+            assert_eq!(report.files.len(), 4);
+            let link = report
+                .files
+                .iter()
+                .find(|file| file.path.as_deref() == Some(Path::new("link.txt")))
+                .unwrap();
+            assert!(link.report.is_err());
+        }
+    }
+}
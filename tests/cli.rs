@@ -0,0 +1,271 @@
+#![cfg(feature = "cli")]
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::process::{Child, Command, Output, Stdio};
+
+fn rclone_crypt(args: &[&str], stdin: &[u8]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rclone-crypt"))
+        .args(args)
+        .env_remove("RCLONE_CRYPT_PASSWORD")
+        .env_remove("RCLONE_CRYPT_PASSWORD2")
+        .env_remove("RCLONE_CONFIG")
+        .env_remove("RCLONE_CRYPT_NEW_PASSWORD")
+        .env_remove("RCLONE_CRYPT_NEW_PASSWORD2")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    child.stdin.take().unwrap().write_all(stdin).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn cli_should_roundtrip_data() {
+    let plaintext = vec![b'x'; 100_000];
+
+    let encrypted = rclone_crypt(&["-p", "test", "-s", "salt", "encrypt"], &plaintext);
+    assert!(encrypted.status.success());
+    assert_ne!(encrypted.stdout, plaintext);
+
+    let decrypted = rclone_crypt(&["-p", "test", "-s", "salt", "decrypt"], &encrypted.stdout);
+    assert!(decrypted.status.success());
+    assert_eq!(decrypted.stdout, plaintext);
+
+    let wrong = rclone_crypt(&["-p", "wrong", "-s", "salt", "decrypt"], &encrypted.stdout);
+    assert_eq!(wrong.status.code(), Some(3));
+
+    let names_only = ["-p", "test", "--no-data-encryption", "encrypt"];
+    assert_eq!(rclone_crypt(&names_only, &plaintext).stdout, plaintext);
+}
+
+#[test]
+fn cli_should_decrypt_names() {
+    let output = rclone_crypt(
+        &[
+            "-p",
+            "test",
+            "-s",
+            "test",
+            "decrypt-name",
+            "g83ktjf47lcm0bprvp034uulhg",
+        ],
+        b"",
+    );
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"test.txt\n");
+
+    let output = rclone_crypt(
+        &["-p", "wrong", "decrypt-name", "g83ktjf47lcm0bprvp034uulhg"],
+        b"",
+    );
+    assert_eq!(output.status.code(), Some(3));
+
+    let names = ["g83ktjf47lcm0bprvp034uulhg", "README.md"];
+    let args = [&["-p", "test", "-s", "test", "decrypt-name"], &names[..]].concat();
+    let output = rclone_crypt(&args, b"");
+    assert_eq!(output.status.code(), Some(3));
+    let output = rclone_crypt(&[&args[..], &["--undecryptable", "skip"]].concat(), b"");
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"test.txt\n");
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Skipping README.md"));
+    let output = rclone_crypt(
+        &[&args[..], &["--undecryptable", "pass-through"]].concat(),
+        b"",
+    );
+    assert_eq!(output.stdout, b"test.txt\nREADME.md\n");
+}
+
+#[test]
+fn cli_should_reveal_obscured_keys() {
+    let output = rclone_crypt(&["reveal", "je8bffZYIlfYtaJszmAb96fua5e11rwU4esR"], b"");
+    assert_eq!(output.stdout, b"hello_world\n");
+
+    let output = rclone_crypt(&["decrypt-name", "g83ktjf47lcm0bprvp034uulhg"], b"");
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn cli_should_read_keys_from_rclone_config() {
+    let config = std::env::temp_dir().join(format!("rclone-crypt-cli-{}.conf", std::process::id()));
+    std::fs::write(
+        &config,
+        "[secret]\ntype = crypt\nremote = local:/tmp\npassword = je8bffZYIlfYtaJszmAb96fua5e11rwU4esR\n",
+    )
+    .unwrap();
+
+    let config_arg = config.to_str().unwrap();
+    let from_config = rclone_crypt(
+        &[
+            "--config",
+            config_arg,
+            "-r",
+            "secret",
+            "encrypt-name",
+            "a/b",
+        ],
+        b"",
+    );
+    let from_flags = rclone_crypt(&["-p", "hello_world", "encrypt-name", "a/b"], b"");
+    std::fs::remove_file(&config).unwrap();
+
+    assert!(from_config.status.success());
+    assert_eq!(from_config.stdout, from_flags.stdout);
+}
+
+#[test]
+fn cli_should_rekey_trees() {
+    let source = tempfile::tempdir().unwrap();
+    let destination = tempfile::tempdir().unwrap();
+    let journal = destination.path().join("journal");
+    let tree = destination.path().join("tree");
+
+    let name = rclone_crypt(&["-p", "old", "encrypt-name", "a.txt"], b"");
+    let name = String::from_utf8(name.stdout).unwrap();
+    let path = source.path().join(name.trim());
+    let output = rclone_crypt(
+        &["-p", "old", "encrypt", "-o", path.to_str().unwrap()],
+        b"hello",
+    );
+    assert!(output.status.success());
+
+    let args = [
+        "-p",
+        "old",
+        "rekey",
+        "--new-password",
+        "new",
+        "--journal",
+        journal.to_str().unwrap(),
+        source.path().to_str().unwrap(),
+        tree.to_str().unwrap(),
+    ];
+    let output = rclone_crypt(&args, b"");
+    assert!(output.status.success());
+    assert!(std::fs::read_to_string(&journal)
+        .unwrap()
+        .contains(name.trim()));
+
+    let name = rclone_crypt(&["-p", "new", "encrypt-name", "a.txt"], b"");
+    let path = tree.join(String::from_utf8(name.stdout).unwrap().trim());
+    let output = rclone_crypt(&["-p", "new", "decrypt", path.to_str().unwrap()], b"");
+    assert_eq!(output.stdout, b"hello");
+
+    // Everything is in the journal already
+    let output = rclone_crypt(&args, b"");
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("0 files re-encrypted"));
+}
+
+#[test]
+fn cli_should_check_passwords() {
+    let root = tempfile::tempdir().unwrap();
+    let name = rclone_crypt(&["-p", "test", "-s", "salt", "encrypt-name", "a.txt"], b"");
+    let path = root
+        .path()
+        .join(String::from_utf8(name.stdout).unwrap().trim());
+    let path = path.to_str().unwrap();
+    rclone_crypt(
+        &["-p", "test", "-s", "salt", "encrypt", "-o", path],
+        b"data",
+    );
+    let root = root.path().to_str().unwrap();
+
+    let output = rclone_crypt(&["-p", "test", "-s", "salt", "check-password", root], b"");
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"Correct\n");
+
+    let output = rclone_crypt(&["-p", "test", "check-password", root], b"");
+    assert_eq!(output.status.code(), Some(3));
+    assert_eq!(output.stdout, b"Wrong password or salt\n");
+
+    let output = rclone_crypt(&["-p", "wrong", "-s", "salt", "check-password", root], b"");
+    assert_eq!(output.status.code(), Some(3));
+    assert_eq!(output.stdout, b"Wrong password\n");
+}
+
+/// Starts `rclone-crypt serve` on a free port and returns the process and the address.
+fn serve(args: &[&str], root: &std::path::Path) -> (Child, String) {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rclone-crypt"))
+        .args(["-p", "test", "serve"])
+        .args(args)
+        .args(["--addr", "127.0.0.1:0"])
+        .arg(root)
+        .env_remove("RCLONE_CRYPT_PASSWORD")
+        .env_remove("RCLONE_CRYPT_PASSWORD2")
+        .env_remove("RCLONE_CONFIG")
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    let mut line = String::new();
+    BufReader::new(child.stderr.take().unwrap())
+        .read_line(&mut line)
+        .unwrap();
+    let addr = line.trim().rsplit("http://").next().unwrap();
+    let addr = addr.trim_end_matches('/').to_string();
+    (child, addr)
+}
+
+/// Sends a raw HTTP request and returns the whole response.
+fn send(addr: &str, request: &str) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(request.as_bytes()).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn cli_should_serve_webdav() {
+    let root = tempfile::tempdir().unwrap();
+    let (mut child, addr) = serve(&["webdav"], root.path());
+
+    let put = send(
+        &addr,
+        "PUT /a.txt HTTP/1.1\r\nConnection: close\r\nContent-Length: 5\r\n\r\nhello",
+    );
+    let get = send(&addr, "GET /a.txt HTTP/1.1\r\nConnection: close\r\n\r\n");
+    child.kill().unwrap();
+    child.wait().unwrap();
+
+    assert!(put.starts_with("HTTP/1.1 201"));
+    assert!(get.starts_with("HTTP/1.1 200"));
+    assert!(get.ends_with("\r\n\r\nhello"));
+    let name = rclone_crypt(&["-p", "test", "encrypt-name", "a.txt"], b"");
+    let name = String::from_utf8(name.stdout).unwrap();
+    assert!(root.path().join(name.trim()).is_file());
+}
+
+#[test]
+fn cli_should_serve_http() {
+    let root = tempfile::tempdir().unwrap();
+    let name = rclone_crypt(&["-p", "test", "encrypt-name", "a.txt"], b"");
+    let path = root
+        .path()
+        .join(String::from_utf8(name.stdout).unwrap().trim());
+    rclone_crypt(
+        &["-p", "test", "encrypt", "-o", path.to_str().unwrap()],
+        b"hello world",
+    );
+    let (mut child, addr) = serve(&["http"], root.path());
+
+    let get = send(
+        &addr,
+        "GET /a.txt HTTP/1.1\r\nConnection: close\r\nRange: bytes=6-\r\n\r\n",
+    );
+    let put = send(
+        &addr,
+        "PUT /b.txt HTTP/1.1\r\nConnection: close\r\nContent-Length: 1\r\n\r\nx",
+    );
+    child.kill().unwrap();
+    child.wait().unwrap();
+
+    assert!(get.starts_with("HTTP/1.1 206"));
+    assert!(get.contains("Content-Range: bytes 6-10/11\r\n"));
+    assert!(get.ends_with("\r\n\r\nworld"));
+    assert!(put.starts_with("HTTP/1.1 405"));
+}